@@ -1,11 +1,8 @@
 
 //use capture::CapKind;
+use value::Value;
 
-#[deriving(Show,Clone)]
-struct CharSet {
-    ranges: Vec<(char,char)>
-}
-#[deriving(Show,Clone)]
+#[deriving(Show,Clone,PartialEq)]
 pub enum Ast {
     Nil,              // the empty string, ε
     Lit(String, Flags),
@@ -18,29 +15,47 @@ pub enum Ast {
     Not(Box<Ast>),      // !e, neg-lookahead pred
 
     Cap(uint, Option<String>, Box<Ast>), // numbered, optionally named, capture
+    Pos(uint, Option<String>),           // capture of the current position
+    Table(uint, Box<Ast>),               // node of the captures made inside it
+    Group(uint, Option<String>, Box<Ast>), // named node; an unnamed one adds none
+    Subst(uint, Box<Ast>),               // the match, with captures inside replaced
+    Backref(String),                     // the text of the last capture named so, again
+    RunTime(uint, String, Box<Ast>),     // p => name, calls function 'name' on p's match
+    Const(uint, Value<'static>),         // $value, a constant, matching nothing
+    Arg(uint, uint),                     // %n, the nth extra argument of the match
+    Format(uint, String, Box<Ast>),      // p -> 'fmt', with %n the values inside
+    Num(uint, uint, Box<Ast>),           // p -> n, the nth value inside
+    Function(uint, String, Box<Ast>),    // p -> name, function 'name' of the values inside
+    Fold(uint, String, Box<Ast>),        // p ~> name, folds the values inside with 'name'
+    Throw(String),                       // ^label, a labeled failure
+
+    Grammar(Vec<Rule>), // named rules; the first one is the start rule
+    NonTerm(String),    // reference to a grammar rule, by name
+}
 
-    // lpeg
-    TChar(char), TSet(CharSet), TAny,  /* standard PEG elements */
-    TTrue, TFalse,
-    TRep(Box<Ast>),
-    TSeq(Box<Ast>,Box<Ast>), TChoice(Box<Ast>,Box<Ast>),
-    TNot(Box<Ast>), TAnd(Box<Ast>),
-    TCall,
-    TOpenCall,
-    TRule(Box<Ast>,Box<Ast>),  /* sib1 is rule's pattern, sib2 is 'next' rule */
-    TGrammar(Box<Ast>),  /* sib1 is initial (and first) rule */
-    TBehind(Box<Ast>),  /* match behind */
-    TCapture(Box<Ast>),  /* regular capture */
-    TRunTime(Box<Ast>)  /* run-time capture */
-    // number of siblings for each tree */
-    //  0, 0, 0,  /* char, set, any */
-    //  0, 0,   /* true, false */
-    //  1,    /* rep */
-    //  2, 2,   /* seq, choice */
-    //  1, 1,   /* not, and */
-    //  0, 0, 2, 1,  /* call, opencall, rule, grammar */
-    //  1,  /* behind */
-    //  1, 1  /* capture, runtime capture */
+/// A named rule of a grammar, `name <- body`.
+#[deriving(Show,Clone)]
+pub struct Rule {
+    pub name: String,
+    pub body: Ast,
+    /// Where the rule is in the grammar's text, if it was parsed from one.
+    pub span: Option<Span>,
+}
+
+// Rules are equal whatever text they came from, so that a parsed grammar
+// equals the same one built by hand.
+impl PartialEq for Rule {
+    fn eq(&self, other: &Rule) -> bool {
+        self.name == other.name && self.body == other.body
+    }
+}
+
+/// A stretch of the source text of a grammar, from char `start` up to char
+/// `end`.  Positions count chars, as those of parse errors do.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Span {
+    pub start: uint,
+    pub end: uint,
 }
 
 #[deriving(Show, PartialEq, Clone)]
@@ -61,76 +76,110 @@ pub const FLAG_NEGATED:    u8 = 1 << 4; // char class or not word boundary
 
 
 
-// pattern <- grammar / simplepatt
-// grammar <- (nonterminal ’<-’ sp simplepatt)+
-// simplepatt <- alternative (’/’ sp alternative)*
-// alternative <- ([!&]? sp suffix)+
-// suffix <- primary ([*+?] sp)*
-// primary <- ’(’ sp pattern ’)’ sp / ’.’ sp / literal /
-// charclass / nonterminal !’<-’
-// literal <- [’] (![’] .)* [’] sp
-// charclass <- ’[’ (!’]’ (. ’-’ . / .))* ’]’ sp
-// nonterminal <- [a-zA-Z]+ sp
-// sp <- [ \t\n]*
-
-fn mk_peg_grammar() -> Ast {
-    let pattern = sp();
-    let grammar = sp();
-    let alternative = sp();
-    let simplepatt = sp();
-    let letter = Cls(vec!(('a','z'), ('A','Z')), FLAG_NORMAL);
-    let nonterm = seq(some(letter), sp());
+/// The PEG of PEGs, from the LPEG paper, as text.  `mk_peg_grammar` builds
+/// the same grammar by hand, to bootstrap a parser for it.
+pub const PEG_GRAMMAR: &'static str = "\
+pattern <- grammar / simplepatt
+grammar <- (nonterminal '<-' sp simplepatt)+
+simplepatt <- alternative ('/' sp alternative)*
+alternative <- ([!&]? sp suffix)+
+suffix <- primary ([*+?] sp)*
+primary <- '(' sp pattern ')' sp / '.' sp / literal /
+           charclass / nonterminal !'<-'
+literal <- ['] (!['] .)* ['] sp
+charclass <- '[' (!']' (. '-' . / .))* ']' sp
+nonterminal <- [a-zA-Z]+ sp
+sp <- [ \\t\\n]*
+";
+
+pub fn mk_peg_grammar() -> Ast {
+    // pattern <- grammar / simplepatt
+    let pattern = alt(nonterm("grammar"), nonterm("simplepatt"));
+    // grammar <- (nonterminal '<-' sp simplepatt)+
+    let grammar = some(seq4(
+        nonterm("nonterminal"), lit("<-"), nonterm("sp"), nonterm("simplepatt")));
+    // simplepatt <- alternative ('/' sp alternative)*
+    let simplepatt = seq(
+        nonterm("alternative"),
+        many(seq3(lit("/"), nonterm("sp"), nonterm("alternative"))));
+    // alternative <- ([!&]? sp suffix)+
+    let alternative = some(seq3(
+        opt(oneof("!&")),
+        nonterm("sp"),
+        nonterm("suffix")));
+    // suffix <- primary ([*+?] sp)*
+    let suffix = seq(
+        nonterm("primary"),
+        many(seq(oneof("*+?"), nonterm("sp"))));
+    // primary <- '(' sp pattern ')' sp / '.' sp / literal /
+    //            charclass / nonterminal !'<-'
+    let primary = alt5(
+        seq5(lit("("), nonterm("sp"), nonterm("pattern"), lit(")"), nonterm("sp")),
+        seq(lit("."), nonterm("sp")),
+        nonterm("literal"),
+        nonterm("charclass"),
+        seq(nonterm("nonterminal"), not(lit("<-"))));
+    // literal <- ['] (!['] .)* ['] sp
+    let literal = seq4(
+        oneof("'"),
+        many(seq(not(oneof("'")), dot())),
+        oneof("'"),
+        nonterm("sp"));
+    // charclass <- '[' (!']' (. '-' . / .))* ']' sp
     let charclass = seq4(
         lit("["),
         many(seq(not(lit("]")),
-                 alt(Seq(vec!(dot(), lit("-"), dot())),
-                     dot())
-            )),
+                 alt(seq3(dot(), lit("-"), dot()),
+                     dot()))),
         lit("]"),
-        sp());
-    let literal = seq4(
-        lit("'"),
-        many(seq(not(lit("'")), dot())),
-        lit("'"),
-        sp()
-        );
-    let primary = alt5(
-        seq5(lit("("), sp(), pattern, lit(")"), sp()),
-        seq(dot(), sp()),
-        literal,
-        charclass,
-        seq(nonterm.clone(), not(lit("<-")))
-        );
-    let suffix = seq(
-        primary,
-        many(seq(alt3(lit("*"),lit("+"),lit("?")), sp())) );
-    let alternative = some(seq3(
-            opt(alt(lit("!"),lit("&"))),
-            sp(),
-            suffix));
-    let simplepatt = alt(alternative.clone(), many(seq3(lit("/"),sp(),alternative)));
-    let grammar = some(seq4(
-        nonterm, lit("<-"), sp(), simplepatt.clone()
-        ));
-    let pattern = alt(grammar, simplepatt);
+        nonterm("sp"));
+    // nonterminal <- [a-zA-Z]+ sp
+    let nonterminal = seq(
+        some(Cls(vec!(('a','z'), ('A','Z')), FLAG_NORMAL)),
+        nonterm("sp"));
+    // sp <- [ \t\n]*
+    let sp = many(oneof(" \t\n"));
+
+    Grammar(vec!(
+        rule("pattern", pattern),
+        rule("grammar", grammar),
+        rule("simplepatt", simplepatt),
+        rule("alternative", alternative),
+        rule("suffix", suffix),
+        rule("primary", primary),
+        rule("literal", literal),
+        rule("charclass", charclass),
+        rule("nonterminal", nonterminal),
+        rule("sp", sp),
+    ))
+}
 
-    pattern
+#[test]
+fn peg_grammar_text_matches_bootstrap() {
+    use parse::parse;
+    assert_eq!(parse(PEG_GRAMMAR).unwrap(), mk_peg_grammar());
 }
-fn many(ast: Ast) -> Ast { Rep(box ast, ZeroMore) }
-fn some(ast: Ast) -> Ast { Rep(box ast, OneMore) }
-fn opt(ast: Ast) -> Ast { Rep(box ast, ZeroOne) }
-fn seq(a1:Ast, a2:Ast) -> Ast { Seq(vec!(a1,a2)) }
-fn seq3(a1:Ast, a2:Ast, a3:Ast) -> Ast { Seq(vec!(a1,a2,a3)) }
-fn seq4(a1:Ast, a2:Ast, a3:Ast, a4:Ast) -> Ast { Seq(vec!(a1,a2,a3,a4)) }
-fn seq5(a1:Ast, a2:Ast, a3:Ast, a4:Ast, a5:Ast) -> Ast { Seq(vec!(a1,a2,a3,a4,a5)) }
-fn alt(a1:Ast, a2:Ast) -> Ast { Alt(vec!(a1,a2)) }
-fn alt3(a1:Ast, a2:Ast, a3:Ast) -> Ast { Alt(vec!(a1,a2,a3)) }
-fn alt4(a1:Ast, a2:Ast, a3:Ast, a4:Ast) -> Ast { Alt(vec!(a1,a2,a3,a4)) }
-fn alt5(a1:Ast, a2:Ast, a3:Ast, a4:Ast, a5:Ast) -> Ast { Alt(vec!(a1,a2,a3,a4,a5)) }
-fn lit(s: &str) -> Ast { Lit(s.to_string(), FLAG_NORMAL) }
-fn not(ast: Ast) -> Ast { Not(box ast) }
-fn dot() -> Ast { Dot(FLAG_NORMAL) }
-fn sp() -> Ast { many(Alt(vec!(lit(" "),lit("\t"),lit("\n")))) }
+
+pub fn many(ast: Ast) -> Ast { Rep(box ast, ZeroMore) }
+pub fn some(ast: Ast) -> Ast { Rep(box ast, OneMore) }
+pub fn opt(ast: Ast) -> Ast { Rep(box ast, ZeroOne) }
+pub fn seq(a1:Ast, a2:Ast) -> Ast { Seq(vec!(a1,a2)) }
+pub fn seq3(a1:Ast, a2:Ast, a3:Ast) -> Ast { Seq(vec!(a1,a2,a3)) }
+pub fn seq4(a1:Ast, a2:Ast, a3:Ast, a4:Ast) -> Ast { Seq(vec!(a1,a2,a3,a4)) }
+pub fn seq5(a1:Ast, a2:Ast, a3:Ast, a4:Ast, a5:Ast) -> Ast { Seq(vec!(a1,a2,a3,a4,a5)) }
+pub fn alt(a1:Ast, a2:Ast) -> Ast { Alt(vec!(a1,a2)) }
+pub fn alt3(a1:Ast, a2:Ast, a3:Ast) -> Ast { Alt(vec!(a1,a2,a3)) }
+pub fn alt4(a1:Ast, a2:Ast, a3:Ast, a4:Ast) -> Ast { Alt(vec!(a1,a2,a3,a4)) }
+pub fn alt5(a1:Ast, a2:Ast, a3:Ast, a4:Ast, a5:Ast) -> Ast { Alt(vec!(a1,a2,a3,a4,a5)) }
+pub fn lit(s: &str) -> Ast { Lit(s.to_string(), FLAG_NORMAL) }
+pub fn not(ast: Ast) -> Ast { Not(box ast) }
+pub fn and(ast: Ast) -> Ast { And(box ast) }
+pub fn nonterm(s: &str) -> Ast { NonTerm(s.to_string()) }
+pub fn rule(name: &str, body: Ast) -> Rule { Rule { name: name.to_string(), body: body, span: None } }
+/// a char class of the individual chars in `chars`
+pub fn oneof(chars: &str) -> Ast { Cls(chars.chars().map(|c| (c, c)).collect(), FLAG_NORMAL) }
+pub fn dot() -> Ast { Dot(FLAG_NORMAL) }
+pub fn sp() -> Ast { many(Alt(vec!(lit(" "),lit("\t"),lit("\n")))) }
 
 
 // below from lpeg
@@ -0,0 +1,149 @@
+//! Bootstrapping: the grammar of PEGs, built by hand in `ast::mk_peg_grammar`,
+//! compiled to a Program and run on the parsing machine.  It reads grammar
+//! text into the same Ast that `parse::parse` does, so marge is
+//! self-hosting: the grammar of PEGs, parsed by itself, is the grammar that
+//! parsed it.
+
+use ast::{Ast, Lit, Cls, Seq, Alt, Grammar, NonTerm, FLAG_NORMAL, mk_peg_grammar, PEG_GRAMMAR};
+use ast::{rule, many, some, opt, not, and, dot};
+use capture::Node;
+use compile::Program;
+use parse::parse;
+use peg::{Peg, group_rules};
+use vm::Vm;
+
+/// The bootstrap grammar, compiled for the VM.  Each of its rules but `sp`
+/// is wrapped in a group named after the rule, so that a match builds a
+/// tree of the rules that matched, from which `self_parse` reads the Ast.
+pub fn bootstrap() -> Program {
+    Program::new(group_rules(mk_peg_grammar(), |name| name != "sp")).unwrap()
+}
+
+/// Parses the text of a PEG into an Ast, as `parse::parse` does, but on the
+/// VM, with the bootstrap grammar.  The paper's grammar is all it knows;
+/// none of `parse`'s extensions, but for escapes in literals and classes.
+pub fn self_parse(src: &str) -> Option<Ast> {
+    let peg = Peg::from_program(bootstrap());
+    match peg.tree(src) {
+        Some(ref tree) if tree.end == src.len() => Some(read_ast(&tree.children[0])),
+        _ => None
+    }
+}
+
+// The Ast of a node of the bootstrap grammar's tree, named for its rule.
+fn read_ast(node: &Node) -> Ast {
+    let kids = node.children.as_slice();
+    // the text of the node from byte offset `from` of the input, to `to`
+    let text = |from: uint, to: uint| node.text.slice(from - node.start, to - node.start);
+    match node.name.as_ref().map(|n| n.as_slice()) {
+        // pattern <- grammar / simplepatt
+        Some("pattern") => read_ast(&kids[0]),
+        // grammar <- (nonterminal '<-' sp simplepatt)+
+        Some("grammar") => Grammar(kids.chunks(2).map(|r| {
+            rule(r[0].text.trim(), read_ast(&r[1]))
+        }).collect()),
+        // simplepatt <- alternative ('/' sp alternative)*
+        Some("simplepatt") => one_or(kids.iter().map(read_ast).collect(), Alt),
+        // alternative <- ([!&]? sp suffix)+
+        Some("alternative") => {
+            let mut prev = node.start;
+            let mut elems = vec!();
+            for k in kids.iter() {
+                let e = read_ast(k);
+                elems.push(match text(prev, k.start).trim() {
+                    "!" => not(e),
+                    "&" => and(e),
+                    _ => e
+                });
+                prev = k.end;
+            }
+            one_or(elems, Seq)
+        }
+        // suffix <- primary ([*+?] sp)*
+        Some("suffix") => {
+            let mut e = read_ast(&kids[0]);
+            for c in text(kids[0].end, node.end).chars() {
+                e = match c {
+                    '*' => many(e),
+                    '+' => some(e),
+                    '?' => opt(e),
+                    _ => e
+                };
+            }
+            e
+        }
+        // primary <- '(' sp pattern ')' sp / '.' sp / literal /
+        //            charclass / nonterminal !'<-'
+        Some("primary") => if kids.is_empty() { dot() } else { read_ast(&kids[0]) },
+        // literal <- ['] (!['] .)* ['] sp
+        Some("literal") => {
+            let t = node.text.trim_right();
+            Lit(unescape(t.slice(1, t.len() - 1)).into_iter().collect(), FLAG_NORMAL)
+        }
+        // charclass <- '[' (!']' (. '-' . / .))* ']' sp
+        Some("charclass") => {
+            let t = node.text.trim_right();
+            let chars = unescape(t.slice(1, t.len() - 1));
+            let mut ranges = vec!();
+            let mut i = 0;
+            while i < chars.len() {
+                if i + 2 < chars.len() && chars[i + 1] == '-' {
+                    ranges.push((chars[i], chars[i + 2]));
+                    i += 3;
+                } else {
+                    ranges.push((chars[i], chars[i]));
+                    i += 1;
+                }
+            }
+            Cls(ranges, FLAG_NORMAL)
+        }
+        // nonterminal <- [a-zA-Z]+ sp
+        Some("nonterminal") => NonTerm(node.text.trim().to_string()),
+        name => fail!("BUG: no rule of the bootstrap grammar is named {}", name)
+    }
+}
+
+fn one_or(mut es: Vec<Ast>, many: fn(Vec<Ast>) -> Ast) -> Ast {
+    if es.len() == 1 { es.pop().unwrap() } else { many(es) }
+}
+
+// The chars of the text of a literal or class, with its escapes read as
+// `parse::parse` reads them.
+fn unescape(s: &str) -> Vec<char> {
+    let mut chars = vec!();
+    let mut it = s.chars();
+    loop {
+        let c = match it.next() {
+            None => break,
+            Some('\\') => match it.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some(c) => c,
+                None => '\\'
+            },
+            Some(c) => c
+        };
+        chars.push(c);
+    }
+    chars
+}
+
+#[test]
+fn bootstrap_recognizes_peg_grammar() {
+    let mut vm = Vm::new(bootstrap());
+    assert_eq!(vm.do_match(PEG_GRAMMAR), Some(PEG_GRAMMAR));
+}
+
+#[test]
+fn self_hosted_parser_fixpoint() {
+    // the grammar of PEGs, parsed by itself, is the grammar that parsed it
+    assert_eq!(self_parse(PEG_GRAMMAR), Some(mk_peg_grammar()));
+    // and it reads what parse::parse does
+    let srcs = ["'a' / 'b'* .", "S <- A S / ''  A <- [a-c] !'x' &(B / 'y')+  B <- 'b'?",
+                "'a\\n' [\\t a-c]"];
+    for src in srcs.iter() {
+        assert_eq!(self_parse(*src), Some(parse(*src).unwrap()));
+    }
+    assert_eq!(self_parse("'a' /"), None);
+}
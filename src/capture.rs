@@ -1,26 +1,491 @@
 
+use std::fmt;
+use std::mem;
+use code;
+use code::BytePos;
+use value::{Value, Slice, Text, Int, List, Map};
+use std::collections::BTreeMap;
+
 /* kinds of captures */
+#[deriving(Eq,PartialEq,Show,Clone)]
 pub enum CapKind {
   Cclose, Cposition, Cconst, Cbackref, Carg, Csimple, Ctable, Cfunction,
-  Cquery, Cstring, Cnum, Csubst, Cfold, Cruntime, Cgroup
+  Cquery, Cstring, Cnum, Csubst, Cfold, Cruntime, Cgroup,
+  // a rule's match, recorded in CST mode; 'key' is the rule's index
+  Crule,
+  // a labeled failure, where it was thrown; 'key' is the label's
+  Cerror
+}
+
+
+/// A capture made by a successful match: its number and name, and the
+/// part of the input it spans, as byte offsets and as a slice of the input.
+/// A position capture spans no input.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Capture<'t> {
+  pub num: uint,
+  pub name: Option<String>,
+  pub start: uint,
+  pub end: uint,
+  pub text: &'t str,
+  /// The capture's value, where it isn't just its text.
+  pub value: Option<Value<'t>>,
+}
+
+impl<'t> Capture<'t> {
+  /// The capture's value, if it's a string, or else its text.
+  pub fn as_str<'a>(&'a self) -> &'a str {
+    match self.value {
+      Some(ref v) => v.as_str().unwrap_or(self.text),
+      None => self.text
+    }
+  }
+
+  /// The capture's value, or else its text.
+  pub fn value(&self) -> Value<'t> {
+    match self.value {
+      Some(ref v) => v.clone(),
+      None => Slice(self.text)
+    }
+  }
 }
 
+/// What a capture's value is made from, besides what it matched.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Param {
+  NoParam,
+  /// the value of a constant capture
+  ConstParam(Value<'static>),
+  /// which extra argument of the match an argument capture is, from 1
+  ArgParam(uint),
+  /// the format of a string capture
+  FormatParam(String),
+  /// which of the values inside it a number capture is, from 1
+  NumParam(uint),
+  /// the name of the function of a function or fold capture
+  FnParam(String),
+}
+
+/// A function of function and fold captures, from values to a value.
+pub type ValueFn = Box<FnMut(&[Value]) -> Value<'static> + 'static>;
+
+/// The tables that captures' values are made from, besides the input and
+/// the match's arguments.
+pub struct CapEnv {
+  /// The name of each capture, by number.
+  pub names: Vec<Option<String>>,
+  /// What each capture's value is made from, by number.
+  pub params: Vec<Param>,
+  /// The numbers and values of the captures match-time functions made,
+  /// by the key of their Cruntime entries.
+  pub dynamic: Vec<(uint, Value<'static>)>,
+  /// The functions of function and fold captures, and their names.
+  pub functions: Vec<(String, ValueFn)>,
+}
 
-pub struct Capture {
-  s: *const char,  /* subject position */
-  idx: u16,  /* extra info about capture (group name, arg index, etc.) */
-  kind: CapKind,  /* kind of capture */
-  siz: u8,  /* size of full capture + 1 (0 = not a full capture) */
+/// Why the value of a capture couldn't be made: it refers to an extra
+/// argument the match wasn't given, or to a value the captures inside it
+/// don't have, or it's a fold of none.
+#[deriving(Clone,PartialEq)]
+pub struct CaptureError {
+  /// The capture's number.
+  pub num: uint,
+  pub msg: String,
 }
 
+impl fmt::Show for CaptureError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "capture {}: {}", self.num, self.msg)
+  }
+}
+
+/// Everything turning the VM's capture list into captures needs, but the
+/// list: as LPeg's CapState.
+pub struct CapState<'a, 't> {
+  pub input: &'t str,
+  pub env: &'a mut CapEnv,
+  /// The extra arguments of the match, for argument captures.
+  pub args: &'a [Value<'t>],
+}
+
+/// Turns the VM's capture list into Captures of the input, in the order
+/// they were opened.  A capture whose value can't be made has none.
+pub fn get_captures<'a, 't>(cs: &mut CapState<'a, 't>, list: &[code::Capture]) -> Vec<Capture<'t>> {
+  evaluate(cs, list).caps
+}
+
+/// The values of the outermost captures of the VM's capture list, as a
+/// match's result.  An unnamed group gives the values inside it.  Fails
+/// if the value of any capture can't be made.
+pub fn get_values<'a, 't>(cs: &mut CapState<'a, 't>, list: &[code::Capture])
+                          -> Result<Vec<Value<'t>>, CaptureError> {
+  let made = evaluate(cs, list);
+  match made.error {
+    Some(e) => Err(e),
+    None => Ok(made.values(None))
+  }
+}
+
+// The captures in the VM's capture list, with the index of the one each is
+// made directly inside, and its kind; and the first capture whose value
+// couldn't be made, if any.
+struct Made<'t> {
+  caps: Vec<Capture<'t>>,
+  parents: Vec<Option<uint>>,
+  kinds: Vec<CapKind>,
+  error: Option<CaptureError>,
+}
+
+// Each capture's value is made when it's closed, from the captures inside,
+// whose values are made already.
+fn evaluate<'a, 't>(cs: &mut CapState<'a, 't>, list: &[code::Capture]) -> Made<'t> {
+  let mut made = Made { caps: vec!(), parents: vec!(), kinds: vec!(), error: None };
+  // the indexes in caps of the captures still open
+  let mut open = vec!();
+  for entry in list.iter() {
+    let BytePos(pos) = entry.pos;
+    let parent = open.last().map(|&k| k);
+    let k = match (entry.kind, entry.len) {
+      (Cclose, _) => {
+        let k = open.pop().expect("BUG: close of a capture that wasn't opened");
+        let cap: &mut Capture = made.caps.get_mut(k);
+        cap.end = pos;
+        cap.text = cs.input.slice(cap.start, pos);
+        k
+      }
+      // a rule's node passes its captures on, as an unnamed group does
+      (Crule, None) => {
+        open.push(made.caps.len());
+        made.push(capture(cs, 0, pos, pos), parent, Cgroup);
+        continue
+      }
+      (kind, None) => {
+        open.push(made.caps.len());
+        made.push(capture(cs, entry.key, pos, pos), parent, kind);
+        continue
+      }
+      // errors aren't captures
+      (Cerror, _) => continue,
+      (Cruntime, Some(n)) => {
+        let (num, ref value) = cs.env.dynamic[entry.key];
+        let mut cap = capture(cs, num, pos, pos + n);
+        cap.value = Some(value.clone());
+        made.push(cap, parent, Cruntime);
+        continue
+      }
+      (kind, Some(n)) => {
+        made.push(capture(cs, entry.key, pos, pos + n), parent, kind);
+        made.caps.len() - 1
+      }
+    };
+    let value = match made.value_of(cs, k) {
+      Ok(value) => value,
+      Err(msg) => {
+        if made.error.is_none() {
+          made.error = Some(CaptureError { num: made.caps[k].num, msg: msg });
+        }
+        None
+      }
+    };
+    let cap: &mut Capture = made.caps.get_mut(k);
+    cap.value = value;
+  }
+  assert!(open.is_empty());
+  made
+}
+
+impl<'t> Made<'t> {
+  fn push(&mut self, cap: Capture<'t>, parent: Option<uint>, kind: CapKind) {
+    self.caps.push(cap);
+    self.parents.push(parent);
+    self.kinds.push(kind);
+  }
+
+  // The value of capture k, if it's other than its text, or why it can't
+  // be made.
+  fn value_of<'a>(&self, cs: &mut CapState<'a, 't>, k: uint) -> Result<Option<Value<'t>>, String> {
+    let cap = &self.caps[k];
+    let param = match cs.env.params.get(cap.num) {
+      Some(p) => p.clone(),
+      None => NoParam
+    };
+    Ok(match (self.kinds[k], param) {
+      (Cposition, _) => Some(Int(cap.start as i64)),
+      (Csubst, _) => Some(Text(self.substitute(k))),
+      (Ctable, _) => Some(self.table(k)),
+      (Cgroup, _) => self.values(Some(k)).into_iter().next(),
+      (Cconst, ConstParam(v)) => Some(v),
+      (Carg, ArgParam(n)) => match cs.args.get(n - 1) {
+        Some(v) => Some(v.clone()),
+        None => return Err(format!("no extra argument {} given to the match", n))
+      },
+      (Cstring, FormatParam(f)) => Some(Text(try!(self.format(k, f.as_slice())))),
+      (Cnum, NumParam(0)) => None,
+      (Cnum, NumParam(n)) => match self.values(Some(k)).into_iter().nth(n - 1) {
+        Some(v) => Some(v),
+        None => return Err(format!("no value {} to give", n))
+      },
+      (Cfunction, FnParam(name)) => {
+        let mut args = self.values(Some(k));
+        if args.is_empty() {
+          args.push(Slice(cap.text));
+        }
+        Some(try!(call(cs, name.as_slice(), args.as_slice())))
+      }
+      (Cfold, FnParam(name)) => {
+        let mut values = self.values(Some(k)).into_iter();
+        let mut acc = match values.next() {
+          Some(v) => v,
+          None => return Err("no values to fold".to_string())
+        };
+        for v in values {
+          acc = try!(call(cs, name.as_slice(), &[acc, v]));
+        }
+        Some(acc)
+      }
+      _ => None
+    })
+  }
+
+  // The values of the captures made directly inside capture k, or outside
+  // of any if k is None; an unnamed group gives the values inside it.
+  fn values(&self, k: Option<uint>) -> Vec<Value<'t>> {
+    let from = match k { Some(k) => k + 1, None => 0 };
+    let mut values = vec!();
+    for j in range(from, self.caps.len()) {
+      if self.parents[j] != k {
+        continue
+      }
+      if self.kinds[j] == Cgroup && self.caps[j].name.is_none() {
+        values.extend(self.values(Some(j)).into_iter());
+      } else {
+        values.push(self.caps[j].value());
+      }
+    }
+    values
+  }
+
+  // A table's value: the list of the values inside it; or if it has named
+  // groups in it, a map of their values by name, with the others by their
+  // place in the list, from 1.
+  fn table(&self, k: uint) -> Value<'t> {
+    let mut list = vec!();
+    let mut map = BTreeMap::new();
+    for j in range(k + 1, self.caps.len()) {
+      if self.parents[j] != Some(k) {
+        continue
+      }
+      match (self.kinds[j], &self.caps[j].name) {
+        (Cgroup, &Some(ref name)) => { map.insert(name.clone(), self.caps[j].value()); }
+        (Cgroup, &None) => list.extend(self.values(Some(j)).into_iter()),
+        _ => list.push(self.caps[j].value())
+      }
+    }
+    if map.is_empty() {
+      return List(list)
+    }
+    for (n, v) in list.into_iter().enumerate() {
+      map.insert((n + 1).to_string(), v);
+    }
+    Map(map)
+  }
+
+  // The text of capture k, with that of each capture made directly inside it
+  // replaced by the inner capture's value.  Position captures match nothing,
+  // and add nothing; nor do captures of text already replaced, such as the
+  // values after the first of a match-time function, which share its span.
+  fn substitute(&self, k: uint) -> String {
+    let outer = &self.caps[k];
+    let mut s = String::new();
+    let mut pos = outer.start;
+    for j in range(k + 1, self.caps.len()) {
+      if self.parents[j] != Some(k) || self.kinds[j] == Cposition || self.caps[j].start < pos {
+        continue
+      }
+      let cap = &self.caps[j];
+      s.push_str(outer.text.slice(pos - outer.start, cap.start - outer.start));
+      s.push_str(format!("{}", cap.value()).as_slice());
+      pos = cap.end;
+    }
+    s.push_str(outer.text.slice_from(pos - outer.start));
+    s
+  }
+
+  // A string capture's format, with %0 replaced by the capture's text,
+  // %1 to %9 by the values inside it, and %% by a '%'.
+  fn format(&self, k: uint, f: &str) -> Result<String, String> {
+    let values = self.values(Some(k));
+    let mut s = String::new();
+    let mut chars = f.chars();
+    loop {
+      match chars.next() {
+        None => break,
+        Some('%') => match chars.next() {
+          Some('0') => s.push_str(self.caps[k].text),
+          Some(d) if d >= '1' && d <= '9' => {
+            let n = d as uint - '1' as uint;
+            match values.get(n) {
+              Some(v) => s.push_str(format!("{}", v).as_slice()),
+              None => return Err(format!("no value {} to format", n + 1))
+            }
+          }
+          Some(c) => s.push(c),
+          None => s.push('%')
+        },
+        Some(c) => s.push(c)
+      }
+    }
+    Ok(s)
+  }
+}
+
+// Calls the function `name` of a function or fold capture.  The VM won't
+// match without every function given, but the capture list of a match
+// can be read at any time.
+fn call<'a, 't>(cs: &mut CapState<'a, 't>, name: &str, args: &[Value<'t>]) -> Result<Value<'t>, String> {
+  for entry in cs.env.functions.iter_mut() {
+    let (ref n, ref mut f) = *entry;
+    if n.as_slice() == name {
+      return Ok((*f)(args))
+    }
+  }
+  Err(format!("no function given for the captures that call '{}'", name))
+}
+
+/// A node of the tree of captures that a match builds: a capture, and the
+/// captures made inside it.  The root is the whole match.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Node<'t> {
+  pub num: uint,
+  pub name: Option<String>,
+  pub start: uint,
+  pub end: uint,
+  pub text: &'t str,
+  pub value: Option<Value<'t>>,
+  pub children: Vec<Node<'t>>,
+}
+
+impl<'t> Node<'t> {
+  /// The first child with the given name.
+  pub fn child(&self, name: &str) -> Option<&Node<'t>> {
+    self.children.iter().find(|c| has_name(*c, name))
+  }
+
+  /// The children with the given name, in order.
+  pub fn children_named(&self, name: &str) -> Vec<&Node<'t>> {
+    self.children.iter().filter(|c| has_name(*c, name)).collect()
+  }
+
+  /// The first node with the given name, of this one and its descendants,
+  /// depth first.
+  pub fn find(&self, name: &str) -> Option<&Node<'t>> {
+    self.iter().find(|n| has_name(*n, name))
+  }
+
+  /// This node and its descendants, depth first, in the order they start.
+  pub fn iter<'a>(&'a self) -> Nodes<'a, 't> {
+    Nodes { stack: vec!(self) }
+  }
+}
+
+fn has_name(node: &Node, name: &str) -> bool {
+  match node.name {
+    Some(ref n) => n.as_slice() == name,
+    None => false
+  }
+}
+
+/// Iterator over a tree of captures, depth first.
+pub struct Nodes<'a, 't: 'a> {
+  stack: Vec<&'a Node<'t>>,
+}
+
+impl<'a, 't> Iterator<&'a Node<'t>> for Nodes<'a, 't> {
+  fn next(&mut self) -> Option<&'a Node<'t>> {
+    let node = match self.stack.pop() {
+      Some(node) => node,
+      None => return None
+    };
+    for child in node.children.iter().rev() {
+      self.stack.push(child);
+    }
+    Some(node)
+  }
+}
+
+/// Builds the tree of the captures in the VM's capture list, under a root
+/// node for the match, which is the first `end` bytes of the input.  An
+/// unnamed group adds no node: its captures become children of the node
+/// it's in.  A capture whose value can't be made has none.
+pub fn get_tree<'a, 't>(cs: &mut CapState<'a, 't>, end: uint, list: &[code::Capture]) -> Node<'t> {
+  let Made { caps, parents, kinds, .. } = evaluate(cs, list);
+  let n = caps.len();
+  // the children of each capture, and last of the root, found in reverse
+  let mut kids: Vec<Vec<Node<'t>>> = range(0, n + 1).map(|_| vec!()).collect();
+  for (j, cap) in caps.into_iter().enumerate().rev() {
+    let parent = parents[j].unwrap_or(n);
+    let mut children = mem::replace(kids.get_mut(j), vec!());
+    if kinds[j] == Cgroup && cap.name.is_none() {
+      kids.get_mut(parent).extend(children.into_iter());
+    } else {
+      children.reverse();
+      let Capture { num, name, start, end, text, value } = cap;
+      kids.get_mut(parent).push(Node { num: num, name: name, start: start, end: end, text: text,
+                                       value: value, children: children });
+    }
+  }
+  let mut children = kids.pop().unwrap();
+  children.reverse();
+  Node { num: 0, name: None, start: 0, end: end, text: cs.input.slice_to(end), value: None,
+         children: children }
+}
+
+fn capture<'a, 't>(cs: &CapState<'a, 't>, num: uint, start: uint, end: uint) -> Capture<'t> {
+  let names = cs.env.names.as_slice();
+  Capture {
+    num: num,
+    name: if num < names.len() { names[num].clone() } else { None },
+    start: start,
+    end: end,
+    text: cs.input.slice(start, end),
+    value: None,
+  }
+}
+
+
+/// What a match-time function sees: the match of the pattern it's attached
+/// to, in the input, and the captures made inside that match.
+pub struct RunTime<'t> {
+  /// The whole input being matched.
+  pub input: &'t str,
+  /// Where the pattern's match starts and ends, as byte offsets.
+  pub start: uint,
+  pub end: uint,
+  pub text: &'t str,
+  /// The captures made inside the match, in the order they were opened.
+  /// The function's values take their place.  (Extra arguments of the
+  /// match aren't known yet, so argument captures here are an error.)
+  pub captures: Vec<Capture<'t>>,
+}
+
+impl<'t> RunTime<'t> {
+  /// Lets the match go on from the end of the pattern's match, as if the
+  /// function weren't there, but for dropping the captures inside it.
+  pub fn accept(&self) -> Outcome {
+    Accept(self.end, vec!())
+  }
+}
 
-pub struct CapState {
-  cap: *const Capture,  /* current capture */
-  ocap: *const Capture,  /* (original) capture list */
-  //lua_State *L;
-  ptop: i32,  /* index of last argument to 'match' */
-  s: *const char,  /* original string */
-  valuecached: int  /* value stored in cache slot */
+/// What a match-time function decides.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Outcome {
+  /// The pattern fails here, and the match backtracks.
+  Reject,
+  /// The match goes on from the byte offset given, which is no less than
+  /// the end of the pattern's match, with the values given as captures
+  /// spanning from its start to that offset, numbered as the match-time
+  /// capture.
+  Accept(uint, Vec<Value<'static>>),
 }
 
 
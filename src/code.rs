@@ -1,6 +1,8 @@
 
-use ast::Flags;
-//use capture::{Capture};
+use ast::{Flags, FLAG_NOCASE, FLAG_NEGATED};
+use capture::{CapKind, Cclose};
+use error::escape;
+use std::char;
 
 // Virtual Machine's instructions
 //
@@ -10,35 +12,292 @@ use ast::Flags;
 pub enum Opcode {
   IAny(Flags),             // if no char, fail
   IChar(char, Flags),      // if char != aux, fail
-  //ISet,             // if char not in buff, fail
-  //ITestAny,         // in no char, jump to 'offset'
-  //ITestChar,        // if char != aux, jump to 'offset'
-  //ITestSet,         // if char not in buff, jump to 'offset'
-  //ISpan,            // read a span of chars in buff
+  IString(uint),           // if the text isn't literal 'key', fail
+  ISet(uint),              // if char not in charset 'key', fail
+  // the tests don't consume; on jumping, they expect what heads 'key' are
+  ITestAny(int, uint),         // if no char, jump to 'offset'
+  ITestChar(char, int, uint),  // if char != aux, jump to 'offset'
+  ITestSet(uint, int, uint),   // if char not in charset 'key', jump to 'offset'
+  ISpan(uint),             // read a span of chars in charset 'key'
   //IBehind,          // walk back 'aux' characters (fail if not possible)
   IRet,             // return from a rule
   IEnd,             // end of pattern
   IChoice(int),     // stack a choice; next fail will jump to 'offset'
   IJmp(int),        // jump to 'offset'
   ICall(int),       // call rule at 'offset'
-  //IOpenCall,        // call rule number 'key' (must be closed to a ICall)
+  ITailCall(int),   // call rule at 'offset' in place of the caller, through its frame
+  IOpenCall(uint),  // call rule number 'key' (must be closed to a ICall)
   ICommit(int),     // pop choice and jump to 'offset'
-  //IPartialCommit,   // update top choice to current position and jump
-  //IBackCommit,      // "fails" but jump to its own 'offset'
-  //IFailTwice,       // pop one choice and then fail
+  IPartialCommit(int), // update top choice to current position and jump
+  IBackCommit(int), // "fails" but jump to its own 'offset'
+  IFailTwice,       // pop one choice and then fail
   IFail,            // go back to saved state on choice and jump to saved offset
-  //IGiveup,          // internal use
-  IFullCapture(int),// complete capture of last 'off' chars
-  //IOpenCapture,     // start a capture
-  //ICloseCapture,
-  //ICloseRunTime
+  IGiveup,          // internal use
+  IFullCapture(CapKind, uint, uint), // complete capture 'key' of last 'n' bytes
+  IOpenCapture(CapKind, uint),       // start a capture 'key'
+  ICloseCapture,    // end the innermost open capture
+  IBackref(uint),   // match again the text of the last capture in back-reference 'key'
+  ICloseRunTime(uint), // end a match-time capture, calling function 'key' on it
+  IThrow(uint),     // throw label 'key', calling its recovery rule if it has one
+}
+
+impl Opcode {
+  /// The offset of a jump-type instruction; None for the others.
+  pub fn offset(&self) -> Option<int> {
+    match *self {
+      IChoice(o) | IJmp(o) | ICall(o) | ITailCall(o) | ICommit(o) | IPartialCommit(o) |
+      IBackCommit(o) | ITestAny(o, _) | ITestChar(_, o, _) | ITestSet(_, o, _) => Some(o),
+      _ => None
+    }
+  }
+
+  /// A jump-type instruction with its offset changed; None for the others.
+  pub fn with_offset(&self, offset: int) -> Option<Opcode> {
+    Some(match *self {
+      IChoice(_) => IChoice(offset),
+      IJmp(_) => IJmp(offset),
+      ICall(_) => ICall(offset),
+      ITailCall(_) => ITailCall(offset),
+      ICommit(_) => ICommit(offset),
+      IPartialCommit(_) => IPartialCommit(offset),
+      IBackCommit(_) => IBackCommit(offset),
+      ITestAny(_, head) => ITestAny(offset, head),
+      ITestChar(ch, _, head) => ITestChar(ch, offset, head),
+      ITestSet(key, _, head) => ITestSet(key, offset, head),
+      _ => return None
+    })
+  }
+}
+
+/// Something a match expects to find at a position, as the VM records it
+/// where the match fails: a char, a set by its key, any char, or a rule by
+/// its index in the program's rules.
+#[deriving(PartialEq,Show,Clone)]
+pub enum Want {
+  WChar(char),
+  WSet(uint),
+  WAny,
+  WRule(uint),
+}
+
+/// A compiled char class, for the ISet, ITestSet and ISpan instructions.
+/// ASCII chars are looked up in a 128-bit bitmap; anything above that in
+/// a sorted list of disjoint ranges, by binary search.
+#[deriving(PartialEq,Show,Clone)]
+pub struct Charset {
+  // bitmap of chars 0-63 and 64-127
+  lo: u64,
+  hi: u64,
+  // sorted, non-overlapping, non-adjacent ranges of chars above 127
+  ranges: Vec<(u32, u32)>,
+  negated: bool,
+  // for ASCII, both cases are put in the bitmap; other chars are
+  // case-folded when tested
+  nocase: bool,
+}
+
+impl Charset {
+  pub fn new(ranges: &[(char, char)], flags: Flags) -> Charset {
+    let nocase = flags & FLAG_NOCASE != 0;
+    let mut set = Charset {
+      lo: 0,
+      hi: 0,
+      ranges: vec!(),
+      negated: flags & FLAG_NEGATED != 0,
+      nocase: nocase,
+    };
+    let mut wide = vec!();
+    for &(a, b) in ranges.iter() {
+      let (a, b) = (a as u32, b as u32);
+      let mut n = a;
+      while n <= b && n < 128 {
+        set.add_ascii(n);
+        if nocase && n >= 'a' as u32 && n <= 'z' as u32 { set.add_ascii(n - 32); }
+        if nocase && n >= 'A' as u32 && n <= 'Z' as u32 { set.add_ascii(n + 32); }
+        n += 1;
+      }
+      if b >= 128 {
+        wide.push((if a < 128 { 128 } else { a }, b));
+      }
+    }
+    wide.sort();
+    for &(a, b) in wide.iter() {
+      let merge = match set.ranges.last() {
+        Some(&(_, hi)) => a <= hi + 1,
+        None => false
+      };
+      if merge {
+        let last = set.ranges.len() - 1;
+        let (lo, hi) = set.ranges[last];
+        *set.ranges.get_mut(last) = (lo, if b > hi { b } else { hi });
+      } else {
+        set.ranges.push((a, b));
+      }
+    }
+    set
+  }
+
+  /// Is `c` in the set (or, for a negated set, not in it)?
+  #[inline]
+  pub fn contains(&self, c: char) -> bool {
+    let mut found = self.has(c as u32);
+    if !found && self.nocase && c as u32 >= 128 {
+      found = self.has(c.to_lowercase() as u32) || self.has(c.to_uppercase() as u32);
+    }
+    found != self.negated
+  }
+
+  #[inline]
+  fn has(&self, n: u32) -> bool {
+    if n < 64 {
+      return self.lo & (1 << n as uint) != 0
+    }
+    if n < 128 {
+      return self.hi & (1 << (n - 64) as uint) != 0
+    }
+    let (mut lo, mut hi) = (0, self.ranges.len());
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      let (a, b) = self.ranges[mid];
+      if n < a { hi = mid; }
+      else if n > b { lo = mid + 1; }
+      else { return true }
+    }
+    false
+  }
+
+  /// The bytes the UTF-8 of a char in the set can begin with, indexed by
+  /// byte: just those in the set, for ASCII, and all the lead bytes of
+  /// longer chars if the set may have any of them.
+  pub fn first_bytes(&self) -> Vec<bool> {
+    let mut bytes = Vec::from_elem(256, false);
+    for b in range(0, 128) {
+      *bytes.get_mut(b) = self.contains(b as u8 as char);
+    }
+    // a case-blind set may have a char that folds to ASCII
+    if self.negated || self.nocase || !self.ranges.is_empty() {
+      for b in range(0xc0, 256) {
+        *bytes.get_mut(b) = true;
+      }
+    }
+    bytes
+  }
+
+  /// The set as a char class would be written in a grammar, such as
+  /// `[a-z_]`, for error messages.
+  pub fn to_class(&self) -> String {
+    let mut ranges = vec!();
+    let mut n = 0;
+    while n < 128 {
+      if !self.has(n) {
+        n += 1;
+        continue
+      }
+      let start = n;
+      while n < 128 && self.has(n) {
+        n += 1;
+      }
+      ranges.push((start, n - 1));
+    }
+    ranges.push_all(self.ranges.as_slice());
+    let mut s = "[".to_string();
+    if self.negated {
+      s.push('^');
+    }
+    for &(a, b) in ranges.iter() {
+      s.push_str(class_char(a).as_slice());
+      if b > a + 1 {
+        s.push('-');
+      }
+      if b > a {
+        s.push_str(class_char(b).as_slice());
+      }
+    }
+    s.push(']');
+    s
+  }
+
+  fn add_ascii(&mut self, n: u32) {
+    if n < 64 { self.lo |= 1 << n as uint; } else { self.hi |= 1 << (n - 64) as uint; }
+  }
 }
 
 #[deriving(Eq,PartialEq,Show,Clone)]
 pub struct CodeIdx(pub uint);
 
+/// A position in the subject: a byte offset, on a char boundary, so that it
+/// can slice the input directly.
 #[deriving(Eq,PartialEq,Show,Clone)]
-pub struct CharNum(pub uint);
+pub struct BytePos(pub uint);
 
+/// An entry of the VM's capture list, as in LPeg: either a full capture,
+/// whose length was known when it was made, or the open or the close of
+/// one whose end wasn't.  What `key` means depends on the kind; for simple
+/// and position captures, it's the capture's number.
 #[deriving(Eq,PartialEq,Show,Clone)]
-pub struct Capture(pub CharNum, pub CodeIdx);
+pub struct Capture {
+  pub kind: CapKind,
+  pub key: uint,
+  // where the capture starts, or for a close, where it ends
+  pub pos: BytePos,
+  // the length of a full capture; None for an open or a close
+  pub len: Option<uint>,
+}
+
+impl Capture {
+  pub fn open(kind: CapKind, key: uint, pos: BytePos) -> Capture {
+    Capture { kind: kind, key: key, pos: pos, len: None }
+  }
+  pub fn close(pos: BytePos) -> Capture {
+    Capture { kind: Cclose, key: 0, pos: pos, len: None }
+  }
+  pub fn full(kind: CapKind, key: uint, pos: BytePos, len: uint) -> Capture {
+    Capture { kind: kind, key: key, pos: pos, len: Some(len) }
+  }
+}
+
+// A char of a class, escaped as it would be in a grammar.
+fn class_char(n: u32) -> String {
+  match char::from_u32(n).unwrap() {
+    c @ ']' | c @ '-' | c @ '^' => format!("\\{}", c),
+    c => escape(c)
+  }
+}
+
+#[test]
+fn charset_ascii_and_unicode() {
+  use ast::FLAG_NORMAL;
+  let set = Charset::new(&[('a','z'), ('0','0'), ('\u00e0','\u00ff'), ('\u4e00','\u9fff'), ('\u00c0','\u00e5')], FLAG_NORMAL);
+  assert!(set.contains('a') && set.contains('q') && set.contains('0'));
+  assert!(!set.contains('A') && !set.contains('1') && !set.contains('{'));
+  assert!(set.contains('\u00c0') && set.contains('\u00e9') && set.contains('\u6f22'));
+  assert!(!set.contains('\u00bf') && !set.contains('\ua000'));
+  // overlapping ranges were merged
+  assert_eq!(set.ranges, vec!((0xc0, 0xff), (0x4e00, 0x9fff)));
+  assert_eq!(set.to_class(), "[0a-z\u00c0-\u00ff\u4e00-\u9fff]".to_string());
+  assert_eq!(Charset::new(&[(']',']'), ('\n','\n'), ('x','y')], FLAG_NEGATED).to_class(),
+             "[^\\n\\]xy]".to_string());
+}
+
+#[test]
+fn charset_flags() {
+  let neg = Charset::new(&[('a','c'), ('\u03b1','\u03c9')], FLAG_NEGATED);
+  assert!(!neg.contains('b') && !neg.contains('\u03b2'));
+  assert!(neg.contains('d') && neg.contains('\u0391'));
+  let nocase = Charset::new(&[('a','c'), ('\u03b1','\u03c9')], FLAG_NOCASE);
+  assert!(nocase.contains('B') && nocase.contains('b') && !nocase.contains('D'));
+  // greek capital beta, against the lowercase range
+  assert!(nocase.contains('\u0392'));
+}
+
+#[test]
+fn charset_first_bytes() {
+  use ast::FLAG_NORMAL;
+  let bytes = Charset::new(&[('a','c')], FLAG_NORMAL).first_bytes();
+  assert_eq!(range(0, 256).filter(|&b| bytes[b]).collect::<Vec<uint>>(), vec!(0x61, 0x62, 0x63));
+  // the lead bytes of chars above ASCII, but never a continuation byte
+  let bytes = Charset::new(&[('a','a'), ('\u00e9','\u00e9')], FLAG_NORMAL).first_bytes();
+  assert!(bytes[0x61] && bytes[0xc3] && bytes[0xe4] && !bytes[0x62] && !bytes[0xa9]);
+  let bytes = Charset::new(&[('k','k')], FLAG_NOCASE).first_bytes();
+  assert!(bytes[0x4b] && bytes[0x6b] && bytes[0xe2]);
+}
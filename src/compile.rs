@@ -1,63 +1,329 @@
 
-use ast::{Ast, Flags, Nil,Lit,Dot,Cls,Seq,Alt,Rep,And,Not,Cap};
+use std::fmt;
+use ast::{Ast, Rule, Flags, FLAG_NORMAL, FLAG_NOCASE, Repeater, Nil,Lit,Dot,Cls,Seq,Alt,Rep,And,Not,Cap};
+use ast::{Pos, Table, Group, Subst, Backref, RunTime, Const, Arg, Format, Num, Function, Fold};
+use ast::Throw;
+use ast::{Grammar, NonTerm, ZeroOne, ZeroMore, OneMore};
+use capture::{CapKind, Csimple, Cposition, Ctable, Csubst, Cgroup, Cruntime};
+use capture::{Cconst, Carg, Cstring, Cnum, Cfunction, Cfold};
+use capture::{Param, NoParam, ConstParam, ArgParam, FormatParam, NumParam, FnParam};
 use code::*;
+use verify::{verify, left_recursive, refers_back};
+use first::{Chars, first, first_sets, follow_sets, prefix};
+use optimize::optimize;
+
+pub struct Error {
+    pub msg: String,
+}
+impl fmt::Show for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compile error: {}", self.msg)
+    }
+}
+pub fn err<T>(msg: String) -> Result<T, Error> {
+    Err(Error { msg: msg })
+}
 
 
 #[deriving(Clone)]
 pub struct Program {
     /// A sequence of instructions.
     pub insts: Vec<Opcode>,
-    // /// If the regular expression requires a literal prefix in order to have a
-    // /// match, that prefix is stored here. (It's used in the VM to implement
-    // /// an optimization.)
-    // pub prefix: String,
+    /// The char classes used by ISet, ITestSet and ISpan, indexed by 'key'.
+    pub sets: Vec<Charset>,
+    /// The literals IStrings match, indexed by 'key'.
+    pub strings: Vec<String>,
+    /// The name and first instruction of each rule, in the order they were
+    /// compiled.  A nested grammar may reuse the name of an outer rule.
+    pub rules: Vec<(String, uint)>,
+    /// The first instruction of each rule that heads a left-recursive cycle;
+    /// the VM grows a seed for calls to these, instead of recursing forever.
+    pub left_recursive: Vec<uint>,
+    /// The name of each capture, if it has one, indexed by its number.
+    /// Capture 0 is the whole match.
+    pub names: Vec<Option<String>>,
+    /// What the value of each capture is made from, besides what it
+    /// matched, indexed by its number.
+    pub params: Vec<Param>,
+    /// The numbers of the captures each IBackref may refer to, indexed by
+    /// its 'key': those with the name it refers to.
+    pub refs: Vec<Vec<uint>>,
+    /// The first instruction of each rule that may match a back-reference;
+    /// the VM doesn't memoize these.
+    pub refers_back: Vec<uint>,
+    /// The names of the functions of match-time captures, indexed by the
+    /// 'key' of their ICloseRunTimes.
+    pub functions: Vec<String>,
+    /// The label each IThrow throws, indexed by its 'key', and the first
+    /// instruction of the label's recovery rule, if it has one.
+    pub labels: Vec<(String, Option<uint>)>,
+    /// The keys in `sets` of the first set and the follow set of each rule,
+    /// in the order of `rules`, which error recovery resynchronizes on.
+    pub first: Vec<uint>,
+    pub follow: Vec<uint>,
+    /// The key in `sets` of the chars a match can begin with, or None if the
+    /// pattern can match the empty string.  A search passes over the
+    /// positions where no match can begin.
+    pub start: Option<uint>,
+    /// The literal every match begins with, if there's one, or else the
+    /// empty string.  A search looks for it, rather than for a char of the
+    /// first set.
+    pub prefix: String,
+    /// What each test instruction expects to find if it jumps, indexed by
+    /// its heads 'key': what the pattern it passes over would have
+    /// expected, failing at its first char.
+    pub heads: Vec<Vec<Want>>,
 }
 
 impl Program {
-    /// Compiles a Regex given its AST.
-    pub fn new(ast: Ast) -> Program { //(Program, Vec<Option<String>>)
+    /// Compiles a Program given its AST, and optimizes its code (see
+    /// `optimize`).  Fails on the first error that `verify` finds in it.
+    pub fn new(ast: Ast) -> Result<Program, Error> {
+        let mut program = try!(Program::unoptimized(ast));
+        optimize(&mut program);
+        Ok(program)
+    }
+
+    /// Compiles a Program as `new` does, but leaves its code as the
+    /// compiler wrote it, for debugging.
+    pub fn unoptimized(ast: Ast) -> Result<Program, Error> { //(Program, Vec<Option<String>>)
+        Program::compile(ast, true)
+    }
+
+    /// Compiles a Program as `new` does, but without test instructions, so
+    /// that every alternative tried pushes a choice; for measuring what the
+    /// tests save.
+    pub fn without_tests(ast: Ast) -> Result<Program, Error> {
+        let mut program = try!(Program::compile(ast, false));
+        optimize(&mut program);
+        Ok(program)
+    }
+
+    fn compile(ast: Ast, tests: bool) -> Result<Program, Error> {
+        for d in verify(&ast).iter() {
+            if d.is_error() {
+                return err(format!("{}", d))
+            }
+        }
         let mut c = Compiler {
             insts: Vec::with_capacity(100),
-            //names: Vec::with_capacity(10),
+            names: vec!(None),
+            params: vec!(NoParam),
+            sets: vec!(),
+            rules: vec!(),
+            rule_pos: vec!(),
+            scopes: vec!(),
+            left_recursive: vec!(),
+            backrefs: vec!(),
+            refers_back: vec!(),
+            functions: vec!(),
+            labels: vec!(),
+            first: vec!(),
+            follow: vec!(),
+            grammars: vec!(),
+            heads: vec!(),
+            tests: tests,
         };
 
+        let starts = first(&ast, &[], &[]);
+        let prefix = prefix(&ast, &[]);
         //c.insts.push(IOpenCapture(0));
-        c.compile(ast);
+        try!(c.compile(ast));
         //c.insts.push(ICloseCapture(1));
         c.insts.push(IEnd);
+        c.link();
+        let start = if starts.eps { None } else { Some(c.add_set(starts.ranges.as_slice(), FLAG_NORMAL)) };
 
         //...
 
-        Program {
-        	insts: c.insts
-        }
+        let refs = c.resolve_backrefs();
+        let labels = c.labels.iter().map(|&(ref label, rule)| {
+            (label.clone(), rule.map(|key| c.rule_pos[key]))
+        }).collect();
+        let rules = c.rules.into_iter().zip(c.rule_pos.into_iter()).collect();
+        Ok(Program {
+        	insts: c.insts,
+        	sets: c.sets,
+        	strings: vec!(),
+        	rules: rules,
+        	left_recursive: c.left_recursive,
+        	names: c.names,
+        	params: c.params,
+        	refs: refs,
+        	refers_back: c.refers_back,
+        	functions: c.functions,
+        	labels: labels,
+        	first: c.first,
+        	follow: c.follow,
+        	start: start,
+        	prefix: prefix,
+        	heads: c.heads,
+        })
     }
 }
 struct Compiler {
 	insts: Vec<Opcode>,
-	//names: Vec<Option<String>>  // named groups
+	sets: Vec<Charset>,
+	names: Vec<Option<String>>,  // named captures
+	params: Vec<Param>,          // what capture values are made from
+
+	// name of each rule, indexed by the key of its IOpenCalls.  Nested
+	// grammars may reuse a name, so the key, not the name, identifies a rule.
+	rules: Vec<String>,
+	// index of the first instruction of each rule
+	rule_pos: Vec<uint>,
+	// keys of the rules of each enclosing grammar, innermost last
+	scopes: Vec<Vec<uint>>,
+	// index of the first instruction of each left-recursive rule
+	left_recursive: Vec<uint>,
+	// the name each back-reference refers to, indexed by the key of its
+	// IBackrefs
+	backrefs: Vec<String>,
+	// index of the first instruction of each rule that may match a
+	// back-reference
+	refers_back: Vec<uint>,
+	// names of the functions of match-time captures, indexed by key
+	functions: Vec<String>,
+	// the label of each IThrow, and the key of its recovery rule, if the
+	// grammar it's thrown in has one; indexed by key
+	labels: Vec<(String, Option<uint>)>,
+	// the keys in sets of the first and follow sets of each rule, by key
+	first: Vec<uint>,
+	follow: Vec<uint>,
+	// the rules of each enclosing grammar and their first sets, innermost
+	// last, which the first sets of the patterns in them are found with
+	grammars: Vec<(Vec<Rule>, Vec<Chars>)>,
+	// what each test instruction expects if it jumps, by its heads key
+	heads: Vec<Vec<Want>>,
+	// whether to pass over alternatives by a test of their first char
+	tests: bool,
 }
 impl Compiler {
-	fn compile(&mut self, ast: Ast) {
+	fn compile(&mut self, ast: Ast) -> Result<(), Error> {
 		match ast {
-			//Nil => {/*ε, no opcode needed*/}
+			Nil => {/*ε, no opcode needed*/}
 			Lit(s, flags) => {
 				for ch in s.as_slice().chars() {
 					self.push(IChar(ch, flags));
 				}
 			}
 			Dot(flags) => { self.push(IAny(flags)); }
-			Cls(cls, flags) => { fail!("char classes not implemented"); }
+			Cls(ranges, flags) => {
+				let key = self.add_set(ranges.as_slice(), flags);
+				self.push(ISet(key));
+			}
 			Seq(es) => {
 				for e in es.into_iter() {
-					self.compile(e);
+					try!(self.compile(e));
 				}
 			}
-			Alt(es) => {}
-			Rep(e, rep) => {}
-			And(e) => {}
-			Not(e) => {}
-			Cap(num, name, e) => {}
+			Alt(es) => { try!(self.compile_alt(es)); }
+			Rep(e, rep) => { try!(self.compile_rep(*e, rep)); }
+			//      Choice L1
+			//      e
+			//      BackCommit L2
+			// L1:  Fail
+			// L2:
+			And(e) => {
+				let choice = self.push_hole(IChoice(0));
+				try!(self.compile(*e));
+				let commit = self.push_hole(IBackCommit(0));
+				let fail = self.here();
+				self.push(IFail);
+				self.patch(choice, fail);
+				let end = self.here();
+				self.patch(commit, end);
+			}
+			//      Choice L1
+			//      e
+			//      FailTwice
+			// L1:
+			Not(e) => {
+				let choice = self.push_hole(IChoice(0));
+				try!(self.compile(*e));
+				self.push(IFailTwice);
+				let end = self.here();
+				self.patch(choice, end);
+			}
+			Cap(num, name, e) => { try!(self.compile_capture(Csimple, num, name, *e)); }
+			Table(num, e) => { try!(self.compile_capture(Ctable, num, None, *e)); }
+			Subst(num, e) => { try!(self.compile_capture(Csubst, num, None, *e)); }
+			Group(num, name, e) => { try!(self.compile_capture(Cgroup, num, name, *e)); }
+			// a capture of the 0 bytes before the current position
+			Pos(num, name) => {
+				self.name_capture(num, name);
+				self.push(IFullCapture(Cposition, num, 0));
+			}
+			// resolved to the numbers of the captures it may refer to
+			// once they've all been named
+			Backref(name) => {
+				let key = match self.backrefs.iter().position(|n| *n == name) {
+					Some(key) => key,
+					None => {
+						self.backrefs.push(name);
+						self.backrefs.len() - 1
+					}
+				};
+				self.push(IBackref(key));
+			}
+			//      OpenCapture Cruntime n
+			//      e
+			//      CloseRunTime f
+			RunTime(num, name, e) => {
+				let key = match self.functions.iter().position(|f| *f == name) {
+					Some(key) => key,
+					None => {
+						self.functions.push(name);
+						self.functions.len() - 1
+					}
+				};
+				self.push(IOpenCapture(Cruntime, num));
+				try!(self.compile(*e));
+				self.push(ICloseRunTime(key));
+			}
+			// constants and arguments match nothing, like positions
+			Const(num, value) => {
+				self.set_param(num, ConstParam(value));
+				self.push(IFullCapture(Cconst, num, 0));
+			}
+			Arg(num, n) => {
+				self.set_param(num, ArgParam(n));
+				self.push(IFullCapture(Carg, num, 0));
+			}
+			Format(num, f, e) => {
+				self.set_param(num, FormatParam(f));
+				try!(self.compile_capture(Cstring, num, None, *e));
+			}
+			Num(num, n, e) => {
+				self.set_param(num, NumParam(n));
+				try!(self.compile_capture(Cnum, num, None, *e));
+			}
+			Function(num, name, e) => {
+				self.set_param(num, FnParam(name));
+				try!(self.compile_capture(Cfunction, num, None, *e));
+			}
+			Fold(num, name, e) => {
+				self.set_param(num, FnParam(name));
+				try!(self.compile_capture(Cfold, num, None, *e));
+			}
+			// a label is recovered from by the rule of its name, if the
+			// innermost grammar has one
+			Throw(label) => {
+				let rule = self.resolve(label.as_slice()).ok();
+				let key = match self.labels.iter().position(|&(ref l, r)| *l == label && r == rule) {
+					Some(key) => key,
+					None => {
+						self.labels.push((label, rule));
+						self.labels.len() - 1
+					}
+				};
+				self.push(IThrow(key));
+			}
+			Grammar(rules) => { try!(self.compile_grammar(rules)); }
+			NonTerm(name) => {
+				let key = try!(self.resolve(name.as_slice()));
+				self.push(IOpenCall(key));
+			}
 
     		//Nil,              // the empty string, ε
     		//Lit(char, Flags),
@@ -71,18 +337,427 @@ impl Compiler {
     		//// match beginning-of-input, eoi, or wordbound (libregex)
     		//// Begin(Flags), End(Flags), WordBoundary(Flags),
     		//Cap(uint, Option<String>, Box<Ast>), // numbered, optionally named, capture
+		}
+		Ok(())
+	}
+
+	// Ordered choice, e1 / e2 / ... / en:
+	//      Choice L1
+	//      e1
+	//      Commit END
+	// L1:  Choice L2
+	//      e2
+	//      Commit END
+	// L2:  ...
+	//      en
+	// END:
+	// An alternative that can't match the empty string is guarded by a test
+	// of its first char, which goes straight on to the next alternative
+	// rather than push a choice entry only to pop it.  If the test is all
+	// the alternative can fail at, or no later alternative can match once
+	// it's passed, the choice isn't needed at all:
+	//      Test L1
+	//      e1
+	//      Jmp END
+	// L1:  ...
+	// and otherwise it's still made:
+	//      Test L1
+	//      Choice L1
+	//      e1
+	//      Commit END
+	// L1:  ...
+	fn compile_alt(&mut self, es: Vec<Ast>) -> Result<(), Error> {
+		let n = es.len();
+		let firsts: Vec<Chars> = es.iter().map(|e| self.first_set(e)).collect();
+		let mut jumps = vec!();
+		for (k, e) in es.into_iter().enumerate() {
+			if k + 1 == n {
+				try!(self.compile(e));
+				break;
+			}
+			let mut rest = Chars::none();
+			for f in firsts.slice_from(k + 1).iter() {
+				rest.add(f);
+			}
+			let set = &firsts[k];
+			let test = if set.eps { None } else { self.push_test(set, &e) };
+			match test {
+				Some(test) if headfail(&e) || (!rest.eps && set.disjoint(&rest)) => {
+					try!(self.compile(e));
+					jumps.push(self.push_hole(IJmp(0)));
+					let next = self.here();
+					self.patch(test, next);
+				}
+				test => {
+					let choice = self.push_hole(IChoice(0));
+					try!(self.compile(e));
+					jumps.push(self.push_hole(ICommit(0)));
+					let next = self.here();
+					self.patch(choice, next);
+					for &test in test.iter() {
+						self.patch(test, next);
+					}
+				}
+			}
+		}
+		let end = self.here();
+		for jump in jumps.into_iter() {
+			self.patch(jump, end);
+		}
+		Ok(())
+	}
+
+	fn compile_rep(&mut self, e: Ast, rep: Repeater) -> Result<(), Error> {
+		match (e, rep) {
+			// a repeated char class is a single instruction:
+			//      Span X
+			(Cls(ranges, flags), ZeroMore) => {
+				let key = self.add_set(ranges.as_slice(), flags);
+				self.push(ISpan(key));
+			}
+			// an optional char class needs no choice entry, and expects
+			// nothing if it's not there:
+			//      TestSet X L1
+			//      Any
+			// L1:
+			(Cls(ref ranges, flags), ZeroOne) if self.tests => {
+				let key = self.add_set(ranges.as_slice(), flags);
+				let head = self.add_heads(vec!());
+				let test = self.push_hole(ITestSet(key, 0, head));
+				self.push(IAny(FLAG_NORMAL));
+				let end = self.here();
+				self.patch(test, end);
+			}
+			// e*, if a test of its first char is all e can fail at:
+			// L1:  Test L2
+			//      e
+			//      Jmp L1
+			// L2:
+			// and otherwise, with the test if e can't match the empty string:
+			//      Test L2
+			//      Choice L2
+			// L1:  e
+			//      PartialCommit L1
+			// L2:
+			(e, ZeroMore) => {
+				let set = self.first_set(&e);
+				let test = if set.eps { None } else { self.push_test(&set, &e) };
+				match test {
+					Some(test) if headfail(&e) => {
+						try!(self.compile(e));
+						let jmp = self.push_hole(IJmp(0));
+						self.patch(jmp, test);
+						let end = self.here();
+						self.patch(test, end);
+					}
+					test => {
+						let choice = self.push_hole(IChoice(0));
+						let body = self.here();
+						try!(self.compile(e));
+						let commit = self.push_hole(IPartialCommit(0));
+						self.patch(commit, body);
+						let end = self.here();
+						self.patch(choice, end);
+						for &test in test.iter() {
+							self.patch(test, end);
+						}
+					}
+				}
+			}
+			// e+ is e e*
+			(e, OneMore) => {
+				try!(self.compile(e.clone()));
+				try!(self.compile_rep(e, ZeroMore));
+			}
+			// e?, if a test of its first char is all e can fail at:
+			//      Test L1
+			//      e
+			// L1:
+			// and otherwise, with the test if e can't match the empty string:
+			//      Test L1
+			//      Choice L1
+			//      e
+			//      Commit L1
+			// L1:
+			(e, ZeroOne) => {
+				let set = self.first_set(&e);
+				let test = if set.eps { None } else { self.push_test(&set, &e) };
+				match test {
+					Some(test) if headfail(&e) => {
+						try!(self.compile(e));
+						let end = self.here();
+						self.patch(test, end);
+					}
+					test => {
+						let choice = self.push_hole(IChoice(0));
+						try!(self.compile(e));
+						let commit = self.push_hole(ICommit(0));
+						let end = self.here();
+						self.patch(choice, end);
+						self.patch(commit, end);
+						for &test in test.iter() {
+							self.patch(test, end);
+						}
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// A grammar calls its first rule, then skips over the code of its rules:
+	//      Call R1
+	//      Jmp END
+	// R1:  rule1
+	//      Ret
+	// R2:  rule2
+	//      Ret
+	//      ...
+	// END:
+	// Nonterminals in the rule bodies become IOpenCalls, which `link`
+	// turns into ICalls once every rule's position is known.
+	fn compile_grammar(&mut self, rules: Vec<Rule>) -> Result<(), Error> {
+		if rules.is_empty() {
+			return err("empty grammar".to_string())
+		}
+		let mut keys = vec!();
+		for r in rules.iter() {
+			keys.push(self.rules.len());
+			self.rules.push(r.name.clone());
+			self.rule_pos.push(0);
+		}
+		let firsts = first_sets(rules.as_slice());
+		let follows = follow_sets(rules.as_slice());
+		for (f1, f2) in firsts.iter().zip(follows.iter()) {
+			let first = self.add_set(f1.ranges.as_slice(), FLAG_NORMAL);
+			let follow = self.add_set(f2.ranges.as_slice(), FLAG_NORMAL);
+			self.first.push(first);
+			self.follow.push(follow);
+		}
+		self.grammars.push((rules.clone(), firsts));
+		let start = keys[0];
+		self.scopes.push(keys.clone());
+		let lr = left_recursive(rules.as_slice());
+		let backs = refers_back(rules.as_slice());
+
+		self.push(IOpenCall(start));
+		let jmp = self.push_hole(IJmp(0));
+		for (((r, &key), &is_lr), &back) in rules.into_iter().zip(keys.iter()).zip(lr.iter())
+		                                                  .zip(backs.iter()) {
+			let pos = self.here();
+			*self.rule_pos.get_mut(key) = pos;
+			if is_lr {
+				self.left_recursive.push(pos);
+			}
+			if back {
+				self.refers_back.push(pos);
+			}
+			try!(self.compile(r.body));
+			self.push(IRet);
+		}
+		let end = self.here();
+		self.patch(jmp, end);
+
+		self.scopes.pop();
+		self.grammars.pop();
+		Ok(())
+	}
+
+	// The first set of `e`, in the innermost grammar.
+	fn first_set(&self, e: &Ast) -> Chars {
+		match self.grammars.last() {
+			Some(&(ref rules, ref firsts)) => first(e, rules.as_slice(), firsts.as_slice()),
+			None => first(e, &[], &[])
+		}
+	}
+
+	// Appends a test of whether the next char is in `set`, the first set of
+	// `e`, for `patch` to point past `e`, and returns its index.  None, and
+	// no test, if tests are off or it can't be told what `e` would expect,
+	// were it run.
+	fn push_test(&mut self, set: &Chars, e: &Ast) -> Option<uint> {
+		let mut wants = vec!();
+		if !self.tests || !self.heads_of(e, &mut wants) {
+			return None
+		}
+		let head = self.add_heads(wants);
+		let test = if set.is_all() {
+			ITestAny(0, head)
+		} else {
+			match set.ranges.iter().next() {
+				Some(&(a, b)) if a == b && set.ranges.len() == 1 => ITestChar(a, 0, head),
+				_ => ITestSet(self.add_set(set.ranges.as_slice(), FLAG_NORMAL), 0, head)
+			}
+		};
+		Some(self.push_hole(test))
+	}
 
+	// Adds what `e` expects if it fails at its first char to `wants`, in the
+	// order the VM would note it, so that a test that passes over `e`
+	// leaves the same error as running it.  False if that can't be told
+	// from `e` alone: what a predicate's pattern expects counts as well.
+	fn heads_of(&mut self, e: &Ast, wants: &mut Vec<Want>) -> bool {
+		match *e {
+			Nil | Pos(..) | Const(..) | Arg(..) => {}
+			Lit(ref s, _) => match s.as_slice().chars().next() {
+				Some(c) => want(wants, WChar(c)),
+				None => {}
+			},
+			Dot(_) => want(wants, WAny),
+			Cls(ref ranges, flags) => {
+				let key = self.add_set(ranges.as_slice(), flags);
+				want(wants, WSet(key));
+			}
+			Seq(ref es) => {
+				for e in es.iter() {
+					if !self.heads_of(e, wants) {
+						return false
+					}
+					if !self.first_set(e).eps {
+						break
+					}
+				}
+			}
+			Alt(ref es) => {
+				for e in es.iter() {
+					if !self.heads_of(e, wants) {
+						return false
+					}
+				}
+			}
+			// a span never fails, and an optional class expects nothing
+			Rep(ref e, rep) => match (&**e, rep) {
+				(&Cls(..), ZeroMore) | (&Cls(..), ZeroOne) => {}
+				(e, _) => return self.heads_of(e, wants)
+			},
+			Cap(_, _, ref e) | Table(_, ref e) | Subst(_, ref e) | Group(_, _, ref e) |
+			RunTime(_, _, ref e) | Format(_, _, ref e) | Num(_, _, ref e) |
+			Function(_, _, ref e) | Fold(_, _, ref e) => return self.heads_of(&**e, wants),
+			// a rule that fails where it's called is expected in place of
+			// what it tried
+			NonTerm(ref name) => match self.resolve(name.as_slice()) {
+				Ok(key) => want(wants, WRule(key)),
+				Err(_) => return false
+			},
+			_ => return false
+		}
+		true
+	}
 
-			_ => { fail!("not implemented: {}", ast) }
+	// The heads key of what a test expects if it jumps.
+	fn add_heads(&mut self, wants: Vec<Want>) -> uint {
+		match self.heads.iter().position(|h| *h == wants) {
+			Some(key) => key,
+			None => {
+				self.heads.push(wants);
+				self.heads.len() - 1
+			}
 		}
 	}
 
+	// The key of the rule a nonterminal refers to, in the innermost grammar.
+	fn resolve(&self, name: &str) -> Result<uint, Error> {
+		match self.scopes.last() {
+			None => err(format!("rule '{}' used outside a grammar", name)),
+			Some(keys) => {
+				for &key in keys.iter() {
+					if self.rules[key].as_slice() == name {
+						return Ok(key)
+					}
+				}
+				err(format!("rule '{}' undefined in given grammar", name))
+			}
+		}
+	}
+
+	// The numbers of the captures with the name each back-reference refers to.
+	fn resolve_backrefs(&self) -> Vec<Vec<uint>> {
+		self.backrefs.iter().map(|name| {
+			range(0, self.names.len()).filter(|&n| self.names[n].as_ref() == Some(name)).collect()
+		}).collect()
+	}
+
+	// Turns every IOpenCall into an ICall to its rule.
+	fn link(&mut self) {
+		for i in range(0, self.insts.len()) {
+			let key = match self.insts[i] {
+				IOpenCall(key) => key,
+				_ => continue
+			};
+			let offset = self.rule_pos[key] as int - i as int;
+			*self.insts.get_mut(i) = ICall(offset);
+		}
+	}
+
+
+    // A capture of what `e` matches:
+    //      OpenCapture kind n
+    //      e
+    //      CloseCapture
+    fn compile_capture(&mut self, kind: CapKind, num: uint, name: Option<String>,
+                       e: Ast) -> Result<(), Error> {
+        self.name_capture(num, name);
+        self.push(IOpenCapture(kind, num));
+        try!(self.compile(e));
+        self.push(ICloseCapture);
+        Ok(())
+    }
+
+    /// Records the name, if any, of capture number `num`.
+    fn name_capture(&mut self, num: uint, name: Option<String>) {
+        while self.names.len() <= num {
+            self.names.push(None);
+        }
+        *self.names.get_mut(num) = name;
+    }
+
+    /// Records what the value of capture number `num` is made from.
+    fn set_param(&mut self, num: uint, param: Param) {
+        while self.params.len() <= num {
+            self.params.push(NoParam);
+        }
+        *self.params.get_mut(num) = param;
+    }
+
+    /// Adds a char class to the program's table of sets, and returns its key.
+    fn add_set(&mut self, ranges: &[(char, char)], flags: Flags) -> uint {
+        self.sets.push(Charset::new(ranges, flags));
+        self.sets.len() - 1
+    }
+
     /// Appends the given instruction to the program.
     #[inline]
     fn push(&mut self, x: Opcode) {
         self.insts.push(x)
     }
 
+    /// Appends a jump-type instruction whose offset is not yet known, and
+    /// returns its index so that `patch` can fill the offset in later.
+    #[inline]
+    fn push_hole(&mut self, x: Opcode) -> uint {
+        self.insts.push(x);
+        self.insts.len() - 1
+    }
+
+    /// Index of the next instruction to be pushed.
+    #[inline]
+    fn here(&self) -> uint {
+        self.insts.len()
+    }
+
+    /// Sets the offset of the jump-type instruction at index `i` so that it
+    /// targets the instruction at index `target`.
+    /// If the instruction at index `i` doesn't take an offset, then
+    /// `fail!` is called.
+    fn patch(&mut self, i: uint, target: uint) {
+        let offset = target as int - i as int;
+        let inst = self.insts.get_mut(i);
+        *inst = match inst.with_offset(offset) {
+            Some(op) => op,
+            None => fail!("BUG: can't patch offset of {}", *inst),
+        }
+    }
+
 //    /// Appends an *empty* `Split` instruction to the program and returns
 //    /// the index of that instruction. (The index can then be used to "patch"
 //    /// the actual locations of the split in later.)
@@ -126,6 +801,200 @@ impl Compiler {
 //    }
 }
 
+// Whether a test of `e`'s first char is all `e` can fail at: it matches
+// one char, any char its first set holds.
+fn headfail(e: &Ast) -> bool {
+	match *e {
+		Lit(ref s, flags) => flags & FLAG_NOCASE == 0 && s.as_slice().chars().count() == 1,
+		Cls(_, flags) => flags & FLAG_NOCASE == 0,
+		Dot(_) => true,
+		_ => false
+	}
+}
+
+// Adds `w` to what's expected, if it isn't there already.
+fn want(wants: &mut Vec<Want>, w: Want) {
+	if !wants.contains(&w) {
+		wants.push(w);
+	}
+}
+
+#[cfg(test)]
+fn run(ast: Ast, input: &str) -> Option<uint> {
+	use vm::Vm;
+	let mut vm = Vm::new(Program::new(ast).unwrap());
+	vm.do_match(input).map(|m| m.len())
+}
+
+#[test]
+fn compile_alt() {
+	use ast::{lit, alt, alt3, seq};
+	assert_eq!(run(alt(lit("ab"), lit("a")), "ab"), Some(2));
+	assert_eq!(run(alt(lit("ab"), lit("a")), "ac"), Some(1));
+	assert_eq!(run(alt3(lit("x"), lit("y"), lit("z")), "z"), Some(1));
+	// ordered: the first alternative that matches wins
+	assert_eq!(run(seq(alt(lit("a"), lit("ab")), lit("c")), "abc"), None);
+}
+
+#[test]
+fn compile_rep() {
+	use ast::{lit, many, some, opt, seq};
+	assert_eq!(run(many(lit("ab")), "ababa"), Some(4));
+	assert_eq!(run(many(lit("ab")), "x"), Some(0));
+	assert_eq!(run(seq(some(lit("a")), lit("b")), "aaab"), Some(4));
+	assert_eq!(run(seq(opt(lit("a")), lit("b")), "ab"), Some(2));
+	assert_eq!(run(seq(opt(lit("a")), lit("b")), "b"), Some(1));
+	// repetition is possessive: it never gives back what it matched
+	assert_eq!(run(seq(many(lit("a")), lit("a")), "aaa"), None);
+}
+
+#[test]
+fn compile_predicates() {
+	use ast::{lit, alt, seq, seq3, many, not, and, dot};
+	// &e matches without consuming
+	assert_eq!(run(seq(and(lit("ab")), dot()), "ab"), Some(1));
+	assert_eq!(run(alt(seq(and(lit("b")), dot()), lit("a")), "a"), Some(1));
+	// !e succeeds only where e fails
+	assert_eq!(run(seq(not(lit("b")), dot()), "a"), Some(1));
+	assert_eq!(run(alt(seq(lit("a"), not(lit("b"))), lit("ab")), "ab"), Some(2));
+	// everything up to the first 'x'
+	assert_eq!(run(seq3(many(seq(not(lit("x")), dot())), lit("x"), many(dot())), "abxcd"), Some(5));
+}
+
+#[test]
+fn compile_grammar() {
+	use parse::parse;
+	// a^n b^n, which no regex can match
+	let anbn = parse("S <- 'a' S 'b' / ''").unwrap();
+	assert_eq!(run(anbn.clone(), "aaabbb"), Some(6));
+	assert_eq!(run(anbn.clone(), "aaabb"), Some(0));
+	// mutual recursion; balanced parentheses
+	let parens = parse("P <- '(' L ')'  L <- P*").unwrap();
+	assert_eq!(run(parens.clone(), "(()(()))x"), Some(8));
+	assert_eq!(run(parens, "(()"), None);
+	// a nested grammar has rules of its own, which may reuse outer names
+	let nested = parse("S <- A (A <- 'b' B  B <- 'c')  A <- 'a'").unwrap();
+	assert_eq!(run(nested, "abc"), Some(3));
+}
+
+#[test]
+fn compile_tests() {
+	use parse::parse;
+	fn prog(src: &str) -> Program { Program::unoptimized(parse(src).unwrap()).unwrap() }
+	// a choice between single chars needs no choice entry
+	assert_eq!(prog("'a' / 'b'").insts, vec!(ITestChar('a', 3, 0), IChar('a', 0), IJmp(2),
+	                                          IChar('b', 0), IEnd));
+	// nor does one whose alternatives begin differently
+	assert_eq!(prog("'ab' / [0-9]").insts, vec!(ITestChar('a', 4, 0), IChar('a', 0), IChar('b', 0),
+	                                             IJmp(2), ISet(0), IEnd));
+	// but one whose alternatives begin alike does, once the test has passed
+	assert_eq!(prog("'ab' / 'ac'").insts, vec!(ITestChar('a', 5, 0), IChoice(4), IChar('a', 0),
+	                                            IChar('b', 0), ICommit(3), IChar('a', 0),
+	                                            IChar('c', 0), IEnd));
+	// loops and options
+	assert_eq!(prog("'a'*").insts, vec!(ITestChar('a', 3, 0), IChar('a', 0), IJmp(-2), IEnd));
+	assert_eq!(prog("'a'?").insts, vec!(ITestChar('a', 2, 0), IChar('a', 0), IEnd));
+	assert_eq!(prog("('a' 'b')*").insts, vec!(ITestChar('a', 5, 0), IChoice(4), IChar('a', 0),
+	                                           IChar('b', 0), IPartialCommit(-2), IEnd));
+	// what each test expects is what its pattern would have, failing
+	let p = prog("S <- A / '-'? [0-9] / 'y'  A <- 'a'");
+	assert_eq!(p.heads, vec!(vec!(WRule(1)), vec!(WChar('-'), WSet(4)), vec!(WChar('-'))));
+	// an alternative that can match the empty string isn't tested for
+	assert_eq!(prog("'a'? / 'b'").insts, vec!(IChoice(4), ITestChar('a', 2, 0), IChar('a', 0),
+	                                           ICommit(2), IChar('b', 0), IEnd));
+}
+
+#[test]
+fn compile_start_set() {
+	use parse::parse;
+	fn start(src: &str) -> Option<Charset> {
+		let p = Program::new(parse(src).unwrap()).unwrap();
+		p.start.map(|key| p.sets[key].clone())
+	}
+	assert_eq!(start("'ab' / [0-9]+"), Some(Charset::new(&[('0', '9'), ('a', 'a')], FLAG_NORMAL)));
+	assert_eq!(start("S <- A 'x'  A <- 'y'?"), Some(Charset::new(&[('x', 'y')], FLAG_NORMAL)));
+	// a pattern that can match the empty string can match anywhere
+	assert_eq!(start("'a'*"), None);
+	assert_eq!(start("&'a'"), None);
+}
+
+#[test]
+fn compile_left_recursion() {
+	use parse::parse;
+	fn heads(src: &str) -> Vec<bool> {
+		match parse(src).unwrap() {
+			Grammar(rules) => left_recursive(rules.as_slice()),
+			_ => unreachable!()
+		}
+	}
+	assert_eq!(heads("E <- E '-' T / T  T <- [0-9]"), vec!(true, false));
+	assert_eq!(heads("S <- 'a' S / 'b'"), vec!(false));
+	// through a rule that may match nothing
+	assert_eq!(heads("S <- O S 'x' / 'y'  O <- 'o'?"), vec!(true, false));
+	// one head is enough for a cycle
+	assert_eq!(heads("A <- B 'a' / 'x'  B <- A 'b'"), vec!(true, false));
+	assert_eq!(heads("A <- 'a' B  B <- B 'b' / A"), vec!(false, true));
+
+	let sub = parse("E <- E '-' T / T  T <- [0-9]").unwrap();
+	assert_eq!(run(sub.clone(), "1-2-3"), Some(5));
+	assert_eq!(run(sub, "1-"), Some(1));
+	let arith = parse("E <- E '+' T / T  T <- T '*' F / F  F <- [0-9] / '(' E ')'").unwrap();
+	assert_eq!(run(arith, "1+2*3+(4*5+6)*7"), Some(15));
+	// indirect
+	let ind = parse("A <- B 'a' / 'x'  B <- A 'b'").unwrap();
+	assert_eq!(run(ind.clone(), "xbaba"), Some(5));
+	assert_eq!(run(ind, "xbab"), Some(3));
+}
+
+#[test]
+fn compile_grammar_errors() {
+	use parse::parse;
+	fn msg(src: &str) -> String { Program::new(parse(src).unwrap()).err().unwrap().msg }
+	assert_eq!(msg("S <- 'a' T"), "rule 'T' undefined in given grammar".to_string());
+	// verify's message, which stops the grammar before it's compiled
+	let twice = parse("S <- 'a'  S <- 'b'").unwrap();
+	assert_eq!(format!("{}", verify(&twice)[0]), "rule 'S' is defined more than once".to_string());
+	assert_eq!(msg("S <- 'a'  S <- 'b'"), format!("{}", verify(&twice)[0]));
+	// an inner grammar can't see the rules of an outer one
+	assert_eq!(msg("S <- (A <- B)  B <- 'b'"), "rule 'B' undefined in given grammar".to_string());
+	// loops that would never end
+	assert_eq!(msg("('a'?)*"), "a loop's body can match the empty string".to_string());
+}
+
+#[test]
+fn compile_captures() {
+	use parse::parse;
+	use value::Int;
+	let p = Program::new(parse("{:word: [a-z]+ } {}").unwrap()).unwrap();
+	assert_eq!(p.insts, vec!(IOpenCapture(Csimple, 1), ISet(0), ISpan(1), ICloseCapture,
+	                         IFullCapture(Cposition, 2, 0), IEnd));
+	assert_eq!(p.names, vec!(None, Some("word".to_string()), None));
+	// values
+	let p = Program::new(parse("$1 %2 [a]+ -> f").unwrap()).unwrap();
+	assert_eq!(p.insts, vec!(IFullCapture(Cconst, 1, 0), IFullCapture(Carg, 2, 0),
+	                         IOpenCapture(Cfunction, 3), ISet(0), ISpan(1), ICloseCapture, IEnd));
+	assert_eq!(p.params, vec!(NoParam, ConstParam(Int(1)), ArgParam(2), FnParam("f".to_string())));
+}
+
+#[test]
+fn compile_classes() {
+	use parse::parse;
+	fn prog(src: &str) -> Vec<Opcode> { Program::new(parse(src).unwrap()).unwrap().insts }
+	assert_eq!(prog("[a-z]"), vec!(ISet(0), IEnd));
+	assert_eq!(prog("[a-z]*"), vec!(ISpan(0), IEnd));
+	assert_eq!(prog("[a-z]+"), vec!(ISet(0), ISpan(1), IEnd));
+	assert_eq!(prog("[a-z]?"), vec!(ITestSet(0, 2, 0), IAny(FLAG_NORMAL), IEnd));
+
+	let ident = parse("[a-zA-Z_] [a-zA-Z0-9_]*").unwrap();
+	assert_eq!(run(ident.clone(), "foo_42 = 1"), Some(6));
+	assert_eq!(run(ident, "42"), None);
+	assert_eq!(run(parse("[^\\n]* '\\n'").unwrap(), "a line\nnext"), Some(7));
+	assert_eq!(run(parse("[0-9]? 'x'").unwrap(), "7x"), Some(2));
+	assert_eq!(run(parse("[0-9]? 'x'").unwrap(), "x"), Some(1));
+	// chars beyond ASCII
+	assert_eq!(run(parse("[\u03b1-\u03c9]+").unwrap(), "\u03bb\u03b1\u03bc\u03b2\u03b4\u03b1!"), Some(12));
+}
+
 /*
 #[deriving(Show, Clone)]
 enum Inst {
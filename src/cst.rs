@@ -0,0 +1,210 @@
+//! Concrete syntax trees: the rules a match called, and the text each one
+//! matched, with nothing left out.  The VM records them in CST mode (see
+//! `Vm::record_rules`), with no capture markers in the grammar.
+//!
+//! The text of a node is split among its children: the nodes of the rules
+//! it called, and the text it matched itself, as tokens.  The text of the
+//! rules that are named as trivia, such as whitespace and comments, is kept
+//! as a leaf, so that the leaves of a tree, in order, are the text it was
+//! built from.  So is the text that error recovery skipped over (see
+//! `Vm::recover`), as an ERROR leaf of the node it was skipped in.
+
+use code;
+use code::BytePos;
+use capture::{Cclose, Crule};
+
+/// A node of a concrete syntax tree: a rule's match, or the root, which is
+/// the whole match.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Cst<'t> {
+    /// The rule; None for the root.
+    pub rule: Option<String>,
+    pub start: uint,
+    pub end: uint,
+    pub text: &'t str,
+    pub children: Vec<Child<'t>>,
+}
+
+/// A child of a node of a concrete syntax tree.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Child<'t> {
+    /// The match of a rule the node's rule called.
+    RuleNode(Cst<'t>),
+    /// Text the node's rule matched itself.
+    Token(&'t str),
+    /// The text a trivia rule matched.
+    Trivia(&'t str),
+    /// Text that error recovery skipped over.
+    ErrorNode(&'t str),
+}
+
+impl<'t> Cst<'t> {
+    /// The text of the tree's leaves, in order: the text the tree was built
+    /// from.
+    pub fn to_source(&self) -> String {
+        let mut s = String::new();
+        self.write_source(&mut s);
+        s
+    }
+
+    fn write_source(&self, s: &mut String) {
+        for child in self.children.iter() {
+            match *child {
+                RuleNode(ref node) => node.write_source(s),
+                Token(text) | Trivia(text) | ErrorNode(text) => s.push_str(text),
+            }
+        }
+    }
+
+    /// The nodes of the rules this node's rule called, in order.
+    pub fn nodes(&self) -> Vec<&Cst<'t>> {
+        self.children.iter().filter_map(|c| match *c {
+            RuleNode(ref node) => Some(node),
+            _ => None
+        }).collect()
+    }
+}
+
+// A node being built: its rule, where it starts, and the end of the text
+// its children cover so far.
+struct Open<'t> {
+    rule: Option<uint>,
+    start: uint,
+    pos: uint,
+    children: Vec<Child<'t>>,
+}
+
+/// Builds the concrete syntax tree of the rule nodes in the VM's capture
+/// list, under a root node for the match, which is the first `end` bytes
+/// of the input.  `rules` are the program's rules, which the nodes are
+/// keyed by, and the text of those named in `trivia` is kept as trivia.
+/// Other captures in the list are passed over.  The text of the `skipped`
+/// spans, which are sorted, is an error wherever it is.
+pub fn build<'t>(input: &'t str, end: uint, list: &[code::Capture], rules: &[(String, uint)],
+                 trivia: &[&str], skipped: &[(uint, uint)]) -> Cst<'t> {
+    let mut open = vec!(Open { rule: None, start: 0, pos: 0, children: vec!() });
+    // whether each capture still open is a rule's node
+    let mut is_rule = vec!();
+    for entry in list.iter() {
+        let BytePos(pos) = entry.pos;
+        match (entry.kind, entry.len) {
+            (Crule, None) => {
+                is_rule.push(true);
+                open.push(Open { rule: Some(entry.key), start: pos, pos: pos, children: vec!() });
+            }
+            (Cclose, _) => {
+                if !is_rule.pop().expect("BUG: close of a capture that wasn't opened") {
+                    continue
+                }
+                let node = finish(input, open.pop().unwrap(), pos, rules, skipped);
+                let parent = open.last_mut().unwrap();
+                add_text(input, &mut parent.children, parent.pos, node.start, skipped, Token);
+                let name = node.rule.as_ref().map(|r| r.as_slice()).unwrap();
+                if trivia.contains(&name) {
+                    add_text(input, &mut parent.children, node.start, node.end, skipped, Trivia);
+                } else {
+                    parent.children.push(RuleNode(node));
+                }
+                parent.pos = pos;
+            }
+            (_, None) => is_rule.push(false),
+            _ => {}
+        }
+    }
+    assert!(open.len() == 1);
+    finish(input, open.pop().unwrap(), end, rules, skipped)
+}
+
+// Adds the text from `from` to `to` to a node's children, as `leaf`s, but
+// for the skipped spans in it, which are errors.
+fn add_text<'t>(input: &'t str, children: &mut Vec<Child<'t>>, from: uint, to: uint,
+                skipped: &[(uint, uint)], leaf: fn(&'t str) -> Child<'t>) {
+    let mut pos = from;
+    for &(start, end) in skipped.iter() {
+        if end <= pos || start >= to {
+            continue
+        }
+        if start > pos {
+            children.push(leaf(input.slice(pos, start)));
+        }
+        let end = if end < to { end } else { to };
+        children.push(ErrorNode(input.slice(if start > pos { start } else { pos }, end)));
+        pos = end;
+    }
+    if to > pos {
+        children.push(leaf(input.slice(pos, to)));
+    }
+}
+
+fn finish<'t>(input: &'t str, mut node: Open<'t>, end: uint, rules: &[(String, uint)],
+              skipped: &[(uint, uint)]) -> Cst<'t> {
+    add_text(input, &mut node.children, node.pos, end, skipped, Token);
+    let Open { rule, start, children, .. } = node;
+    Cst {
+        rule: rule.map(|k| { let (ref name, _) = rules[k]; name.clone() }),
+        start: start,
+        end: end,
+        text: input.slice(start, end),
+        children: children,
+    }
+}
+
+#[test]
+fn cst_round_trip() {
+    use peg::Peg;
+    // the shape of a tree, with trivia as '~'
+    fn shape(node: &Cst) -> String {
+        let kids: Vec<String> = node.children.iter().map(|c| match *c {
+            RuleNode(ref n) => shape(n),
+            Token(text) => text.to_string(),
+            Trivia(_) => "~".to_string(),
+            ErrorNode(text) => format!("<{}>", text),
+        }).collect();
+        format!("({} {})", node.rule.as_ref().map(|r| r.as_slice()).unwrap_or("^"), kids.connect(" "))
+    }
+    let peg = Peg::new("
+        doc <- sp (form sp)* !.
+        form <- list / atom
+        list <- '(' sp (form sp)* ')'
+        atom <- [a-z0-9]+
+        sp <- ([ \n]+ / comment)*
+        comment <- ';' (!'\n' .)*").unwrap();
+    let src = "; hi\n(a (b 12) ; x\n c)\n";
+    let cst = peg.cst(src, &["sp"]).unwrap();
+    assert_eq!(cst.to_source(), src.to_string());
+    assert_eq!(shape(&cst), "(^ (doc ~ (form (list ( (form (atom a)) ~ (form (list ( \
+                             (form (atom b)) ~ (form (atom 12)) ))) ~ (form (atom c)) ))) ~))".to_string());
+    let doc = cst.nodes()[0];
+    assert_eq!((doc.start, doc.end), (0, src.len()));
+    assert!(peg.cst("(a", &["sp"]).is_none());
+    // without trivia, the whitespace rules are nodes too
+    let cst = peg.cst(" a", &[]).unwrap();
+    assert_eq!(shape(&cst), "(^ (doc (sp  ) (form (atom a)) (sp )))".to_string());
+    // captures are made as before, once CST mode is off
+    assert_eq!(peg.tree("(a)").unwrap().children.len(), 0);
+}
+
+#[test]
+fn cst_backtracking() {
+    use peg::Peg;
+    use vm::Vm;
+    use compile::Program;
+    use parse::parse;
+    // the nodes of an alternative that failed are dropped
+    let peg = Peg::new("S <- A 'x' / A B  A <- 'a'  B <- 'b'").unwrap();
+    let cst = peg.cst("ab", &[]).unwrap();
+    let s = cst.nodes()[0];
+    let rules: Vec<&str> = s.nodes().iter().map(|n| n.rule.as_ref().unwrap().as_slice()).collect();
+    assert_eq!(rules, vec!("A", "B"));
+    // memoized calls and left recursion record nodes as well
+    let mut vm = Vm::new(Program::new(parse("S <- A 'x' / A B  A <- 'a'  B <- 'b'").unwrap()).unwrap());
+    vm.memoize_all(100);
+    vm.record_rules(true);
+    assert_eq!(vm.do_match("ab"), Some("ab"));
+    assert_eq!(vm.get_cst("ab", 2, &[]), cst);
+    let peg = Peg::new("E <- E '-' N / N  N <- [0-9]").unwrap();
+    let cst = peg.cst("1-2-3", &[]).unwrap();
+    assert_eq!(cst.to_source(), "1-2-3".to_string());
+    let e = cst.nodes()[0];
+    assert_eq!(e.nodes().iter().map(|n| n.text).collect::<Vec<&str>>(), vec!("1-2", "3"));
+}
@@ -0,0 +1,160 @@
+//! Why a match failed: where, as the farthest position in the input the
+//! match reached, and what it expected to find there.  Or, for a labeled
+//! failure, where it was thrown, and its label.  Or, for a match that a
+//! match-time function broke off, why.
+
+use std::fmt;
+
+/// Something a match expected to find, at the position it failed.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Expected {
+    ExpectChar(char),
+    /// A char class, as it would be written in a grammar.
+    ExpectClass(String),
+    ExpectAny,
+    /// A rule that failed where it was called, without matching anything.
+    ExpectRule(String),
+}
+
+impl Expected {
+    fn describe(&self) -> String {
+        match *self {
+            ExpectChar(c) => quote(c),
+            ExpectClass(ref class) => class.clone(),
+            ExpectAny => "any character".to_string(),
+            ExpectRule(ref name) => name.clone(),
+        }
+    }
+}
+
+/// A failed match: the farthest position in the input it reached, as a
+/// byte offset and as a line and column, and what it expected there.
+#[deriving(Clone,PartialEq)]
+pub struct MatchError {
+    pub pos: uint,
+    /// The line and column of `pos`, from 1; the column counts chars.
+    pub line: uint,
+    pub col: uint,
+    pub expected: Vec<Expected>,
+    /// The label of a labeled failure, `^label`, thrown at `pos`.
+    pub label: Option<String>,
+    /// What kept the match from running to an end: a match-time function
+    /// that wasn't given, or one that moved the match somewhere it can't go.
+    pub fault: Option<String>,
+    /// The char at `pos`; None at the end of the input.
+    pub found: Option<char>,
+    /// The line of the input that `pos` is on, and under it, a caret at
+    /// `pos`.
+    pub snippet: String,
+}
+
+impl MatchError {
+    pub fn new(input: &str, pos: uint, expected: Vec<Expected>) -> MatchError {
+        let line_start = match input.slice_to(pos).rfind('\n') {
+            Some(k) => k + 1,
+            None => 0
+        };
+        let line_end = match input.slice_from(pos).find('\n') {
+            Some(k) => pos + k,
+            None => input.len()
+        };
+        let before = input.slice(line_start, pos);
+        let mut snippet = input.slice(line_start, line_end).to_string();
+        snippet.push('\n');
+        // tabs are kept, so that the caret lines up under them
+        for c in before.chars() {
+            snippet.push(if c == '\t' { '\t' } else { ' ' });
+        }
+        snippet.push('^');
+        MatchError {
+            pos: pos,
+            line: input.slice_to(pos).chars().filter(|&c| c == '\n').count() + 1,
+            col: before.chars().count() + 1,
+            expected: expected,
+            label: None,
+            fault: None,
+            found: input.slice_from(pos).chars().next(),
+            snippet: snippet,
+        }
+    }
+
+    /// The error of a labeled failure thrown at `pos`.
+    pub fn labeled(input: &str, pos: uint, label: &str) -> MatchError {
+        let mut e = MatchError::new(input, pos, vec!());
+        e.label = Some(label.to_string());
+        e
+    }
+
+    /// The error of a match broken off at `pos`, for the reason given.
+    pub fn fault(input: &str, pos: uint, why: &str) -> MatchError {
+        let mut e = MatchError::new(input, pos, vec!());
+        e.fault = Some(why.to_string());
+        e
+    }
+}
+
+impl fmt::Show for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let found = match self.found {
+            Some(c) => quote(c),
+            None => "end of input".to_string()
+        };
+        try!(write!(f, "line {}, column {}: ", self.line, self.col));
+        let n = self.expected.len();
+        if self.fault.is_some() {
+            try!(write!(f, "{}", self.fault.as_ref().unwrap()));
+        } else if self.label.is_some() {
+            try!(write!(f, "{}, found {}", self.label.as_ref().unwrap(), found));
+        } else if n == 0 {
+            try!(write!(f, "unexpected {}", found));
+        } else {
+            let items: Vec<String> = self.expected.iter().map(|e| e.describe()).collect();
+            let list = if n == 1 {
+                items[0].clone()
+            } else {
+                format!("{} or {}", items.slice_to(n - 1).connect(", "), items[n - 1])
+            };
+            try!(write!(f, "expected {}, found {}", list, found));
+        }
+        write!(f, "\n{}", self.snippet)
+    }
+}
+
+// A char, quoted as in a grammar's literal.
+fn quote(c: char) -> String {
+    let mut s = "'".to_string();
+    s.push_str(escape(c).as_slice());
+    s.push('\'');
+    s
+}
+
+/// A char as it's written in a literal or a class: with a backslash if it's
+/// a newline, tab or return, a quote, or a backslash.
+pub fn escape(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\'' | '\\' => format!("\\{}", c),
+        c => c.to_string()
+    }
+}
+
+#[test]
+fn match_error_position() {
+    let input = "a = 1\nb =\t@ 2\n";
+    let e = MatchError::new(input, 10, vec!(ExpectChar('"'), ExpectClass("[0-9]".to_string()),
+                                             ExpectRule("value".to_string())));
+    assert_eq!((e.line, e.col, e.found), (2, 5, Some('@')));
+    assert_eq!(format!("{}", e), "line 2, column 5: expected '\"', [0-9] or value, found '@'\n\
+                                  b =\t@ 2\n   \t^".to_string());
+    let e = MatchError::new("ab", 2, vec!(ExpectChar('\n')));
+    assert_eq!(format!("{}", e), "line 1, column 3: expected '\\n', found end of input\nab\n  ^".to_string());
+    let e = MatchError::new("", 0, vec!());
+    assert_eq!(format!("{}", e), "line 1, column 1: unexpected end of input\n\n^".to_string());
+    let e = MatchError::labeled("f(a", 3, "MissingParen");
+    assert_eq!(format!("{}", e), "line 1, column 4: MissingParen, found end of input\nf(a\n   ^".to_string());
+    let e = MatchError::fault("ab", 1, "no function given for match-time captures of 'f'");
+    assert_eq!(format!("{}", e), "line 1, column 2: no function given for match-time captures of 'f'\n\
+                                  ab\n ^".to_string());
+}
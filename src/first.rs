@@ -0,0 +1,431 @@
+//! First and follow sets: the chars a pattern's match can begin with, and
+//! the chars that can come after a rule's match, in the grammar it's in.
+//! Error recovery resynchronizes on them, skipping input up to a char that
+//! what was expected could begin with, or that could follow the rule the
+//! error was in.
+//!
+//! The sets are of chars, not tokens, and may hold more than the pattern
+//! can really begin with: predicates, back-references and match-time
+//! captures are taken to allow anything.  They never hold less.
+
+use std::char;
+use ast::{Ast, Rule, Nil, Lit, Dot, Cls, Seq, Alt, Rep, And, Not, Cap, Pos, Table, Group, Subst,
+          Backref, RunTime, Const, Arg, Format, Num, Function, Fold, Throw, Grammar, NonTerm};
+use ast::{FLAG_NOCASE, FLAG_NEGATED, ZeroOne, OneMore};
+
+/// A set of chars, as sorted ranges that neither overlap nor touch, and ε:
+/// in a first set, whether the pattern can match the empty string; in a
+/// follow set, whether the end of the input can follow.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Chars {
+    pub ranges: Vec<(char, char)>,
+    pub eps: bool,
+}
+
+impl Chars {
+    pub fn none() -> Chars {
+        Chars { ranges: vec!(), eps: false }
+    }
+
+    pub fn empty_string() -> Chars {
+        Chars { ranges: vec!(), eps: true }
+    }
+
+    /// Every char.
+    pub fn all() -> Chars {
+        Chars { ranges: vec!(('\0', char::MAX)), eps: false }
+    }
+
+    /// Whether it holds every char.  The surrogates, which aren't chars,
+    /// needn't be in it.
+    pub fn is_all(&self) -> bool {
+        let n = self.ranges.iter().fold(0, |n, &(a, b)| n + (b as u32 - a as u32 + 1));
+        n >= char::MAX as u32 + 1 - 0x800
+    }
+
+    /// Whether no char is in both sets.
+    pub fn disjoint(&self, other: &Chars) -> bool {
+        !self.ranges.iter().any(|&(a, b)| other.ranges.iter().any(|&(c, d)| a <= d && c <= b))
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(a, b)| a <= c && c <= b)
+    }
+
+    /// Adds the chars of `other`, and its ε.  Returns whether that added
+    /// anything.
+    pub fn add(&mut self, other: &Chars) -> bool {
+        let before = self.clone();
+        self.ranges.push_all(other.ranges.as_slice());
+        self.eps = self.eps || other.eps;
+        self.normalize();
+        *self != before
+    }
+
+    fn add_range(&mut self, a: char, b: char) {
+        self.ranges.push((a, b));
+        self.normalize();
+    }
+
+    fn normalize(&mut self) {
+        self.ranges.sort();
+        let mut merged: Vec<(char, char)> = vec!();
+        for &(a, b) in self.ranges.iter() {
+            let touches = match merged.last() {
+                Some(&(_, hi)) => a as u32 <= hi as u32 + 1,
+                None => false
+            };
+            if touches {
+                let last = merged.len() - 1;
+                let (lo, hi) = merged[last];
+                *merged.get_mut(last) = (lo, if b > hi { b } else { hi });
+            } else {
+                merged.push((a, b));
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+// The chars of a class, as `Charset` reads its flags: with both cases of
+// ASCII letters, and anything above ASCII, if it ignores case; and the chars
+// not in it, if it's negated.  A negated class that ignores case is taken
+// to hold what isn't in its ranges, and anything above ASCII: widening the
+// ranges before leaving them out would leave out chars it holds.
+fn class(ranges: &[(char, char)], flags: u8) -> Chars {
+    let mut set = Chars::none();
+    for &(a, b) in ranges.iter() {
+        set.add_range(a, b);
+    }
+    if flags & FLAG_NOCASE != 0 && flags & FLAG_NEGATED == 0 {
+        if set.ranges.iter().any(|&(a, b)| a <= 'z' && b >= 'A') {
+            set.add_range('a', 'z');
+            set.add_range('A', 'Z');
+        }
+        if set.ranges.iter().any(|&(_, b)| b as u32 >= 128) {
+            set.add_range('\u0080', char::MAX);
+        }
+    }
+    if flags & FLAG_NEGATED == 0 {
+        return set
+    }
+    // the gaps between the ranges, leaving out the surrogates, which
+    // aren't chars
+    let mut gaps = Chars::none();
+    let mut next: u32 = 0;
+    for &(a, b) in set.ranges.iter() {
+        if a as u32 > next {
+            add_chars(&mut gaps, next, a as u32 - 1);
+        }
+        next = b as u32 + 1;
+    }
+    if next <= char::MAX as u32 {
+        add_chars(&mut gaps, next, char::MAX as u32);
+    }
+    if flags & FLAG_NOCASE != 0 {
+        gaps.add_range('\u0080', char::MAX);
+    }
+    gaps
+}
+
+// Adds the chars from `a` to `b`, but for the surrogates.
+fn add_chars(set: &mut Chars, a: u32, b: u32) {
+    let (lo, hi) = (0xd800, 0xdfff);
+    if a < lo {
+        let end = if b < lo { b } else { lo - 1 };
+        set.add_range(char::from_u32(a).unwrap(), char::from_u32(end).unwrap());
+    }
+    if b > hi {
+        let start = if a > hi { a } else { hi + 1 };
+        set.add_range(char::from_u32(start).unwrap(), char::from_u32(b).unwrap());
+    }
+}
+
+fn rule_index(rules: &[Rule], name: &str) -> Option<uint> {
+    rules.iter().position(|r| r.name.as_slice() == name)
+}
+
+/// The first set of `ast`, a part of a grammar with `rules`, given the
+/// first sets of those rules.
+pub fn first(ast: &Ast, rules: &[Rule], firsts: &[Chars]) -> Chars {
+    match *ast {
+        Nil | Pos(..) | Const(..) | Arg(..) => Chars::empty_string(),
+        Lit(ref s, flags) => match s.as_slice().chars().next() {
+            Some(c) => class(&[(c, c)], flags & FLAG_NOCASE),
+            None => Chars::empty_string()
+        },
+        Dot(_) => Chars::all(),
+        Cls(ref ranges, flags) => class(ranges.as_slice(), flags),
+        Seq(ref es) => {
+            let mut set = Chars::empty_string();
+            for e in es.iter() {
+                let f = first(e, rules, firsts);
+                set.eps = false;
+                set.add(&f);
+                if !f.eps {
+                    break
+                }
+            }
+            set
+        }
+        Alt(ref es) => {
+            let mut set = Chars::none();
+            for e in es.iter() {
+                set.add(&first(e, rules, firsts));
+            }
+            set
+        }
+        Rep(ref e, rep) => {
+            let mut set = first(&**e, rules, firsts);
+            if rep != OneMore {
+                set.eps = true;
+            }
+            set
+        }
+        // a predicate consumes nothing; what comes after it does
+        And(_) | Not(_) => Chars::empty_string(),
+        Backref(_) => { let mut set = Chars::all(); set.eps = true; set }
+        // the function may move the match on from anywhere p leaves it
+        RunTime(_, _, ref e) => {
+            let mut set = first(&**e, rules, firsts);
+            if set.eps {
+                set.add(&Chars::all());
+            }
+            set
+        }
+        Cap(_, _, ref e) | Table(_, ref e) | Subst(_, ref e) | Group(_, _, ref e) |
+        Format(_, _, ref e) | Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => {
+            first(&**e, rules, firsts)
+        }
+        NonTerm(ref name) => match rule_index(rules, name.as_slice()) {
+            Some(n) => firsts[n].clone(),
+            None => Chars::none()
+        },
+        // a throw matches only what its recovery rule does; with none, it
+        // ends the match wherever it's reached
+        Throw(ref name) => match rule_index(rules, name.as_slice()) {
+            Some(n) => firsts[n].clone(),
+            None => { let mut set = Chars::all(); set.eps = true; set }
+        },
+        Grammar(ref inner) if !inner.is_empty() => first_sets(inner.as_slice())[0].clone(),
+        _ => Chars::none()
+    }
+}
+
+/// The first set of each rule of a grammar, found as a fixpoint.
+pub fn first_sets(rules: &[Rule]) -> Vec<Chars> {
+    let mut firsts = Vec::from_elem(rules.len(), Chars::none());
+    loop {
+        let mut changed = false;
+        for (n, r) in rules.iter().enumerate() {
+            let f = first(&r.body, rules, firsts.as_slice());
+            if firsts.get_mut(n).add(&f) {
+                changed = true;
+            }
+        }
+        if !changed {
+            return firsts
+        }
+    }
+}
+
+/// The follow set of each rule of a grammar: the chars that can come after
+/// a match of it, with ε if the end of the input can.  The end of the
+/// input follows the first rule.
+pub fn follow_sets(rules: &[Rule]) -> Vec<Chars> {
+    let firsts = first_sets(rules);
+    let mut follows = Vec::from_elem(rules.len(), Chars::none());
+    if !rules.is_empty() {
+        follows.get_mut(0).eps = true;
+    }
+    loop {
+        let mut changed = false;
+        for (n, r) in rules.iter().enumerate() {
+            let after = follows[n].clone();
+            changed = follow(&r.body, &after, rules, firsts.as_slice(), &mut follows) || changed;
+        }
+        if !changed {
+            return follows
+        }
+    }
+}
+
+// Adds `after`, the set of what can come after `ast`, to the follow sets of
+// the rules `ast` calls last.  Returns whether that added anything.
+fn follow(ast: &Ast, after: &Chars, rules: &[Rule], firsts: &[Chars],
+          follows: &mut Vec<Chars>) -> bool {
+    match *ast {
+        Seq(ref es) => {
+            let mut changed = false;
+            let mut after = after.clone();
+            for e in es.iter().rev() {
+                changed = follow(e, &after, rules, firsts, follows) || changed;
+                let f = first(e, rules, firsts);
+                let mut before = f.clone();
+                before.eps = false;
+                if f.eps {
+                    before.add(&after);
+                }
+                after = before;
+            }
+            changed
+        }
+        Alt(ref es) => {
+            let mut changed = false;
+            for e in es.iter() {
+                changed = follow(e, after, rules, firsts, follows) || changed;
+            }
+            changed
+        }
+        // a repeated pattern may be followed by itself
+        Rep(ref e, rep) if rep != ZeroOne => {
+            let mut again = first(&**e, rules, firsts);
+            again.eps = false;
+            again.add(after);
+            follow(&**e, &again, rules, firsts, follows)
+        }
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => {
+            follow(&**e, after, rules, firsts, follows)
+        }
+        NonTerm(ref name) | Throw(ref name) => match rule_index(rules, name.as_slice()) {
+            Some(n) => follows.get_mut(n).add(after),
+            None => false
+        },
+        // a nested grammar's rules are its own
+        _ => false
+    }
+}
+
+/// The literal that every match of `ast`, a part of a grammar with `rules`,
+/// begins with, as far as can be told; it may be empty.  Only literals that
+/// don't ignore case count.
+pub fn prefix(ast: &Ast, rules: &[Rule]) -> String {
+    let (prefix, _) = literal_prefix(ast, rules, &mut Vec::from_elem(rules.len(), None));
+    prefix
+}
+
+// The literal a match of `ast` begins with, and whether it's all of the
+// match.  `known` is that of each rule that's been looked into; a rule
+// that's still being looked into counts as having none, so that recursion
+// ends.
+fn literal_prefix(ast: &Ast, rules: &[Rule], known: &mut Vec<Option<(String, bool)>>)
+                  -> (String, bool) {
+    match *ast {
+        Nil | Pos(..) | Const(..) | Arg(..) | And(_) | Not(_) => (String::new(), true),
+        Lit(ref s, flags) if flags & FLAG_NOCASE == 0 => (s.clone(), true),
+        Seq(ref es) => {
+            let mut prefix = String::new();
+            for e in es.iter() {
+                let (p, all) = literal_prefix(e, rules, known);
+                prefix.push_str(p.as_slice());
+                if !all {
+                    return (prefix, false)
+                }
+            }
+            (prefix, true)
+        }
+        // what the alternatives' literals begin with
+        Alt(ref es) => {
+            let mut found: Option<(String, bool)> = None;
+            for e in es.iter() {
+                let (p, all) = literal_prefix(e, rules, known);
+                found = Some(match found {
+                    None => (p, all),
+                    Some((q, same)) => {
+                        let common: String = q.as_slice().chars().zip(p.as_slice().chars())
+                            .take_while(|&(a, b)| a == b).map(|(a, _)| a).collect();
+                        let same = same && all && common == q && common == p;
+                        (common, same)
+                    }
+                });
+            }
+            found.unwrap_or((String::new(), false))
+        }
+        Rep(ref e, OneMore) | RunTime(_, _, ref e) => {
+            let (p, _) = literal_prefix(&**e, rules, known);
+            (p, false)
+        }
+        Cap(_, _, ref e) | Table(_, ref e) | Subst(_, ref e) | Group(_, _, ref e) |
+        Format(_, _, ref e) | Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => {
+            literal_prefix(&**e, rules, known)
+        }
+        NonTerm(ref name) => match rule_index(rules, name.as_slice()) {
+            Some(n) => rule_prefix(n, rules, known),
+            None => (String::new(), false)
+        },
+        Grammar(ref inner) if !inner.is_empty() => {
+            rule_prefix(0, inner.as_slice(), &mut Vec::from_elem(inner.len(), None))
+        }
+        _ => (String::new(), false)
+    }
+}
+
+fn rule_prefix(n: uint, rules: &[Rule], known: &mut Vec<Option<(String, bool)>>) -> (String, bool) {
+    if known[n].is_some() {
+        return known[n].clone().unwrap()
+    }
+    *known.get_mut(n) = Some((String::new(), false));
+    let found = literal_prefix(&rules[n].body, rules, known);
+    *known.get_mut(n) = Some(found.clone());
+    found
+}
+
+#[test]
+fn first_and_follow_sets() {
+    use parse::parse;
+    let rules = match parse("
+        prog <- sp (stmt sp)* !.
+        stmt <- name sp '(' args? ')' ';' / 'x'?
+        args <- name (',' name)*
+        name <- [a-z]+
+        sp <- [ \\n]*").unwrap() {
+        Grammar(rules) => rules,
+        _ => unreachable!()
+    };
+    fn chars(ranges: &[(char, char)], eps: bool) -> Chars {
+        Chars { ranges: ranges.to_vec(), eps: eps }
+    }
+    let letters = ('a', 'z');
+    assert_eq!(first_sets(rules.as_slice()), vec!(
+        chars(&[('\n', '\n'), (' ', ' '), letters], true),
+        chars(&[letters], true),
+        chars(&[letters], false),
+        chars(&[letters], false),
+        chars(&[('\n', '\n'), (' ', ' ')], true)));
+    assert_eq!(follow_sets(rules.as_slice()), vec!(
+        chars(&[], true),
+        chars(&[('\n', '\n'), (' ', ' '), letters], true),
+        chars(&[(')', ')')], false),
+        chars(&[('\n', '\n'), (' ', ' '), ('(', ')'), (',', ',')], false),
+        chars(&[('\n', '\n'), (' ', ' '), ('(', '('), letters], true)));
+    // negated and case-blind classes
+    assert_eq!(class(&[('b', 'y')], FLAG_NEGATED).ranges,
+               vec!(('\0', 'a'), ('z', '\ud7ff'), ('\ue000', char::MAX)));
+    assert_eq!(class(&[('B', 'B')], FLAG_NOCASE).ranges, vec!(('A', 'Z'), letters));
+    // [^k] ignoring case holds 'b': all but the chars of its ranges
+    let set = class(&[('k', 'k')], FLAG_NOCASE | FLAG_NEGATED);
+    assert!(set.contains('b') && set.contains('B') && set.contains('é') && !set.contains('k'));
+    assert_eq!(set.ranges, vec!(('\0', 'j'), ('l', char::MAX)));
+}
+
+#[test]
+fn literal_prefixes() {
+    use parse::parse;
+    fn prefix_of(src: &str) -> String {
+        prefix(&parse(src).unwrap(), &[])
+    }
+    assert_eq!(prefix_of("'foo' [0-9]"), "foo".to_string());
+    assert_eq!(prefix_of("'ab' 'cd'* 'e'"), "ab".to_string());
+    assert_eq!(prefix_of("{:x: 'a' :} &'b' 'bc'+ 'd'"), "abc".to_string());
+    assert_eq!(prefix_of("'iff' / 'if' / 'ifé'"), "if".to_string());
+    assert_eq!(prefix_of("'éa' / 'éb'"), "é".to_string());
+    // through rules, but not round a recursive one again
+    assert_eq!(prefix_of("S <- '<' T  T <- 'a' S / 'b'"), "<".to_string());
+    assert_eq!(prefix_of("S <- K ' ' K  K <- 'key'"), "key key".to_string());
+    // none, where a literal may be missing or ignore case
+    assert_eq!(prefix_of("'a'? 'b'"), "".to_string());
+    assert_eq!(prefix(&Lit("ab".to_string(), FLAG_NOCASE), &[]), "".to_string());
+    assert_eq!(prefix_of("'a' / 'b'"), "".to_string());
+}
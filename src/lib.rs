@@ -9,13 +9,25 @@
 
 
 #![feature(macro_rules, phase)]
-#![feature(struct_variant, globs)]
+#![feature(struct_variant, globs, unboxed_closures)]
 #![allow(dead_code, unused_imports, unused_variable)]
 
 // Unicode tables for character classes are defined in libunicode
 extern crate unicode;
-
-pub use parse::Error;
+#[cfg(test)]
+extern crate test;
+
+pub use parse::{parse, Error};
+pub use ast::{Ast, Rule, Span};
+pub use compile::Program;
+pub use vm::{Vm, MatchFn, Anchor, Anchored, Whole, Unanchored};
+pub use verify::{verify, Diagnostic, Problem};
+pub use peg::{Peg, PegError, Parsed, Replacer, FindMatches, FindCaptures, Split};
+pub use capture::{Capture, CaptureError, Node, Nodes, RunTime, Outcome, Accept, Reject, ValueFn};
+pub use error::{MatchError, Expected, ExpectChar, ExpectClass, ExpectAny, ExpectRule};
+pub use cst::{Cst, Child, RuleNode, Token, Trivia, ErrorNode};
+pub use run::{Env, Action, RunError, NoMatch, ActionFailed};
+pub use value::{Value, Slice, Text, Int, Float, Bool, List, Map, Data};
 //pub use std::collections::HashMap;
 
 mod ast;
@@ -23,8 +35,16 @@ mod code;
 mod parse;
 mod compile;
 mod capture;
+mod value;
 mod peg;
+mod bootstrap;
 mod vm;
+mod verify;
+mod run;
+mod cst;
+mod error;
+mod first;
+mod optimize;
 
 // parse a string to an AST
 // compile the AST to a Program
@@ -0,0 +1,304 @@
+//! A peephole pass over the code of a compiled program, which shortens the
+//! paths the VM takes through it without changing what any match does:
+//! how far it gets, what it captures, or what it expects where it fails.
+//! `Program::new` runs it; `Program::unoptimized` leaves the code as the
+//! compiler wrote it.
+
+use compile::Program;
+use code::*;
+
+/// Optimizes a program's code.  In order:
+///
+/// - a jump to a jump goes where the last of the chain does, and a jump
+///   to a return, the end or a failure is one;
+/// - a call just before a return is a tail call, which returns through
+///   the caller's frame;
+/// - a loop that pushes a choice on each round, and commits back to it,
+///   updates the one choice with a partial commit instead;
+/// - code that can't be reached is removed;
+/// - a run of chars, with no jump into it, is matched by one instruction.
+pub fn optimize(program: &mut Program) {
+    collapse_jumps(&mut program.insts);
+    tail_calls(&mut program.insts);
+    choice_loops(&mut program.insts);
+    let mut keep = reachable(program);
+    merge_chars(program, &mut keep);
+    compact(program, keep.as_slice());
+}
+
+// The instruction a jump-type instruction at `i` goes to.
+fn target(insts: &[Opcode], i: uint) -> Option<uint> {
+    insts[i].offset().map(|offset| (i as int + offset) as uint)
+}
+
+// Where a chain of jumps from `i` ends.
+fn chain_end(insts: &[Opcode], i: uint) -> uint {
+    let mut i = i;
+    // a chain that loops is followed no further than it's long
+    for _ in range(0, insts.len()) {
+        match insts[i] {
+            IJmp(offset) => i = (i as int + offset) as uint,
+            _ => break
+        }
+    }
+    i
+}
+
+// Points each jump at the end of the chain of jumps it lands on, but for
+// calls, whose targets begin rules.
+fn collapse_jumps(insts: &mut Vec<Opcode>) {
+    for i in range(0, insts.len()) {
+        let op = insts[i];
+        let end = match (op, target(insts.as_slice(), i)) {
+            (ICall(_), _) | (ITailCall(_), _) | (_, None) => continue,
+            (_, Some(t)) => chain_end(insts.as_slice(), t)
+        };
+        *insts.get_mut(i) = match (op, insts[end]) {
+            (IJmp(_), IRet) | (IJmp(_), IEnd) | (IJmp(_), IFail) => insts[end],
+            _ => op.with_offset(end as int - i as int).unwrap()
+        };
+    }
+}
+
+//      Call L          TailCall L
+//      Ret       ⇒     Ret
+// The return stays, for when the caller's frame can't be shared.
+fn tail_calls(insts: &mut Vec<Opcode>) {
+    for i in range(1, insts.len()) {
+        match (insts[i - 1], insts[i]) {
+            (ICall(offset), IRet) => *insts.get_mut(i - 1) = ITailCall(offset),
+            _ => {}
+        }
+    }
+}
+
+// L1:  Choice L2                   Choice L2
+//      p                     ⇒    L1: p
+//      Commit L1                   PartialCommit L1
+// L2:                          L2:
+fn choice_loops(insts: &mut Vec<Opcode>) {
+    for j in range(0, insts.len()) {
+        let i = match (insts[j], target(insts.as_slice(), j)) {
+            (ICommit(_), Some(i)) if i < j => i,
+            _ => continue
+        };
+        if target(insts.as_slice(), i) == Some(j + 1) && insts[i] == IChoice((j + 1 - i) as int) {
+            *insts.get_mut(j) = IPartialCommit(i as int + 1 - j as int);
+        }
+    }
+}
+
+// The instructions that begin the program, its rules and its recovery
+// rules.
+fn entries(program: &Program) -> Vec<uint> {
+    let mut entries = vec!(0);
+    for &(_, pos) in program.rules.iter() {
+        entries.push(pos);
+    }
+    for &(_, rule) in program.labels.iter() {
+        match rule {
+            Some(pos) => entries.push(pos),
+            None => {}
+        }
+    }
+    entries
+}
+
+// Which instructions can be reached from an entry.
+fn reachable(program: &Program) -> Vec<bool> {
+    let insts = program.insts.as_slice();
+    let mut seen = Vec::from_elem(insts.len(), false);
+    let mut todo = entries(program);
+    loop {
+        let i = match todo.pop() {
+            Some(i) => i,
+            None => break
+        };
+        if i >= insts.len() || seen[i] {
+            continue
+        }
+        *seen.get_mut(i) = true;
+        match target(insts, i) {
+            Some(t) => todo.push(t),
+            None => {}
+        }
+        match insts[i] {
+            IJmp(_) | ICommit(_) | IPartialCommit(_) | IBackCommit(_) |
+            IRet | IEnd | IFail | IFailTwice | IGiveup => {}
+            _ => todo.push(i + 1)
+        }
+    }
+    seen
+}
+
+// Turns each run of chars with the same flags that are kept, and that
+// nothing jumps into the middle of, into an IString, and drops the rest of
+// the run.
+fn merge_chars(program: &mut Program, keep: &mut Vec<bool>) {
+    let n = program.insts.len();
+    let mut landed = Vec::from_elem(n + 1, false);
+    for i in range(0, n) {
+        match target(program.insts.as_slice(), i) {
+            Some(t) => *landed.get_mut(t) = true,
+            None => {}
+        }
+    }
+    for pos in entries(program).into_iter() {
+        *landed.get_mut(pos) = true;
+    }
+    let mut i = 0;
+    while i < n {
+        let flags = match program.insts[i] {
+            IChar(_, flags) if keep[i] => flags,
+            _ => { i += 1; continue }
+        };
+        let mut s = String::new();
+        let mut j = i;
+        while j < n && keep[j] && (j == i || !landed[j]) {
+            match program.insts[j] {
+                IChar(ch, f) if f == flags => s.push(ch),
+                _ => break
+            }
+            j += 1;
+        }
+        if j - i > 1 {
+            let key = match program.strings.iter().position(|x| *x == s) {
+                Some(key) => key,
+                None => {
+                    program.strings.push(s);
+                    program.strings.len() - 1
+                }
+            };
+            *program.insts.get_mut(i) = IString(key);
+            for k in range(i + 1, j) {
+                *keep.get_mut(k) = false;
+            }
+        }
+        i = j;
+    }
+}
+
+// Removes the instructions not kept, and moves the offsets of jumps and the
+// positions of rules to match.
+fn compact(program: &mut Program, keep: &[bool]) {
+    let n = program.insts.len();
+    // where each instruction moves to; one that's removed, to where the
+    // next one kept does
+    let mut moved = Vec::with_capacity(n + 1);
+    let mut k = 0;
+    for i in range(0, n) {
+        moved.push(k);
+        if keep[i] {
+            k += 1;
+        }
+    }
+    moved.push(k);
+    let mut insts = Vec::with_capacity(k);
+    for i in range(0, n) {
+        if !keep[i] {
+            continue
+        }
+        let op = program.insts[i];
+        insts.push(match target(program.insts.as_slice(), i) {
+            Some(t) => op.with_offset(moved[t] as int - moved[i] as int).unwrap(),
+            None => op
+        });
+    }
+    program.insts = insts;
+    for rule in program.rules.iter_mut() {
+        let (_, ref mut pos) = *rule;
+        *pos = moved[*pos];
+    }
+    for label in program.labels.iter_mut() {
+        let (_, ref mut rule) = *label;
+        *rule = rule.map(|pos| moved[pos]);
+    }
+    program.left_recursive = program.left_recursive.iter().map(|&pos| moved[pos]).collect();
+    program.refers_back = program.refers_back.iter().map(|&pos| moved[pos]).collect();
+}
+
+#[cfg(test)]
+fn unoptimized(insts: Vec<Opcode>) -> Program {
+    use parse::parse;
+    let mut p = Program::unoptimized(parse("''").unwrap()).unwrap();
+    p.insts = insts;
+    p
+}
+
+#[test]
+fn peephole() {
+    use parse::parse;
+    fn insts(src: &str) -> Vec<Opcode> { Program::new(parse(src).unwrap()).unwrap().insts }
+    // chains of jumps
+    let mut p = unoptimized(vec!(IChoice(3), IChar('a', 0), ICommit(2), IJmp(2), IJmp(-1), IEnd));
+    optimize(&mut p);
+    assert_eq!(p.insts, vec!(IChoice(3), IChar('a', 0), ICommit(1), IEnd));
+    // a loop of choices
+    let mut p = unoptimized(vec!(IChoice(3), IChar('a', 0), ICommit(-2), IEnd));
+    optimize(&mut p);
+    assert_eq!(p.insts, vec!(IChoice(3), IChar('a', 0), IPartialCommit(-1), IEnd));
+    // runs of chars, but for one a jump lands in
+    assert_eq!(insts("'abc' 'd'"), vec!(IString(0), IEnd));
+    assert_eq!(insts("'ab'*"), vec!(ITestChar('a', 4, 0), IChoice(3), IString(0), IPartialCommit(-1),
+                                    IEnd));
+    assert_eq!(insts("'ab' 'c'? 'd'"), vec!(IString(0), ITestChar('c', 2, 0), IChar('c', 0), IChar('d', 0),
+                                           IEnd));
+    // a rule that ends in a call
+    let p = Program::new(parse("S <- 'a' T  T <- 'b' S / 'c'").unwrap()).unwrap();
+    assert_eq!(p.insts, vec!(ICall(2), IEnd, IChar('a', 0), ITailCall(2), IRet, ITestChar('b', 4, 0),
+                             IChar('b', 0), ITailCall(-5), IRet, IChar('c', 0), IRet));
+    assert_eq!(p.rules, vec!(("S".to_string(), 2), ("T".to_string(), 5)));
+}
+
+// The same grammars, matched with and without optimizing them, give the
+// same results.
+#[test]
+fn peephole_differential() {
+    use parse::parse;
+    use vm::Vm;
+    let cases = [
+        ("'abc' / 'abd' / 'x'", vec!("abc", "abd", "abx", "x", "")),
+        ("{ [a-z]+ } (',' { [a-z]+ })*", vec!("a,bc,d", "a,,b", "", "ab,")),
+        ("S <- 'a' T  T <- 'b' S / 'c'", vec!("abac", "ababab", "aba", "c", "")),
+        ("list <- '(' sp (item sp)* ')'  item <- list / atom  atom <- { [a-z0-9]+ }
+          sp <- ' '*", vec!("(a (b 12) c)", "(a (b", "()", "(a))")),
+        ("E <- E '+' T / T  T <- { [0-9] }", vec!("1+2+3", "1+", "+")),
+        ("S <- A / B  A <- 'x' ('y' / ^Y)  B <- 'z'  Y <- (!'z' .)*", vec!("xy", "xq", "xqz", "z")),
+        ("S <- 'if' / 'while' / 'return' / [a-z]+", vec!("while", "wh", "x", "9")),
+        ("(!'end' .)* 'end'", vec!("abc end", "abc en")),
+    ];
+    for &(src, ref inputs) in cases.iter() {
+        let ast = parse(src).unwrap();
+        let mut on = Vm::new(Program::new(ast.clone()).unwrap());
+        let mut off = Vm::new(Program::unoptimized(ast).unwrap());
+        for round in range(0, 3) {
+            // plainly, then building concrete syntax trees, then memoizing
+            if round == 1 {
+                on.record_rules(true);
+                off.record_rules(true);
+            } else if round == 2 {
+                on.record_rules(false);
+                off.record_rules(false);
+                on.memoize_all(100);
+                off.memoize_all(100);
+            }
+            for &input in inputs.iter() {
+                let m = on.do_match(input);
+                assert_eq!(m, off.do_match(input));
+                assert_eq!(on.captures(), off.captures());
+                assert!(on.stack_pushes() <= off.stack_pushes());
+                match m {
+                    Some(m) if round == 1 => {
+                        assert_eq!(on.get_cst(input, m.len(), &[]), off.get_cst(input, m.len(), &[]))
+                    }
+                    Some(_) => {}
+                    None => assert_eq!(on.match_error(input), off.match_error(input))
+                }
+            }
+        }
+        // and recovering from errors
+        for &input in inputs.iter() {
+            assert_eq!(on.recover(input, &[]), off.recover(input, &[]));
+        }
+    }
+}
@@ -1,6 +1,10 @@
 
 use std::fmt;
-use ast::{Ast, Repeater, Flags};
+use ast::{Ast, Rule, Span, Repeater, Flags, FLAG_NORMAL, FLAG_NEGATED};
+use ast::{Lit, Dot, Cls, Seq, Alt, Rep, And, Not, Cap, Pos, Table, Group, Subst, Backref,
+          RunTime, Const, Arg, Format, Num, Function, Fold, Throw, Grammar, NonTerm};
+use ast::{ZeroOne, ZeroMore, OneMore};
+use value::{Value, Text, Int, Float, Bool};
 
 pub struct Error {
     pub pos: uint,
@@ -19,7 +23,694 @@ pub fn err<T>(msg: &str, pos: uint) -> Result<T, Error> {
     })
 }
 
-pub fn parse(src: &str) -> Result<Ast, Error> { fail!("not implemented") }
+/// Parse the text of a PEG into an Ast.
+///
+/// ```text
+/// pattern <- grammar / simplepatt
+/// grammar <- (nonterminal '<-' sp simplepatt)+
+/// simplepatt <- alternative ('/' sp alternative)*
+/// alternative <- ([!&]? sp suffix)+
+/// suffix <- primary ([*+?] sp)*
+/// primary <- '(' sp pattern ')' sp / '.' sp / literal /
+///            charclass / nonterminal !'<-'
+/// literal <- ['] (!['] .)* ['] sp
+/// charclass <- '[' (!']' (. '-' . / .))* ']' sp
+/// nonterminal <- [a-zA-Z]+ sp
+/// sp <- [ \t\n]*
+/// ```
+///
+/// Beyond the paper's grammar: literals may also be double-quoted,
+/// a class may be negated with a leading '^', names may contain '_' and
+/// digits, and '\' escapes a quote, bracket, or one of "\n\t\r" inside
+/// literals and classes.
+///
+/// Captures are written in braces: `{ p }` captures what p matches, and
+/// `{}` the position it's at.  Either may be named, as `{:name: p }` or
+/// `{:name:}`.  In the tree of captures a match builds, `{| p |}` is a node
+/// of the captures made inside it, and the group `{:name: p :}` a node
+/// named `name`; an unnamed group `{: p :}` just passes its captures on to
+/// the node it's in.  The substitution `{~ p ~}` captures what p matches
+/// with the text of each capture made directly inside it replaced by that
+/// capture's value.  Captures are numbered by their opening brace, from 1.
+///
+/// A back-reference `=name` matches the text of the last capture named
+/// `name` again, as in LPeg's `re`: `{:q: ['"] :} (!=q .)* =q` is a quoted
+/// string that ends with the quote it began with.
+///
+/// A match-time capture `p => name` calls the function registered for
+/// `name` (see `Vm::on_match`) when p matches, during the match; it
+/// decides whether the match goes on, and from where.  It's numbered after
+/// the captures inside p.
+///
+/// Captures have values (see `Value`); most just their text.  The constant
+/// `$'text'`, `$true`, `$false` or `$12.5` matches nothing and has the value
+/// written.  `%n` matches nothing and has the value of the nth extra
+/// argument given to the match, from 1.  `p -> 'fmt'` is the format with
+/// `%0` replaced by p's match and `%1` to `%9` by the values of the
+/// captures inside p, and `%%` by '%'; `p -> n` the nth of those values; and
+/// `p -> name` what the function registered for `name` (see `Vm::function`)
+/// makes of them, or of p's match if there are none.  `p ~> name` folds
+/// them with the function: it's called on the first two, then on its result
+/// and the third, and so on.  `->` and `~>` captures are numbered after the
+/// captures inside p.
+///
+/// A labeled failure `^label` fails with the error `label`, which no choice
+/// or predicate catches: the match ends there, with the error (see
+/// `Peg::parse`).  Unless the grammar has a rule named `label`, which is the
+/// label's recovery rule: it's called where the error was thrown, and if it
+/// matches, the error is recorded and the match goes on, as if the throw
+/// had matched what the rule did.  In `'(' e (')' / ^MissingParen)`, with
+/// `MissingParen <- (!')' .)* ')'?`, a missing parenthesis is an error that
+/// the rest of the input is still matched after.
+pub fn parse(src: &str) -> Result<Ast, Error> {
+    let mut p = Parser {
+        chars: src.chars().collect(),
+        chari: 0,
+        ncaps: 0,
+    };
+    p.parse()
+}
+
+struct Parser {
+    // The input, parsed only as a sequence of UTF8 code points.
+    chars: Vec<char>,
+    // The index of the current character in the input.
+    chari: uint,
+    // The number of captures so far.
+    ncaps: uint,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<Ast, Error> {
+        self.sp();
+        let ast = try!(self.pattern());
+        if !self.eof() {
+            return self.err("unexpected character")
+        }
+        Ok(ast)
+    }
+
+    // pattern <- grammar / simplepatt
+    fn pattern(&mut self) -> Result<Ast, Error> {
+        if self.at_rule() { self.grammar() } else { self.simplepatt() }
+    }
+
+    // grammar <- (nonterminal '<-' sp simplepatt)+
+    fn grammar(&mut self) -> Result<Ast, Error> {
+        let mut rules = vec!();
+        while self.at_rule() {
+            let start = self.chari;
+            let name = self.nonterminal();
+            self.chari += 2; // '<-', checked by at_rule
+            self.sp();
+            let body = try!(self.simplepatt());
+            // the span leaves out the space after the rule
+            let mut end = self.chari;
+            while end > start && self.chars[end - 1].is_whitespace() {
+                end -= 1;
+            }
+            rules.push(Rule { name: name, body: body, span: Some(Span { start: start, end: end }) });
+        }
+        Ok(Grammar(rules))
+    }
+
+    // simplepatt <- alternative ('/' sp alternative)*
+    fn simplepatt(&mut self) -> Result<Ast, Error> {
+        let mut alts = vec!(try!(self.alternative()));
+        while self.cur_is('/') {
+            self.next_char();
+            self.sp();
+            alts.push(try!(self.alternative()));
+        }
+        if alts.len() == 1 { Ok(alts.pop().unwrap()) } else { Ok(Alt(alts)) }
+    }
+
+    // alternative <- ([!&]? sp suffix)+
+    fn alternative(&mut self) -> Result<Ast, Error> {
+        let mut elems = vec!();
+        while self.at_elem() {
+            let pred = self.cur();
+            if pred == Some('!') || pred == Some('&') {
+                self.next_char();
+                self.sp();
+            }
+            let e = try!(self.suffix());
+            elems.push(match pred {
+                Some('!') => Not(box e),
+                Some('&') => And(box e),
+                _ => e
+            });
+        }
+        match elems.len() {
+            0 => self.err("expected a pattern"),
+            1 => Ok(elems.pop().unwrap()),
+            _ => Ok(Seq(elems))
+        }
+    }
+
+    // suffix <- primary ([*+?] sp)*
+    fn suffix(&mut self) -> Result<Ast, Error> {
+        let mut e = try!(self.primary());
+        loop {
+            let rep = match self.cur() {
+                Some('*') => ZeroMore,
+                Some('+') => OneMore,
+                Some('?') => ZeroOne,
+                // '=>' sp nonterminal, a match-time capture
+                Some('=') if self.peek(1) == Some('>') => {
+                    self.chari += 2;
+                    self.sp();
+                    match self.cur() {
+                        Some(c) if is_name_start(c) => {}
+                        _ => return self.err("expected the name of a function after '=>'")
+                    }
+                    self.ncaps += 1;
+                    e = RunTime(self.ncaps, self.nonterminal(), box e);
+                    continue
+                }
+                // '->' sp (literal / [0-9]+ sp / nonterminal)
+                Some('-') if self.peek(1) == Some('>') => {
+                    self.chari += 2;
+                    self.sp();
+                    let num = self.ncaps + 1;
+                    e = match self.cur() {
+                        Some('\'') | Some('"') => Format(num, try!(self.string()), box e),
+                        Some(c) if is_digit(c) => {
+                            let n = try!(self.number());
+                            self.sp();
+                            Num(num, n, box e)
+                        }
+                        Some(c) if is_name_start(c) => Function(num, self.nonterminal(), box e),
+                        _ => return self.err("expected a format, a number or the name of a function after '->'")
+                    };
+                    self.ncaps += 1;
+                    continue
+                }
+                // '~>' sp nonterminal, a fold
+                Some('~') if self.peek(1) == Some('>') => {
+                    self.chari += 2;
+                    self.sp();
+                    match self.cur() {
+                        Some(c) if is_name_start(c) => {}
+                        _ => return self.err("expected the name of a function after '~>'")
+                    }
+                    self.ncaps += 1;
+                    e = Fold(self.ncaps, self.nonterminal(), box e);
+                    continue
+                }
+                _ => break
+            };
+            self.next_char();
+            self.sp();
+            e = Rep(box e, rep);
+        }
+        Ok(e)
+    }
+
+    // primary <- '(' sp pattern ')' sp / '.' sp / literal /
+    //            charclass / nonterminal !'<-'
+    fn primary(&mut self) -> Result<Ast, Error> {
+        match self.cur() {
+            Some('(') => {
+                let start = self.chari;
+                self.next_char();
+                self.sp();
+                let e = try!(self.pattern());
+                if !self.cur_is(')') {
+                    return err("unclosed parenthesis", start)
+                }
+                self.next_char();
+                self.sp();
+                Ok(e)
+            }
+            Some('.') => {
+                self.next_char();
+                self.sp();
+                Ok(Dot(FLAG_NORMAL))
+            }
+            Some('\'') | Some('"') => self.literal(),
+            Some('[') => self.charclass(),
+            Some('{') => self.capture(),
+            // '=' nonterminal, a back-reference
+            Some('=') => {
+                self.next_char();
+                match self.cur() {
+                    Some(c) if is_name_start(c) => Ok(Backref(self.nonterminal())),
+                    _ => self.err("expected the name of a capture after '='")
+                }
+            }
+            // '$' constant, a constant capture
+            Some('$') => {
+                self.next_char();
+                let value = try!(self.constant());
+                self.ncaps += 1;
+                Ok(Const(self.ncaps, value))
+            }
+            // '^' nonterminal, a labeled failure
+            Some('^') => {
+                self.next_char();
+                match self.cur() {
+                    Some(c) if is_name_start(c) => Ok(Throw(self.nonterminal())),
+                    _ => self.err("expected a label after '^'")
+                }
+            }
+            // '%' [0-9]+ sp, an argument capture
+            Some('%') => {
+                self.next_char();
+                match self.cur() {
+                    Some(c) if is_digit(c) => {}
+                    _ => return self.err("expected the number of an argument after '%'")
+                }
+                let pos = self.chari;
+                let n = try!(self.number());
+                if n == 0 {
+                    return err("arguments are numbered from 1", pos)
+                }
+                self.sp();
+                self.ncaps += 1;
+                Ok(Arg(self.ncaps, n))
+            }
+            Some(c) if is_name_start(c) => Ok(NonTerm(self.nonterminal())),
+            _ => self.err("expected a pattern")
+        }
+    }
+
+    // constant <- literal / ('true' / 'false') sp / '-'? [0-9]+ ('.' [0-9]+)? sp
+    fn constant(&mut self) -> Result<Value<'static>, Error> {
+        match self.cur() {
+            Some('\'') | Some('"') => return Ok(Text(try!(self.string()))),
+            Some(c) if is_name_start(c) => {
+                let pos = self.chari;
+                return match self.nonterminal().as_slice() {
+                    "true" => Ok(Bool(true)),
+                    "false" => Ok(Bool(false)),
+                    _ => err("expected a constant after '$'", pos)
+                }
+            }
+            _ => {}
+        }
+        let mut num = String::new();
+        if self.cur_is('-') {
+            num.push('-');
+            self.next_char();
+        }
+        match self.cur() {
+            Some(c) if is_digit(c) => num.push_str(self.digits().as_slice()),
+            _ => return self.err("expected a constant after '$'")
+        }
+        let value = if self.cur_is('.') && self.peek(1).map_or(false, is_digit) {
+            self.next_char();
+            num.push('.');
+            num.push_str(self.digits().as_slice());
+            Float(from_str::<f64>(num.as_slice()).unwrap())
+        } else {
+            match from_str::<i64>(num.as_slice()) {
+                Some(n) => Int(n),
+                None => return self.err("number out of range")
+            }
+        };
+        self.sp();
+        Ok(value)
+    }
+
+    // capture <- '{' sp (':' nonterminal? ':' sp)? pattern? '}' sp
+    //          / '{' sp ':' (nonterminal ':')? sp pattern ':}' sp
+    //          / '{|' sp pattern '|}' sp
+    //          / '{~' sp pattern '~}' sp
+    fn capture(&mut self) -> Result<Ast, Error> {
+        let start = self.chari;
+        self.next_char();
+        self.ncaps += 1;
+        let num = self.ncaps;
+        self.sp();
+        if self.cur_is('|') {
+            self.next_char();
+            self.sp();
+            let e = try!(self.pattern());
+            if !(self.cur_is('|') && self.peek(1) == Some('}')) {
+                return err("unclosed table capture", start)
+            }
+            self.chari += 2;
+            self.sp();
+            return Ok(Table(num, box e))
+        }
+        if self.cur_is('~') {
+            self.next_char();
+            self.sp();
+            let e = try!(self.pattern());
+            if !(self.cur_is('~') && self.peek(1) == Some('}')) {
+                return err("unclosed substitution capture", start)
+            }
+            self.chari += 2;
+            self.sp();
+            return Ok(Subst(num, box e))
+        }
+        let mut colon = false;
+        let mut name = None;
+        if self.cur_is(':') {
+            self.next_char();
+            colon = true;
+            match self.cur() {
+                Some(c) if is_name_start(c) => {
+                    name = Some(self.nonterminal());
+                    if !self.cur_is(':') {
+                        return self.err("expected ':' after the capture name")
+                    }
+                    self.next_char();
+                }
+                _ => {}
+            }
+            self.sp();
+        }
+        if name.is_some() || !colon {
+            if self.cur_is('}') {
+                self.next_char();
+                self.sp();
+                return Ok(Pos(num, name))
+            }
+        }
+        let e = try!(self.pattern());
+        if self.cur_is(':') && self.peek(1) == Some('}') && colon {
+            self.chari += 2;
+            self.sp();
+            return Ok(Group(num, name, box e))
+        }
+        if !self.cur_is('}') || (colon && name.is_none()) {
+            return err("unclosed capture", start)
+        }
+        self.next_char();
+        self.sp();
+        Ok(Cap(num, name, box e))
+    }
+
+    // literal <- ['] (!['] .)* ['] sp
+    fn literal(&mut self) -> Result<Ast, Error> {
+        let start = self.chari;
+        let quote = self.cur().unwrap();
+        self.next_char();
+        let mut s = String::new();
+        loop {
+            match self.cur() {
+                None => return err("unclosed literal", start),
+                Some(c) if c == quote => break,
+                _ => s.push(try!(self.literal_char()))
+            }
+        }
+        self.next_char();
+        self.sp();
+        Ok(Lit(s, FLAG_NORMAL))
+    }
+
+    // The text of a literal, as in a format or a constant.
+    fn string(&mut self) -> Result<String, Error> {
+        match try!(self.literal()) {
+            Lit(s, _) => Ok(s),
+            _ => unreachable!()
+        }
+    }
+
+    // [0-9]+, which the caller has checked starts here
+    fn digits(&mut self) -> String {
+        let mut s = String::new();
+        loop {
+            match self.cur() {
+                Some(c) if is_digit(c) => { s.push(c); self.next_char(); }
+                _ => break
+            }
+        }
+        s
+    }
+
+    // [0-9]+, which the caller has checked starts here, as a number; an
+    // error at its first digit if it's too big for one
+    fn number(&mut self) -> Result<uint, Error> {
+        let pos = self.chari;
+        match from_str::<uint>(self.digits().as_slice()) {
+            Some(n) => Ok(n),
+            None => err("number out of range", pos)
+        }
+    }
+
+    // charclass <- '[' (!']' (. '-' . / .))* ']' sp
+    fn charclass(&mut self) -> Result<Ast, Error> {
+        let start = self.chari;
+        self.next_char();
+        let mut flags = FLAG_NORMAL;
+        if self.cur_is('^') {
+            self.next_char();
+            flags |= FLAG_NEGATED;
+        }
+        let mut ranges = vec!();
+        loop {
+            match self.cur() {
+                None => return err("unclosed character class", start),
+                Some(']') => break,
+                _ => {}
+            }
+            let pos = self.chari;
+            let lo = try!(self.literal_char());
+            // a '-' just before the closing ']' stands for itself
+            if self.cur_is('-') && self.peek(1).is_some() && self.peek(1) != Some(']') {
+                self.next_char();
+                let hi = try!(self.literal_char());
+                if hi < lo {
+                    return err("invalid character class range", pos)
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        self.next_char();
+        self.sp();
+        Ok(Cls(ranges, flags))
+    }
+
+    // nonterminal <- [a-zA-Z]+ sp
+    // The caller has checked that a name starts here.
+    fn nonterminal(&mut self) -> String {
+        let mut name = String::new();
+        loop {
+            match self.cur() {
+                Some(c) if is_name_char(c) => { name.push(c); self.next_char(); }
+                _ => break
+            }
+        }
+        self.sp();
+        name
+    }
+
+    // sp <- [ \t\n]*
+    fn sp(&mut self) {
+        loop {
+            match self.cur() {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') => self.next_char(),
+                _ => break
+            }
+        }
+    }
+
+    // One (possibly escaped) char of a literal or char class.
+    fn literal_char(&mut self) -> Result<char, Error> {
+        let c = self.cur().unwrap();
+        self.next_char();
+        if c != '\\' {
+            return Ok(c)
+        }
+        let esc = match self.cur() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some(c) => c,
+            None => return self.err("incomplete escape sequence")
+        };
+        self.next_char();
+        Ok(esc)
+    }
+
+    // Does a rule definition, `name <-`, start at the current position?
+    fn at_rule(&mut self) -> bool {
+        match self.cur() {
+            Some(c) if is_name_start(c) => {}
+            _ => return false
+        }
+        let start = self.chari;
+        self.nonterminal();
+        let found = self.cur_is('<') && self.peek(1) == Some('-');
+        self.chari = start;
+        found
+    }
+
+    // Can an element of an alternative start at the current position?
+    // A nonterminal followed by '<-' begins the next rule instead.
+    fn at_elem(&mut self) -> bool {
+        match self.cur() {
+            Some('!') | Some('&') | Some('(') | Some('.') |
+            Some('\'') | Some('"') | Some('[') | Some('{') | Some('$') | Some('%') | Some('^') => true,
+            Some('=') => self.peek(1) != Some('>'),
+            Some(c) if is_name_start(c) => !self.at_rule(),
+            _ => false
+        }
+    }
+
+    fn err<T>(&self, msg: &str) -> Result<T, Error> {
+        err(msg, self.chari)
+    }
+
+    fn eof(&self) -> bool {
+        self.chari >= self.chars.len()
+    }
+
+    fn cur(&self) -> Option<char> {
+        self.peek(0)
+    }
+
+    fn peek(&self, offset: uint) -> Option<char> {
+        if self.chari + offset < self.chars.len() {
+            Some(self.chars[self.chari + offset])
+        } else {
+            None
+        }
+    }
+
+    fn cur_is(&self, c: char) -> bool {
+        self.cur() == Some(c)
+    }
+
+    fn next_char(&mut self) {
+        self.chari += 1;
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start(c) || is_digit(c)
+}
+
+fn is_digit(c: char) -> bool {
+    c >= '0' && c <= '9'
+}
+
+
+#[test]
+fn parse_simple() {
+    use ast::{lit, seq3, alt, many, some, opt, not, and, dot};
+    assert_eq!(parse("'ana'").unwrap(), lit("ana"));
+    assert_eq!(parse(" 'a' \"b\" . ").unwrap(), seq3(lit("a"), lit("b"), dot()));
+    assert_eq!(parse("'a' / 'b'").unwrap(), alt(lit("a"), lit("b")));
+    assert_eq!(parse("'a'* 'b'+ 'c'?").unwrap(),
+               seq3(many(lit("a")), some(lit("b")), opt(lit("c"))));
+    assert_eq!(parse("!'a' & 'b'").unwrap(),
+               Seq(vec!(not(lit("a")), and(lit("b")))));
+    assert_eq!(parse("('a' / 'b')*").unwrap(), many(alt(lit("a"), lit("b"))));
+}
+
+#[test]
+fn parse_classes() {
+    assert_eq!(parse("[a-zA-Z_]").unwrap(),
+               Cls(vec!(('a','z'), ('A','Z'), ('_','_')), FLAG_NORMAL));
+    assert_eq!(parse("[^ \\t\\n-]").unwrap(),
+               Cls(vec!((' ',' '), ('\t','\t'), ('\n','\n'), ('-','-')), FLAG_NEGATED));
+    assert_eq!(parse("[\\]]").unwrap(), Cls(vec!((']',']')), FLAG_NORMAL));
+    assert_eq!(parse("'\\''").unwrap(), Lit("'".to_string(), FLAG_NORMAL));
+}
+
+#[test]
+fn parse_grammar() {
+    use ast::{lit, seq, alt, many, nonterm, rule};
+    let g = parse("S <- A S / ''\n A <- 'a' B*\n B <- [b]").unwrap();
+    assert_eq!(g, Grammar(vec!(
+        rule("S", alt(seq(nonterm("A"), nonterm("S")), lit(""))),
+        rule("A", seq(lit("a"), many(nonterm("B")))),
+        rule("B", Cls(vec!(('b','b')), FLAG_NORMAL)),
+    )));
+    match g {
+        Grammar(rules) => {
+            let spans: Vec<Option<Span>> = rules.into_iter().map(|r| r.span).collect();
+            assert_eq!(spans, vec!(Some(Span { start: 0, end: 13 }),
+                                   Some(Span { start: 15, end: 26 }),
+                                   Some(Span { start: 28, end: 36 })));
+        }
+        _ => unreachable!()
+    }
+    // a nested grammar, in parentheses
+    match parse("'x' (S <- 'y')").unwrap() {
+        Seq(es) => assert_eq!(es[1], Grammar(vec!(rule("S", lit("y"))))),
+        ast => fail!("unexpected {}", ast)
+    }
+}
+
+#[test]
+fn parse_captures() {
+    use ast::{lit, seq, nonterm};
+    assert_eq!(parse("{ 'a' } {}").unwrap(),
+               Seq(vec!(Cap(1, None, box lit("a")), Pos(2, None))));
+    assert_eq!(parse("{:x: 'a' {:y:} } 'b'").unwrap(),
+               seq(Cap(1, Some("x".to_string()), box seq(lit("a"), Pos(2, Some("y".to_string())))),
+                   lit("b")));
+    // numbered across rules, in order
+    match parse("S <- {A} {}  A <- {'a'}").unwrap() {
+        Grammar(rules) => {
+            assert_eq!(rules[0].body, seq(Cap(1, None, box nonterm("A")), Pos(2, None)));
+            assert_eq!(rules[1].body, Cap(3, None, box lit("a")));
+        }
+        ast => fail!("unexpected {}", ast)
+    }
+    // tables and groups
+    assert_eq!(parse("{| {'a'} |} {:x: 'b' :} {: 'c' :}").unwrap(),
+               Seq(vec!(Table(1, box Cap(2, None, box lit("a"))),
+                        Group(3, Some("x".to_string()), box lit("b")),
+                        Group(4, None, box lit("c")))));
+    assert_eq!(parse("{~ {'a'} 'b' ~}").unwrap(),
+               Subst(1, box seq(Cap(2, None, box lit("a")), lit("b"))));
+    assert_eq!(parse("{'a'").err().unwrap().pos, 0);
+    assert_eq!(parse("{:x 'a'}").err().unwrap().pos, 4);
+    assert_eq!(parse("{| 'a' }").err().unwrap().pos, 0);
+    assert_eq!(parse("{: 'a' }").err().unwrap().pos, 0);
+    assert_eq!(parse("{~ 'a' }").err().unwrap().pos, 0);
+    // back-references
+    assert_eq!(parse("{:q: 'a' :} !=q").unwrap(),
+               seq(Group(1, Some("q".to_string()), box lit("a")),
+                   Not(box Backref("q".to_string()))));
+    assert_eq!(parse("'a' = q").err().unwrap().pos, 5);
+    // match-time captures
+    assert_eq!(parse("{'a'}+ => f =q").unwrap(),
+               seq(RunTime(2, "f".to_string(), box Rep(box Cap(1, None, box lit("a")), OneMore)),
+                   Backref("q".to_string())));
+    assert_eq!(parse("'a' => 'b'").err().unwrap().pos, 7);
+    // values
+    assert_eq!(parse("$'x' $true $-12 $0.5 %2").unwrap(),
+               Seq(vec!(Const(1, Text("x".to_string())), Const(2, Bool(true)),
+                        Const(3, Int(-12)), Const(4, Float(0.5)), Arg(5, 2))));
+    assert_eq!(parse("{'a'} -> '<%1>' -> 1 -> f ~> g").unwrap(),
+               Fold(5, "g".to_string(), box Function(4, "f".to_string(),
+                   box Num(3, 1, box Format(2, "<%1>".to_string(), box Cap(1, None, box lit("a")))))));
+    assert_eq!(parse("'a' -> ").err().unwrap().pos, 7);
+    assert_eq!(parse("'a' ~> 1").err().unwrap().pos, 7);
+    assert_eq!(parse("$maybe").err().unwrap().pos, 1);
+    assert_eq!(parse("%0").err().unwrap().pos, 1);
+    assert_eq!(parse("'a' -> 99999999999999999999").err().unwrap().pos, 7);
+    assert_eq!(parse("%99999999999999999999").err().unwrap().pos, 1);
+    // labeled failures
+    assert_eq!(parse("'(' (')' / ^MissingParen)").unwrap(),
+               seq(lit("("), Alt(vec!(lit(")"), Throw("MissingParen".to_string())))));
+    assert_eq!(parse("'a' ^ 'b'").err().unwrap().pos, 5);
+}
+
+#[test]
+fn parse_errors() {
+    fn pos(src: &str) -> uint { parse(src).err().unwrap().pos }
+    assert_eq!(pos("'abc"), 0);
+    assert_eq!(pos("'a' [b-"), 4);
+    assert_eq!(pos("'a' / "), 6);
+    assert_eq!(pos("('a' 'b'"), 0);
+    assert_eq!(pos("'a' )"), 4);
+    assert_eq!(pos("[z-a]"), 1);
+    assert_eq!(pos("S <- 'a' / "), 11);
+}
 
 
 
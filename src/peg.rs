@@ -0,0 +1,1047 @@
+//! `Peg`, a compiled grammar ready to match input, and the ways of
+//! matching with it.
+
+use std::cell::RefCell;
+use std::fmt;
+use ast::{Ast, Rule, Grammar, Group};
+use capture::{Capture, CaptureError, Node, ValueFn};
+use compile::Program;
+use cst::Cst;
+use error::MatchError;
+use parse::parse;
+use run::{Env, RunError, NoMatch, run};
+use verify::last_capture;
+use value::{Value, Slice};
+use vm::{Vm, MatchFn, Anchor};
+
+/// Wraps the body of each rule of a grammar that `wrap` picks in a group
+/// named after the rule, numbered after the captures the grammar has.  A
+/// pattern that isn't a grammar, or the rules of grammars nested in it,
+/// are left as they are.
+pub fn group_rules(ast: Ast, wrap: |&str| -> bool) -> Ast {
+    let mut num = last_capture(&ast);
+    let rules = match ast {
+        Grammar(rules) => rules,
+        ast => return ast
+    };
+    Grammar(rules.into_iter().map(|r| {
+        if !wrap(r.name.as_slice()) {
+            return r
+        }
+        num += 1;
+        let Rule { name, body, span } = r;
+        Rule { name: name.clone(), body: Group(num, Some(name), box body), span: span }
+    }).collect())
+}
+
+/// Why a grammar couldn't be made into a Peg.
+pub enum PegError {
+    /// The grammar's text doesn't parse.
+    SyntaxError(::parse::Error),
+    /// The grammar parsed, but can't be compiled.
+    CompileError(::compile::Error),
+}
+
+impl fmt::Show for PegError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxError(ref e) => write!(f, "{}", e),
+            CompileError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// A compiled grammar.  A match, as `match_str` and the like make, is
+/// anchored at the start of the input, and needn't reach its end.  A
+/// search, as `find` and the like make, looks for matches where the anchor
+/// set by `anchor` allows: anywhere in the input, to begin with.
+pub struct Peg {
+    vm: RefCell<Vm>,
+}
+
+impl Peg {
+    /// Parses and compiles a grammar.
+    pub fn new(src: &str) -> Result<Peg, PegError> {
+        let ast = try!(parse(src).map_err(SyntaxError));
+        Peg::from_ast(ast)
+    }
+
+    /// Parses and compiles a grammar, with the match of each of its rules a
+    /// node of the tree of captures named after the rule, as if its body
+    /// were written `{:rule: ... :}`, so that the rule can name an action
+    /// (see `run`).
+    pub fn with_rule_nodes(src: &str) -> Result<Peg, PegError> {
+        let ast = try!(parse(src).map_err(SyntaxError));
+        Peg::from_ast(group_rules(ast, |_| true))
+    }
+
+    /// Compiles a grammar that's already been parsed, or built by hand.
+    pub fn from_ast(ast: Ast) -> Result<Peg, PegError> {
+        let program = try!(Program::new(ast).map_err(CompileError));
+        Ok(Peg::from_program(program))
+    }
+
+    /// A Peg of a program that's already been compiled, such as one left
+    /// unoptimized (see `Program::unoptimized`).
+    pub fn from_program(program: Program) -> Peg {
+        Peg { vm: RefCell::new(Vm::new(program)) }
+    }
+
+    /// Gives the function that match-time captures `p => name` call; see
+    /// `Vm::on_match`.
+    pub fn on_match(&mut self, name: &str, f: MatchFn) -> Result<(), PegError> {
+        self.vm.borrow_mut().on_match(name, f).map_err(CompileError)
+    }
+
+    /// Gives the function that function and fold captures `p -> name` and
+    /// `p ~> name` call; see `Vm::function`.
+    pub fn function(&mut self, name: &str, f: ValueFn) -> Result<(), PegError> {
+        self.vm.borrow_mut().function(name, f).map_err(CompileError)
+    }
+
+    /// Matches the input, returning the part of it that matched.
+    pub fn match_str<'t>(&self, input: &'t str) -> Option<&'t str> {
+        self.vm.borrow_mut().do_match(input)
+    }
+
+    /// Matches the input, or says why it doesn't match; see
+    /// `Vm::match_error`.
+    pub fn try_match<'t>(&self, input: &'t str) -> Result<&'t str, MatchError> {
+        self.vm.borrow_mut().try_match(input)
+    }
+
+    /// Matches the input, returning its captures in the order they were
+    /// opened, after capture 0, which is the whole match.
+    pub fn captures<'t>(&self, input: &'t str) -> Option<Vec<Capture<'t>>> {
+        let mut vm = self.vm.borrow_mut();
+        let m = match vm.do_match(input) {
+            Some(m) => m,
+            None => return None
+        };
+        Some(whole_match(m, 0, vm.get_captures(input, &[])))
+    }
+
+    /// Sets where a search may find a match; see `Vm::anchor`.
+    pub fn anchor(&mut self, anchor: Anchor) {
+        self.vm.borrow_mut().anchor(anchor)
+    }
+
+    /// Whether a search finds a match in the input.
+    pub fn is_match(&self, input: &str) -> bool {
+        self.find(input).is_some()
+    }
+
+    /// Searches the input for the first match, returning the byte offsets it
+    /// starts and ends at.
+    pub fn find(&self, input: &str) -> Option<(uint, uint)> {
+        self.vm.borrow_mut().find(input, 0)
+    }
+
+    /// The matches in the input, searching each time from the end of the
+    /// match before.  After an empty match the search moves on a char, so
+    /// that the matches don't overlap, and an empty match where the match
+    /// before ended doesn't count.
+    pub fn find_iter<'p, 't>(&'p self, input: &'t str) -> FindMatches<'p, 't> {
+        FindMatches { peg: self, input: input, scan: Scan { at: 0, last: None } }
+    }
+
+    /// The captures of the matches in the input, found as `find_iter` finds
+    /// them; capture 0 of each is the whole match.
+    pub fn captures_iter<'p, 't>(&'p self, input: &'t str) -> FindCaptures<'p, 't> {
+        FindCaptures { peg: self, input: input, scan: Scan { at: 0, last: None } }
+    }
+
+    /// The text between the matches in the input, found as `find_iter` finds
+    /// them: before the first, between each pair, and after the last.
+    pub fn split<'p, 't>(&'p self, input: &'t str) -> Split<'p, 't> {
+        Split { matches: self.find_iter(input), last: 0, done: false }
+    }
+
+    // Searches the input from byte offset `at`, returning the captures of
+    // the match it finds.
+    fn captures_from<'t>(&self, input: &'t str, at: uint) -> Option<Vec<Capture<'t>>> {
+        let mut vm = self.vm.borrow_mut();
+        let (start, end) = match vm.find(input, at) {
+            Some(m) => m,
+            None => return None
+        };
+        Some(whole_match(input.slice(start, end), start, vm.get_captures(input, &[])))
+    }
+
+    /// Replaces the first match a search finds in the input with the
+    /// replacement for its captures.
+    pub fn replace<R: Replacer>(&self, input: &str, rep: R) -> String {
+        self.replacen(input, 1, rep)
+    }
+
+    /// Replaces each match in the input, found as `find_iter` finds them.
+    pub fn replace_all<R: Replacer>(&self, input: &str, rep: R) -> String {
+        self.replacen(input, 0, rep)
+    }
+
+    /// Replaces the first `limit` matches in the input, or all of them if
+    /// `limit` is 0.
+    pub fn replacen<R: Replacer>(&self, input: &str, limit: uint, mut rep: R) -> String {
+        let mut s = String::new();
+        let mut done = 0;
+        // the end of the last match
+        let mut last = 0;
+        for caps in self.captures_iter(input) {
+            s.push_str(input.slice(last, caps[0].start));
+            s.push_str(rep.replacement(caps.as_slice()).as_slice());
+            last = caps[0].end;
+            done += 1;
+            if done == limit {
+                break
+            }
+        }
+        s.push_str(input.slice_from(last));
+        s
+    }
+
+    /// Matches the input, returning the tree of its captures: the root is
+    /// the whole match, and each capture's node has the captures made inside
+    /// it as children.
+    pub fn tree<'t>(&self, input: &'t str) -> Option<Node<'t>> {
+        let mut vm = self.vm.borrow_mut();
+        let m = match vm.do_match(input) {
+            Some(m) => m,
+            None => return None
+        };
+        Some(vm.get_tree(input, m.len(), &[]))
+    }
+
+    /// Matches the input, returning the values of its outermost captures,
+    /// or the whole match if it made none; None if it doesn't match.
+    /// `args` are the extra arguments that argument captures `%n` refer to.
+    /// Fails if a capture's value can't be made from what it refers to.
+    pub fn values<'t>(&self, input: &'t str, args: &[Value<'t>])
+                      -> Result<Option<Vec<Value<'t>>>, CaptureError> {
+        let mut vm = self.vm.borrow_mut();
+        let m = match vm.do_match(input) {
+            Some(m) => m,
+            None => return Ok(None)
+        };
+        let values = try!(vm.get_values(input, args));
+        Ok(Some(if values.is_empty() { vec!(Slice(m)) } else { values }))
+    }
+
+    /// Matches the input, returning its concrete syntax tree: the rules that
+    /// matched, and the text of each, with the text of the rules named in
+    /// `trivia` kept as trivia (see `Vm::record_rules`).
+    pub fn cst<'t>(&self, input: &'t str, trivia: &[&str]) -> Option<Cst<'t>> {
+        let mut vm = self.vm.borrow_mut();
+        vm.record_rules(true);
+        let cst = match vm.do_match(input) {
+            Some(m) => Some(vm.get_cst(input, m.len(), trivia)),
+            None => None
+        };
+        vm.record_rules(false);
+        cst
+    }
+
+    /// Matches the input in recovery mode, giving a concrete syntax tree of
+    /// all of it, with what couldn't be matched in ERROR leaves, and the
+    /// errors; see `Vm::recover`.
+    pub fn recover<'t>(&self, input: &'t str, trivia: &[&str]) -> (Cst<'t>, Vec<MatchError>) {
+        self.vm.borrow_mut().recover(input, trivia)
+    }
+
+    /// Sets the text that error recovery resynchronizes on; see
+    /// `Vm::sync_tokens`.
+    pub fn sync_tokens(&mut self, tokens: &[&str]) {
+        self.vm.borrow_mut().sync_tokens(tokens)
+    }
+
+    /// Matches the input, then runs the actions that its captures name, in
+    /// the tree of captures, children first, on the state stack.  Returns
+    /// what's on top of the stack at the end.  The actions run only on the
+    /// captures the match kept, so none run for alternatives that failed.
+    pub fn run<S>(&self, input: &str, env: &mut Env<S>, stack: Vec<S>) -> Result<Option<S>, RunError> {
+        let tree = match self.tree(input) {
+            Some(tree) => tree,
+            None => return Err(NoMatch)
+        };
+        let mut stack = stack;
+        try!(run(&tree, env, &mut stack));
+        Ok(stack.pop())
+    }
+
+    /// Matches the input, collecting the errors of the labeled failures it
+    /// throws (see `parse::parse`), and the tree of the captures it made,
+    /// even if it failed.
+    pub fn parse<'t>(&self, input: &'t str) -> Parsed<'t> {
+        let mut vm = self.vm.borrow_mut();
+        match vm.do_match(input) {
+            Some(m) => Parsed {
+                tree: vm.get_tree(input, m.len(), &[]),
+                matched: true,
+                errors: vm.errors(input),
+            },
+            None => {
+                let mut errors = vm.errors(input);
+                if errors.is_empty() {
+                    errors.push(vm.match_error(input));
+                }
+                // the captures made up to the error that ended the match
+                let end = if errors[0].label.is_some() { errors.last().unwrap().pos } else { 0 };
+                Parsed { tree: vm.get_tree(input, end, &[]), matched: false, errors: errors }
+            }
+        }
+    }
+}
+
+/// The result of `Peg::parse`.
+pub struct Parsed<'t> {
+    /// The tree of the match's captures, as `Peg::tree` gives; if a labeled
+    /// failure ended the match, the tree of those made before it, which the
+    /// root ends at; if it failed otherwise, an empty root.
+    pub tree: Node<'t>,
+    /// Whether the grammar matched, recovering from any errors on the way.
+    pub matched: bool,
+    /// The errors of the labeled failures thrown, in order, the one that
+    /// ended the match last; or if it failed without throwing one, why it
+    /// failed, as `Peg::try_match` says.
+    pub errors: Vec<MatchError>,
+}
+
+fn next_char(s: &str, i: uint) -> uint {
+    if i < s.len() { s.char_range_at(i).next } else { i + 1 }
+}
+
+// The captures of a match, `m`, which starts at byte offset `start`, after
+// capture 0, which is the whole match.
+fn whole_match<'t>(m: &'t str, start: uint, caps: Vec<Capture<'t>>) -> Vec<Capture<'t>> {
+    let mut all = vec!(Capture { num: 0, name: None, start: start, end: start + m.len(), text: m,
+                                 value: None });
+    all.extend(caps.into_iter());
+    all
+}
+
+// How far a scan of the input for matches has got: where to search from
+// next, and where the last match ended.
+struct Scan {
+    at: uint,
+    last: Option<uint>,
+}
+
+impl Scan {
+    // Moves the scan on past a match.  Returns false if the match doesn't
+    // count: if it's empty, and just where the last one ended.
+    fn pass(&mut self, input: &str, start: uint, end: uint) -> bool {
+        let counts = !(start == end && self.last == Some(end));
+        if counts {
+            self.last = Some(end);
+        }
+        self.at = if end > start { end } else { next_char(input, end) };
+        counts
+    }
+}
+
+/// Iterator over the matches of a search, as byte offsets; see
+/// `Peg::find_iter`.
+pub struct FindMatches<'p, 't> {
+    peg: &'p Peg,
+    input: &'t str,
+    scan: Scan,
+}
+
+impl<'p, 't> Iterator<(uint, uint)> for FindMatches<'p, 't> {
+    fn next(&mut self) -> Option<(uint, uint)> {
+        while self.scan.at <= self.input.len() {
+            let (start, end) = match self.peg.vm.borrow_mut().find(self.input, self.scan.at) {
+                Some(m) => m,
+                None => break
+            };
+            if self.scan.pass(self.input, start, end) {
+                return Some((start, end))
+            }
+        }
+        self.scan.at = self.input.len() + 1;
+        None
+    }
+}
+
+/// Iterator over the captures of the matches of a search; see
+/// `Peg::captures_iter`.
+pub struct FindCaptures<'p, 't> {
+    peg: &'p Peg,
+    input: &'t str,
+    scan: Scan,
+}
+
+impl<'p, 't> Iterator<Vec<Capture<'t>>> for FindCaptures<'p, 't> {
+    fn next(&mut self) -> Option<Vec<Capture<'t>>> {
+        while self.scan.at <= self.input.len() {
+            let caps = match self.peg.captures_from(self.input, self.scan.at) {
+                Some(caps) => caps,
+                None => break
+            };
+            if self.scan.pass(self.input, caps[0].start, caps[0].end) {
+                return Some(caps)
+            }
+        }
+        self.scan.at = self.input.len() + 1;
+        None
+    }
+}
+
+/// Iterator over the text between the matches of a search; see
+/// `Peg::split`.
+pub struct Split<'p, 't> {
+    matches: FindMatches<'p, 't>,
+    last: uint,
+    done: bool,
+}
+
+impl<'p, 't> Iterator<&'t str> for Split<'p, 't> {
+    fn next(&mut self) -> Option<&'t str> {
+        let input = self.matches.input;
+        match self.matches.next() {
+            Some((start, end)) => {
+                let text = input.slice(self.last, start);
+                self.last = end;
+                Some(text)
+            }
+            None if self.done => None,
+            None => {
+                self.done = true;
+                Some(input.slice_from(self.last))
+            }
+        }
+    }
+}
+
+/// What `Peg::replace` replaces a match with: a template, or a closure from
+/// the match's captures to the replacement.
+pub trait Replacer {
+    /// The replacement for a match with the given captures, of which
+    /// capture 0 is the whole match.
+    fn replacement(&mut self, caps: &[Capture]) -> String;
+}
+
+/// A template, in which `$n` or `${n}` is the value of capture number n,
+/// `$name` or `${name}` that of the first capture named `name`, and `$$`
+/// is a '$'.  A capture that wasn't made is replaced by nothing.
+impl<'a> Replacer for &'a str {
+    fn replacement(&mut self, caps: &[Capture]) -> String {
+        expand(*self, caps)
+    }
+}
+
+impl<'a> Replacer for |&[Capture]|: 'a -> String {
+    fn replacement(&mut self, caps: &[Capture]) -> String {
+        (*self)(caps)
+    }
+}
+
+fn expand(template: &str, caps: &[Capture]) -> String {
+    let mut s = String::new();
+    let mut rest = template;
+    loop {
+        let i = match rest.find('$') {
+            Some(i) => i,
+            None => break
+        };
+        s.push_str(rest.slice_to(i));
+        rest = rest.slice_from(i + 1);
+        if rest.starts_with("$") {
+            s.push('$');
+            rest = rest.slice_from(1);
+            continue
+        }
+        // the capture's name or number, and the length of the reference
+        let (key, len) = if rest.starts_with("{") {
+            match rest.find('}') {
+                Some(j) => (rest.slice(1, j), j + 1),
+                None => ("", 0)
+            }
+        } else {
+            let j = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            (rest.slice_to(j), j)
+        };
+        if key.is_empty() {
+            s.push('$');
+            continue
+        }
+        let cap = match from_str::<uint>(key) {
+            Some(n) => caps.iter().find(|c| c.num == n),
+            None => caps.iter().find(|c| c.name.as_ref().map(|n| n.as_slice()) == Some(key))
+        };
+        match cap {
+            Some(cap) => s.push_str(format!("{}", cap.value()).as_slice()),
+            None => {}
+        }
+        rest = rest.slice_from(len);
+    }
+    s.push_str(rest);
+    s
+}
+
+#[test]
+fn peg_captures() {
+    let peg = Peg::new("{:key: [a-z]+ } '=' {} {:value: [0-9]+ }").unwrap();
+    let caps = peg.captures("x=42;").unwrap();
+    let spans: Vec<(uint, Option<String>, uint, uint, &str)> = caps.into_iter()
+        .map(|c| (c.num, c.name, c.start, c.end, c.text)).collect();
+    assert_eq!(spans, vec!(
+        (0, None, 0, 4, "x=42"),
+        (1, Some("key".to_string()), 0, 1, "x"),
+        (2, None, 2, 2, ""),
+        (3, Some("value".to_string()), 2, 4, "42")));
+    assert!(peg.captures("=42").is_none());
+    assert_eq!(peg.match_str("abc=1"), Some("abc=1"));
+}
+
+#[test]
+fn peg_captures_backtracking() {
+    // captures of an alternative that failed are dropped; nested ones
+    // are listed by where they were opened
+    let peg = Peg::new("S <- {A 'x'} / {A} 'y'  A <- {'a'}+").unwrap();
+    let caps = peg.captures("aay").unwrap();
+    let spans: Vec<(uint, &str)> = caps.iter().map(|c| (c.num, c.text)).collect();
+    assert_eq!(spans, vec!((0, "aay"), (2, "aa"), (3, "a"), (3, "a")));
+    // byte offsets, into UTF-8
+    let peg = Peg::new("'é' {.}").unwrap();
+    let caps = peg.captures("éè!").unwrap();
+    assert_eq!((caps[1].start, caps[1].end, caps[1].text), (2, 4, "è"));
+
+    match Peg::new("S <- T") {
+        Err(e) => assert_eq!(format!("{}", e), "compile error: rule 'T' undefined in given grammar".to_string()),
+        Ok(_) => fail!()
+    }
+}
+
+#[test]
+fn peg_tree() {
+    let peg = Peg::new("list <- {:list: '(' (sp (atom / list))* sp ')' :}
+                        atom <- {:atom: [a-z0-9]+ :}
+                        sp <- ' '*").unwrap();
+    let tree = peg.tree("(a (b 12) c)!").unwrap();
+    assert_eq!((tree.start, tree.end, tree.text), (0, 12, "(a (b 12) c)"));
+    let list = tree.child("list").unwrap();
+    let atoms: Vec<&str> = list.children_named("atom").iter().map(|n| n.text).collect();
+    assert_eq!(atoms, vec!("a", "c"));
+    let inner = list.child("list").unwrap();
+    assert_eq!((inner.start, inner.end, inner.text), (3, 9, "(b 12)"));
+    assert_eq!(inner.children.len(), 2);
+    assert_eq!(tree.find("atom").unwrap().text, "a");
+    let all: Vec<&str> = tree.iter().filter(|n| n.name.is_some()).map(|n| n.text).collect();
+    assert_eq!(all, vec!("(a (b 12) c)", "a", "(b 12)", "b", "12", "c"));
+    assert!(peg.tree("(a").is_none());
+
+    // an unnamed group passes its captures on; a table is a node of them
+    let peg = Peg::new("{: {'a'} {'b'} :} {| {'c'} {'d'} |}").unwrap();
+    let tree = peg.tree("abcd").unwrap();
+    let texts: Vec<&str> = tree.children.iter().map(|n| n.text).collect();
+    assert_eq!(texts, vec!("a", "b", "cd"));
+    assert_eq!(tree.children[2].children.len(), 2);
+}
+
+#[test]
+fn peg_tree_left_recursion() {
+    // left-associative, as the grammar is written: ((1-2)-3)
+    fn show(n: &Node) -> String {
+        if n.children.is_empty() {
+            return n.text.to_string()
+        }
+        let kids: Vec<String> = n.children.iter().map(show).collect();
+        format!("({})", kids.connect(" "))
+    }
+    let peg = Peg::new("E <- {| E {'-'} T |} / T  T <- {[0-9]}").unwrap();
+    assert_eq!(show(&peg.tree("1-2-3").unwrap()), "(((1 - 2) - 3))".to_string());
+    let peg = Peg::new("E <- {| E {[+-]} T |} / T  T <- {| T {[*/]} F |} / F  F <- {[0-9]}").unwrap();
+    assert_eq!(show(&peg.tree("1+2*3*4-5").unwrap()), "(((1 + ((2 * 3) * 4)) - 5))".to_string());
+}
+
+#[test]
+fn peg_substitution() {
+    use value::Text;
+    // a substitution capture's value is its text, with that of the captures
+    // directly inside it replaced by their values
+    let peg = Peg::new("{~ 'a' {'b'} {~ 'c' {'d'} ~} {} 'e' ~}").unwrap();
+    let caps = peg.captures("abcde").unwrap();
+    let values: Vec<&str> = caps.iter().map(|c| c.as_str()).collect();
+    assert_eq!(values, vec!("abcde", "abcde", "b", "cd", "d", ""));
+    assert_eq!(caps[1].value, Some(Text("abcde".to_string())));
+    assert_eq!(caps[2].value, None);
+}
+
+#[test]
+fn peg_replace() {
+    let peg = Peg::new("{:key: [a-z]+ } '=' {:value: [0-9]+ }").unwrap();
+    assert_eq!(peg.replace("x=1, y=22", "$value=$key"), "1=x, y=22".to_string());
+    assert_eq!(peg.replace_all("x=1, y=22", "$value=$key"), "1=x, 22=y".to_string());
+    assert_eq!(peg.replace_all("x=1, y=22", "${2}_$1$$ $0 $3 $"), "1_x$ x=1  $, 22_y$ y=22  $".to_string());
+    assert_eq!(peg.replace_all("no match", "!"), "no match".to_string());
+    assert_eq!(peg.replacen("a=1 b=2 c=3", 2, "$1"), "a b c=3".to_string());
+    // closures
+    let lens = peg.replace_all("x=1, y=22", |caps: &[Capture]| {
+        format!("{}:{}", caps[1].text, caps[2].text.len())
+    });
+    assert_eq!(lens, "x:1, y:2".to_string());
+    // empty matches, between chars
+    let peg = Peg::new("''").unwrap();
+    assert_eq!(peg.replace_all("aé", "-"), "-a-é-".to_string());
+    let peg = Peg::new("[0-9]*").unwrap();
+    assert_eq!(peg.replace_all("a12b", "<$0>"), "<>a<12>b<>".to_string());
+}
+
+#[test]
+fn peg_search() {
+    use vm::{Anchored, Whole};
+    let peg = Peg::new("[0-9]+ ('.' [0-9]+)?").unwrap();
+    assert!(peg.is_match("pi is 3.14"));
+    assert!(!peg.is_match("pi"));
+    assert_eq!(peg.find("pi is 3.14, e is 2.72"), Some((6, 10)));
+    assert_eq!(peg.find_iter("pi is 3.14, e is 2.72").collect::<Vec<(uint, uint)>>(),
+               vec!((6, 10), (17, 21)));
+    let nums: Vec<&str> = peg.captures_iter("x1 é22 y333").map(|caps| caps[0].text).collect();
+    assert_eq!(nums, vec!("1", "22", "333"));
+    // matching is still anchored at the start
+    assert_eq!(peg.match_str("pi 3"), None);
+    // found by a literal prefix, or by the lead byte of a char in the
+    // first set, and then the char
+    let peg = Peg::new("'ab' [0-9]").unwrap();
+    assert_eq!(peg.find_iter("aab ab ab1 éab2").collect::<Vec<(uint, uint)>>(),
+               vec!((7, 10), (13, 16)));
+    let peg = Peg::new("[é-ê] [a-z]").unwrap();
+    assert_eq!(peg.find("èx ëy éz"), Some((8, 11)));
+    let mut peg = Peg::new("[0-9]+ ('.' [0-9]+)?").unwrap();
+    // only where the search starts
+    peg.anchor(Anchored);
+    assert_eq!(peg.find("pi 3"), None);
+    assert_eq!(peg.find("3.14 2"), Some((0, 4)));
+    assert_eq!(peg.find_iter("1 2").collect::<Vec<(uint, uint)>>(), vec!((0, 1)));
+    // and to the end of the input
+    peg.anchor(Whole);
+    assert_eq!(peg.find("3.14"), Some((0, 4)));
+    assert_eq!(peg.find("3.14 "), None);
+    // anchored searches from the end of each match make a tokenizer
+    let mut tokens = Peg::new("[a-z]+ / [0-9]+ / ' '+").unwrap();
+    tokens.anchor(Anchored);
+    assert_eq!(tokens.find_iter("ab  12!x").collect::<Vec<(uint, uint)>>(),
+               vec!((0, 2), (2, 4), (4, 6)));
+    // captures are at their offsets in the input
+    let peg = Peg::new("{[a-z]} '=' {[0-9]+}").unwrap();
+    let caps: Vec<(uint, uint, &str)> = peg.captures_iter("x=1, y=22").map(|caps| {
+        (caps[0].start, caps[2].start, caps[2].text)
+    }).collect();
+    assert_eq!(caps, vec!((0, 2, "1"), (5, 7, "22")));
+    // empty matches, between chars, but not just after a match
+    let peg = Peg::new("[0-9]*").unwrap();
+    assert_eq!(peg.find_iter("a12b").collect::<Vec<(uint, uint)>>(), vec!((0, 0), (1, 3), (4, 4)));
+    let comma = Peg::new("' '* ',' ' '*").unwrap();
+    assert_eq!(comma.split("a, b ,c,").collect::<Vec<&str>>(), vec!("a", "b", "c", ""));
+    assert_eq!(comma.split("").collect::<Vec<&str>>(), vec!(""));
+}
+
+#[test]
+fn peg_rewrite() {
+    // a grammar-aware rewrite: rename the variable `x` to `y`, but not in
+    // longer names or in strings, which are matched whole
+    let peg = Peg::new("
+        token <- string / {:x: 'x' !w :} / w+
+        string <- '\"' (!'\"' .)* '\"'
+        w <- [a-zA-Z0-9_]").unwrap();
+    let rename = |caps: &[Capture]| -> String {
+        if caps.iter().any(|c| c.name == Some("x".to_string())) {
+            "y".to_string()
+        } else {
+            caps[0].text.to_string()
+        }
+    };
+    assert_eq!(peg.replace_all("x + xs(\"x\") * ax / x", rename),
+               "y + xs(\"x\") * ax / y".to_string());
+}
+
+#[test]
+fn peg_backrefs() {
+    // Lua long strings: the closing brackets have as many '='s as the opening
+    let peg = Peg::new("'[' {:eq: '='* :} '[' (!(']' =eq ']') .)* ']' =eq ']'").unwrap();
+    assert_eq!(peg.match_str("[==[a]]b]=]c]==]d"), Some("[==[a]]b]=]c]==]"));
+    assert_eq!(peg.match_str("[[x]]"), Some("[[x]]"));
+    assert_eq!(peg.match_str("[=[x]]"), None);
+    // shell heredocs, through a rule that refers back
+    let peg = Peg::new("
+        doc <- '<<' {:tag: [A-Z]+ :} nl (!end line)* end
+        end <- =tag nl
+        line <- (!nl .)* nl
+        nl <- '\n'").unwrap();
+    let text = "<<EOF\nEOFS\nEO\nEOF\nrest";
+    assert_eq!(peg.match_str(text), Some("<<EOF\nEOFS\nEO\nEOF\n"));
+    let caps = peg.captures(text).unwrap();
+    let spans: Vec<(uint, &str)> = caps.iter().map(|c| (c.num, c.text)).collect();
+    assert_eq!(spans, vec!((0, "<<EOF\nEOFS\nEO\nEOF\n"), (1, "EOF"), (1, "EOF")));
+    // the rule that refers back isn't memoized
+    let peg = Peg::new("S <- {:q: [\"'] :} Q / 'x'  Q <- (!=q .)* =q").unwrap();
+    peg.vm.borrow_mut().memoize_all(100);
+    assert_eq!(peg.match_str("'a\"b'c"), Some("'a\"b'"));
+    assert!(peg.vm.borrow_mut().memoize(&["Q"], 100).is_err());
+    assert!(peg.vm.borrow_mut().memoize(&["S"], 100).is_err());
+    // backtracking: the capture made in a failed alternative is dropped
+    let peg = Peg::new("{:d: 'a' :} 'b' / {:d: 'c' :} =d").unwrap();
+    assert_eq!(peg.match_str("cc"), Some("cc"));
+    assert_eq!(peg.match_str("ca"), None);
+    let peg = Peg::new("{:d: [0-9] :} ({:d: [a-z] :} '!' / '?') =d").unwrap();
+    assert_eq!(peg.match_str("1?1"), Some("1?1"));
+    assert_eq!(peg.match_str("1a?a"), None);
+    assert_eq!(peg.match_str("1a!a"), Some("1a!a"));
+}
+
+#[test]
+fn peg_match_time() {
+    use capture::{RunTime, Outcome, Accept, Reject};
+    use value::Text;
+    // validate a number's range
+    let mut peg = Peg::new("{:n: [0-9]+ :} => byte '.'").unwrap();
+    peg.on_match("byte", box |m: &RunTime| -> Outcome {
+        match from_str::<uint>(m.text) {
+            Some(n) if n < 256 => m.accept(),
+            _ => Reject
+        }
+    }).unwrap();
+    assert_eq!(peg.match_str("255."), Some("255."));
+    assert_eq!(peg.match_str("256."), None);
+    // its captures are dropped, without values in their place
+    assert_eq!(peg.captures("7.").unwrap().len(), 1);
+    assert!(peg.on_match("word", box |m: &RunTime| -> Outcome { m.accept() }).is_err());
+
+    // keywords against a symbol table, with backtracking to another rule
+    let mut peg = Peg::new("
+        stmt <- keyword sp name / name sp '=' sp name
+        keyword <- {[a-z]+} => kw
+        name <- {:name: [a-z]+ :}
+        sp <- ' '*").unwrap();
+    let keywords = vec!("let", "var");
+    peg.on_match("kw", box move |m: &RunTime| -> Outcome {
+        if keywords.iter().any(|k| *k == m.text) {
+            Accept(m.end, vec!(Text(m.captures[0].text.to_string() + "!")))
+        } else {
+            Reject
+        }
+    }).unwrap();
+    let texts = |s: &str| -> Vec<(uint, String)> {
+        peg.captures(s).unwrap().iter().map(|c| (c.num, c.as_str().to_string())).collect()
+    };
+    assert_eq!(texts("let x"), vec!((0, "let x".to_string()), (2, "let!".to_string()),
+                                    (3, "x".to_string())));
+    assert_eq!(texts("y = x"), vec!((0, "y = x".to_string()), (3, "y".to_string()),
+                                    (3, "x".to_string())));
+
+    // length-prefixed data: the count, then that many chars, read by
+    // moving the match on
+    let mut peg = Peg::new("S <- (len => chunk)* !.  len <- [0-9]+ ':'").unwrap();
+    peg.on_match("chunk", box |m: &RunTime| -> Outcome {
+        let n = from_str::<uint>(m.text.slice_to(m.text.len() - 1)).unwrap();
+        match m.input.slice_from(m.end).char_indices().nth(n) {
+            Some((k, _)) => Accept(m.end + k, vec!(Text(m.input.slice(m.end, m.end + k).to_string()))),
+            None if m.input.slice_from(m.end).chars().count() == n =>
+                Accept(m.input.len(), vec!(Text(m.input.slice_from(m.end).to_string()))),
+            None => Reject
+        }
+    }).unwrap();
+    let caps = peg.captures("3:abc0:2:é:").unwrap();
+    let values: Vec<&str> = caps.iter().skip(1).map(|c| c.as_str()).collect();
+    assert_eq!(values, vec!("abc", "", "é:"));
+    assert_eq!((caps[3].start, caps[3].end, caps[3].text), (7, 12, "2:é:"));
+    assert!(peg.captures("3:ab").is_none());
+
+    // a function's values all span its match: in a substitution, the first
+    // takes the place of its text
+    let mut peg = Peg::new("{~ ([a-z]+ => wrap ',')* ~}").unwrap();
+    peg.on_match("wrap", box |m: &RunTime| -> Outcome {
+        Accept(m.end, vec!(Text(format!("<{}>", m.text)), Text(m.text.to_string())))
+    }).unwrap();
+    assert_eq!(peg.values("ab,cd,", &[]), Ok(Some(vec!(Text("<ab>,<cd>,".to_string())))));
+    let spans: Vec<(uint, uint)> = peg.captures("ab,").unwrap().iter().map(|c| (c.start, c.end)).collect();
+    assert_eq!(spans, vec!((0, 3), (0, 3), (0, 2), (0, 2)));
+
+    // a function not given yet fails the match, and says so
+    let mut peg = Peg::new("'a' => f 'b'").unwrap();
+    assert_eq!(peg.match_str("ab"), None);
+    assert_eq!(peg.find("xab"), None);
+    let e = peg.try_match("ab").err().unwrap();
+    assert_eq!((e.pos, e.fault), (0, Some("no function given for match-time captures of 'f'".to_string())));
+    // one that moves the match back, or into a char, breaks it off
+    peg.on_match("f", box |m: &RunTime| -> Outcome { Accept(m.start, vec!()) }).unwrap();
+    assert_eq!(peg.match_str("ab"), None);
+    assert_eq!(peg.try_match("ab").err().unwrap().pos, 1);
+    let mut peg = Peg::new("('a' => f / 'a') .").unwrap();
+    peg.on_match("f", box |m: &RunTime| -> Outcome { Accept(m.end + 1, vec!()) }).unwrap();
+    assert_eq!(peg.match_str("aéb"), None);
+    assert!(peg.try_match("aéb").err().unwrap().fault.is_some());
+    assert_eq!(peg.match_str("abc"), Some("abc"));
+}
+
+#[test]
+fn peg_values() {
+    use value::{Text, Int, Float, Bool, List, Map};
+    use std::collections::BTreeMap;
+    fn values<'t>(src: &str, input: &'t str) -> Vec<Value<'t>> {
+        let peg = Peg::new(src).unwrap();
+        peg.values(input, &[Int(7), Text("x".to_string())]).unwrap().unwrap()
+    }
+    assert_eq!(values("$'a' $true $-2 $1.5 %1 %2 [a-z]+", "ab"),
+               vec!(Text("a".to_string()), Bool(true), Int(-2), Float(1.5), Int(7),
+                    Text("x".to_string())));
+    assert_eq!(values("[a-z]+", "ab!"), vec!(Text("ab".to_string())));
+    // formats and numbered values
+    assert_eq!(values("({[a-z]+} '=' {[0-9]+}) -> '%2 is %1 (%0), 100%%'", "x=1"),
+               vec!(Text("1 is x (x=1), 100%".to_string())));
+    assert_eq!(values("({'a'} {'b'} {}) -> 3", "ab"), vec!(Int(2)));
+    // tables are lists, or maps if they have named groups
+    assert_eq!(values("{| {'a'} {: {'b'} {'c'} :} |}", "abc"),
+               vec!(List(vec!(Text("a".to_string()), Text("b".to_string()), Text("c".to_string())))));
+    let mut m = BTreeMap::new();
+    m.insert("1".to_string(), Text("a".to_string()));
+    m.insert("k".to_string(), Text("b".to_string()));
+    assert_eq!(values("{| {'a'} {:k: {'b'} :} |}", "ab"), vec!(Map(m)));
+    // a substitution shows the values inside it
+    assert_eq!(values("{~ ('a' -> '<%0>' / 'b' $1)* ~}", "abab"),
+               vec!(Text("<a>b1<a>b1".to_string())));
+
+    // values that can't be made are errors, not values
+    let error = |src: &str, input: &str| -> String {
+        let peg = Peg::new(src).unwrap();
+        format!("{}", peg.values(input, &[Int(7)]).err().unwrap())
+    };
+    assert_eq!(error("'a' %2", "a"), "capture 1: no extra argument 2 given to the match".to_string());
+    assert_eq!(error("{'a'} -> 2", "a"), "capture 2: no value 2 to give".to_string());
+    assert_eq!(error("'a' -> '%1'", "a"), "capture 1: no value 1 to format".to_string());
+    let mut peg = Peg::new("'a' ~> f").unwrap();
+    peg.function("f", box |vs: &[Value]| -> Value<'static> { Int(vs.len() as i64) }).unwrap();
+    assert_eq!(format!("{}", peg.values("a", &[]).err().unwrap()), "capture 1: no values to fold".to_string());
+    // and matching with a function not given fails, and says so
+    let peg = Peg::new("'a' -> f").unwrap();
+    assert_eq!(peg.values("a", &[]), Ok(None));
+    assert_eq!(peg.try_match("a").err().unwrap().fault,
+               Some("no function given for the captures that call 'f'".to_string()));
+}
+
+#[test]
+fn peg_calculator() {
+    use value::Int;
+    let mut peg = Peg::new("
+        calc <- sp exp !.
+        exp <- (term {| {[+-]} sp term |}*) ~> apply
+        term <- (factor {| {[*/]} sp factor |}*) ~> apply
+        factor <- num / '(' sp exp ')' sp
+        num <- [0-9]+ -> int sp
+        sp <- ' '*").unwrap();
+    peg.function("int", box |vs: &[Value]| -> Value<'static> {
+        Int(from_str::<i64>(vs[0].as_str().unwrap()).unwrap())
+    }).unwrap();
+    peg.function("apply", box |vs: &[Value]| -> Value<'static> {
+        let op = vs[1].as_list().unwrap();
+        let (a, b) = (vs[0].as_int().unwrap(), op[1].as_int().unwrap());
+        Int(match op[0].as_str().unwrap() {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            _ => a / b
+        })
+    }).unwrap();
+    assert_eq!(peg.values("1 + 2 * 3", &[]), Ok(Some(vec!(Int(7)))));
+    assert_eq!(peg.values(" (1 + 2) * 3 - 20 / 4 / 5", &[]), Ok(Some(vec!(Int(8)))));
+    assert_eq!(peg.values("10 - 2 - 3", &[]), Ok(Some(vec!(Int(5)))));
+    assert_eq!(peg.values("1 +", &[]), Ok(None));
+    assert!(peg.function("sum", box |_: &[Value]| -> Value<'static> { Int(0) }).is_err());
+}
+
+#[test]
+fn peg_run() {
+    use capture::Node;
+    use run::{Env, ActionFailed, NoMatch};
+    // a stack machine: numbers push, operators pop two and push one
+    let peg = Peg::with_rule_nodes("
+        sum <- term (sp (add / sub))*
+        add <- '+' sp term
+        sub <- '-' sp term
+        term <- num (sp mul)*
+        mul <- '*' sp num
+        num <- [0-9]+
+        sp <- ' '*").unwrap();
+    let mut env: Env<i64> = Env::new();
+    env.action("num", box |stack: &mut Vec<i64>, n: &Node| -> Result<(), String> {
+        stack.push(from_str::<i64>(n.text).unwrap());
+        Ok(())
+    });
+    fn binary(stack: &mut Vec<i64>, f: |i64, i64| -> i64) -> Result<(), String> {
+        let b = stack.pop().unwrap();
+        let a = stack.pop().unwrap();
+        stack.push(f(a, b));
+        Ok(())
+    }
+    env.action("add", box |stack: &mut Vec<i64>, _: &Node| -> Result<(), String> {
+        binary(stack, |a, b| a + b)
+    });
+    env.action("sub", box |stack: &mut Vec<i64>, _: &Node| -> Result<(), String> {
+        binary(stack, |a, b| a - b)
+    });
+    env.action("mul", box |stack: &mut Vec<i64>, _: &Node| -> Result<(), String> {
+        binary(stack, |a, b| a * b)
+    });
+    assert_eq!(peg.run("2 * 3 + 4 - 5 * 2", &mut env, vec!()), Ok(Some(0)));
+    assert_eq!(peg.run("7", &mut env, vec!()), Ok(Some(7)));
+    assert_eq!(peg.run("x", &mut env, vec!()), Err(NoMatch));
+
+    // an alternative that fails after its actions' captures were made
+    // leaves nothing on the stack
+    let peg = Peg::new("{:item: [a-z] :}+ '!' / {:other: [a-z]+ :} '?'").unwrap();
+    let mut env: Env<String> = Env::new();
+    env.action("item", box |stack: &mut Vec<String>, n: &Node| -> Result<(), String> {
+        stack.push(n.text.to_string());
+        Ok(())
+    });
+    env.action("other", box |stack: &mut Vec<String>, n: &Node| -> Result<(), String> {
+        if n.text.len() > 3 {
+            return Err("too long".to_string())
+        }
+        stack.push(format!("<{}>", n.text));
+        Ok(())
+    });
+    let mut stack = vec!("bottom".to_string());
+    assert_eq!(peg.run("abc?", &mut env, stack.clone()), Ok(Some("<abc>".to_string())));
+    assert_eq!(peg.run("abc!", &mut env, stack.clone()), Ok(Some("c".to_string())));
+    stack.push("top".to_string());
+    assert_eq!(peg.run("abcd?", &mut env, stack),
+               Err(ActionFailed("other".to_string(), 0, "too long".to_string())));
+}
+
+#[test]
+fn peg_match_errors() {
+    use error::{ExpectChar, ExpectClass, ExpectAny, ExpectRule};
+    let peg = Peg::new("
+        config <- sp (pair sp)* !.
+        pair <- key sp '=' sp value
+        key <- [a-z_]+
+        value <- string / number / bool
+        string <- '\"' (!'\"' .)* '\"'
+        number <- [0-9]+
+        bool <- 'true' / 'false'
+        sp <- [ \t\n]*").unwrap();
+    assert_eq!(peg.try_match("a = 1\nb = \"x\"\n"), Ok("a = 1\nb = \"x\"\n"));
+    // a rule that fails where it's called is expected as a whole
+    let e = peg.try_match("a = 1\nb = @\n").err().unwrap();
+    assert_eq!((e.pos, e.line, e.col, e.found), (10, 2, 5, Some('@')));
+    assert_eq!(e.expected, vec!(ExpectRule("value".to_string())));
+    assert_eq!(format!("{}", e), "line 2, column 5: expected value, found '@'\nb = @\n    ^".to_string());
+    // the farthest failure wins, inside a rule that got somewhere
+    let e = peg.try_match("a = tru").err().unwrap();
+    assert_eq!((e.pos, e.expected.clone()), (7, vec!(ExpectChar('e'))));
+    assert_eq!(format!("{}", e), "line 1, column 8: expected 'e', found end of input\na = tru\n       ^".to_string());
+    let e = peg.try_match("a = \"x").err().unwrap();
+    assert_eq!((e.pos, e.expected.clone()), (6, vec!(ExpectChar('"'), ExpectAny)));
+    let e = peg.try_match("ab1 = 2").err().unwrap();
+    assert_eq!((e.pos, e.expected.clone()), (2, vec!(ExpectChar('='))));
+    // what a pattern that isn't a grammar expected
+    let peg = Peg::new("[a-c]+ ('x' / .)").unwrap();
+    let e = peg.try_match("d").err().unwrap();
+    assert_eq!(e.expected, vec!(ExpectClass("[a-c]".to_string())));
+    assert_eq!(format!("{}", peg.try_match("ab").err().unwrap()),
+               "line 1, column 3: expected 'x' or any character, found end of input\nab\n  ^".to_string());
+}
+
+#[test]
+fn peg_labeled_errors() {
+    fn errors(parsed: &Parsed) -> Vec<(String, uint, uint)> {
+        parsed.errors.iter().map(|e| (e.label.clone().unwrap(), e.line, e.col)).collect()
+    }
+    // recovery rules skip past each error, and the match goes on
+    let peg = Peg::new("
+        prog <- sp (stmt sp)* !.
+        stmt <- {:call: name sp '(' sp args? (')' / ^MissingParen) sp (';' / ^MissingSemi) :}
+        args <- {name} sp (',' sp {name} sp)*
+        name <- [a-z]+
+        sp <- [ \\n]*
+        MissingParen <- (![;\\n] .)*
+        MissingSemi <- ''").unwrap();
+    let parsed = peg.parse("f(a);\ng(b;\nh(c, d)\n");
+    assert!(parsed.matched);
+    assert_eq!(errors(&parsed), vec!(("MissingParen".to_string(), 2, 4),
+                                     ("MissingSemi".to_string(), 4, 1)));
+    assert_eq!(format!("{}", parsed.errors[0]), "line 2, column 4: MissingParen, found ';'\ng(b;\n   ^".to_string());
+    let calls: Vec<Vec<&str>> = parsed.tree.children.iter().map(|n| {
+        n.children.iter().map(|c| c.text).collect()
+    }).collect();
+    assert_eq!(calls, vec!(vec!("a"), vec!("b"), vec!("c", "d")));
+    assert!(peg.parse("f(a);").errors.is_empty());
+    // with no recovery rule, a label ends the match, which no choice catches
+    let peg = Peg::new("prog <- stmt* !.  stmt <- {[a-z]} '(' (')' / ^MissingParen) ';' / 'x'").unwrap();
+    let parsed = peg.parse("f();g(;");
+    assert!(!parsed.matched);
+    assert_eq!(errors(&parsed), vec!(("MissingParen".to_string(), 1, 7)));
+    assert_eq!(parsed.tree.end, 6);
+    assert_eq!(parsed.tree.children.iter().map(|n| n.text).collect::<Vec<&str>>(), vec!("f", "g"));
+    assert_eq!(peg.match_str("f();g(;"), None);
+    // a failure without a label is reported as try_match reports it
+    let parsed = peg.parse("f();1");
+    assert!(!parsed.matched && parsed.tree.children.is_empty());
+    assert_eq!((parsed.errors[0].label.clone(), parsed.errors[0].pos), (None, 4));
+    // the errors of an alternative that failed after recovering are dropped
+    let peg = Peg::new("S <- 'a' ('b' / ^B) 'c' / 'a' .*  B <- ''").unwrap();
+    let parsed = peg.parse("ax");
+    assert!(parsed.matched && parsed.errors.is_empty());
+    assert_eq!(peg.parse("ac").errors.len(), 1);
+}
+
+#[test]
+fn peg_recovery() {
+    use cst::{RuleNode, Token, Trivia, ErrorNode};
+    use error::{Expected, ExpectChar, ExpectRule};
+    // the shape of a tree, with trivia left out and errors in angle brackets
+    fn shape(node: &Cst) -> String {
+        let kids: Vec<String> = node.children.iter().filter_map(|c| match *c {
+            RuleNode(ref n) => Some(shape(n)),
+            Token(text) => Some(text.to_string()),
+            Trivia(_) => None,
+            ErrorNode(text) => Some(format!("<{}>", text)),
+        }).collect();
+        format!("({} {})", node.rule.as_ref().map(|r| r.as_slice()).unwrap_or("^"), kids.connect(" "))
+    }
+    // where each error is, and what was expected there
+    fn expected(errors: &[MatchError]) -> Vec<(uint, Vec<Expected>)> {
+        errors.iter().map(|e| (e.pos, e.expected.clone())).collect()
+    }
+    let peg = Peg::new("
+        prog <- sp (stmt sp)* !.
+        stmt <- name sp '(' sp args? ')' sp ';'
+        args <- name sp (',' sp name sp)*
+        name <- [a-z]+
+        sp <- [ \\n]*").unwrap();
+    let src = "f(a);\ng(b c);\n123\nh(d);\nk(";
+    let (cst, errors) = peg.recover(src, &["sp"]);
+    assert_eq!(cst.to_source(), src.to_string());
+    assert_eq!(shape(&cst), "(^ (prog (stmt (name f) ( (args (name a)) ) ;) \
+                             (stmt (name g) ( (args (name b) <c>) ) ;) <123\n> \
+                             (stmt (name h) ( (args (name d)) ) ;) <k(>))".to_string());
+    // skipping resynchronizes before what was expected, or what can follow
+    // the rule it was expected in; at the end of the input, the rule's
+    // match is skipped
+    let found: Vec<(uint, uint, Vec<Expected>)> =
+        errors.iter().map(|e| (e.line, e.col, e.expected.clone())).collect();
+    assert_eq!(found, vec!((2, 5, vec!(ExpectChar(','), ExpectChar(')'))),
+                           (3, 1, vec!(ExpectRule("stmt".to_string()))),
+                           (5, 3, vec!(ExpectRule("args".to_string()), ExpectChar(')')))));
+    // input that matches has no errors, and the tree `cst` gives
+    let (tree, errors) = peg.recover("f(a);", &["sp"]);
+    assert!(errors.is_empty());
+    assert_eq!(Some(tree), peg.cst("f(a);", &["sp"]));
+    // sync tokens, in place of the grammar's sets
+    let mut peg = peg;
+    peg.sync_tokens(&["\n"]);
+    let (cst, _) = peg.recover("f(a);\n123 456\nh(d);", &["sp"]);
+    assert_eq!(shape(&cst), "(^ (prog (stmt (name f) ( (args (name a)) ) ;) <123 456> \
+                             (stmt (name h) ( (args (name d)) ) ;)))".to_string());
+    // what can't be matched at all is one error, the first one found
+    let peg = Peg::new("'a' 'b'").unwrap();
+    let (cst, errors) = peg.recover("ax", &[]);
+    assert_eq!(shape(&cst), "(^ <ax>)".to_string());
+    assert_eq!(expected(errors.as_slice()), vec!((1, vec!(ExpectChar('b')))));
+    // a match that ends early says what it expected where it ended
+    let peg = Peg::new("('a' / 'b')*").unwrap();
+    let (cst, errors) = peg.recover("abx", &[]);
+    assert_eq!(shape(&cst), "(^ ab <x>)".to_string());
+    assert_eq!(expected(errors.as_slice()), vec!((2, vec!(ExpectChar('a'), ExpectChar('b')))));
+}
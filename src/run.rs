@@ -0,0 +1,76 @@
+//! Semantic actions: functions named by the captures of a grammar, run on a
+//! match against a stack of the caller's state, as the third way of using a
+//! Program, after recognizing and matching.
+//!
+//! Actions run once the match is over, on the tree of the captures it
+//! kept, children before parents, so an action finds what the actions of
+//! the captures inside it left on the stack.  The captures of alternatives
+//! that failed were dropped as the VM backtracked, so an action never runs
+//! for a parse that didn't happen, and there's no effect to undo.
+
+use std::fmt;
+use capture::Node;
+
+/// An action: it gets the state stack and the node of the capture that
+/// named it, and can fail the run with a message.
+pub type Action<S> = Box<FnMut(&mut Vec<S>, &Node) -> Result<(), String> + 'static>;
+
+/// The actions a run calls, by name.
+pub struct Env<S> {
+    actions: Vec<(String, Action<S>)>,
+}
+
+impl<S> Env<S> {
+    pub fn new() -> Env<S> {
+        Env { actions: vec!() }
+    }
+
+    /// Gives the action that captures named `name` call, replacing any it
+    /// had.
+    pub fn action(&mut self, name: &str, f: Action<S>) {
+        match self.actions.iter().position(|&(ref n, _)| n.as_slice() == name) {
+            Some(k) => { *self.actions.get_mut(k) = (name.to_string(), f); }
+            None => self.actions.push((name.to_string(), f))
+        }
+    }
+}
+
+/// Why a run gave no result.
+#[deriving(Clone,PartialEq)]
+pub enum RunError {
+    /// The grammar didn't match the input.
+    NoMatch,
+    /// An action failed: its name, the byte offset its capture starts at,
+    /// and its message.
+    ActionFailed(String, uint, String),
+}
+
+impl fmt::Show for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NoMatch => write!(f, "no match"),
+            ActionFailed(ref name, pos, ref msg) =>
+                write!(f, "action '{}' failed at byte {}: {}", name, pos, msg),
+        }
+    }
+}
+
+/// Runs the actions of the nodes of a tree of captures, depth first, each
+/// node's after those of its children.  Nodes with no action are passed
+/// through.
+pub fn run<'t, S>(node: &Node<'t>, env: &mut Env<S>, stack: &mut Vec<S>) -> Result<(), RunError> {
+    for child in node.children.iter() {
+        try!(run(child, env, stack));
+    }
+    let name = match node.name {
+        Some(ref name) => name,
+        None => return Ok(())
+    };
+    for entry in env.actions.iter_mut() {
+        let (ref n, ref mut f) = *entry;
+        if n == name {
+            return (*f)(stack, node).map_err(|msg| ActionFailed(name.clone(), node.start, msg))
+        }
+    }
+    Ok(())
+}
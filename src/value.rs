@@ -0,0 +1,142 @@
+//! The values that captures produce.  A capture's value is its text,
+//! unless it computes another: a constant, an argument of the match, a
+//! formatted string, or whatever a function makes of the values of the
+//! captures inside it.
+
+use std::any::{Any, AnyRefExt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// A capture's value.  Slices borrow from the input that was matched.
+#[deriving(Clone)]
+pub enum Value<'t> {
+    Slice(&'t str),
+    Text(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<Value<'t>>),
+    Map(BTreeMap<String, Value<'t>>),
+    /// Anything else a function makes, shared.
+    Data(Rc<Box<Any + 'static>>),
+}
+
+impl<'t> Value<'t> {
+    /// The string, of a slice or a text.
+    pub fn as_str<'a>(&'a self) -> Option<&'a str> {
+        match *self {
+            Slice(s) => Some(s),
+            Text(ref s) => Some(s.as_slice()),
+            _ => None
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Int(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// The number, of a float or an int.
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Float(x) => Some(x),
+            Int(n) => Some(n as f64),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Bool(b) => Some(b),
+            _ => None
+        }
+    }
+
+    pub fn as_list<'a>(&'a self) -> Option<&'a [Value<'t>]> {
+        match *self {
+            List(ref vs) => Some(vs.as_slice()),
+            _ => None
+        }
+    }
+
+    pub fn as_map<'a>(&'a self) -> Option<&'a BTreeMap<String, Value<'t>>> {
+        match *self {
+            Map(ref m) => Some(m),
+            _ => None
+        }
+    }
+
+    /// The data, if it's a `T`.
+    pub fn data<'a, T: 'static>(&'a self) -> Option<&'a T> {
+        match *self {
+            Data(ref d) => (**d).downcast_ref::<T>(),
+            _ => None
+        }
+    }
+}
+
+// Data are equal only to themselves.
+impl<'t> PartialEq for Value<'t> {
+    fn eq(&self, other: &Value<'t>) -> bool {
+        match (self, other) {
+            (&Data(ref a), &Data(ref b)) => &**a as *const Box<Any> == &**b as *const Box<Any>,
+            (&Data(_), _) | (_, &Data(_)) => false,
+            (&Slice(_), _) | (&Text(_), _) => self.as_str() == other.as_str(),
+            (&Int(a), &Int(b)) => a == b,
+            (&Float(a), &Float(b)) => a == b,
+            (&Bool(a), &Bool(b)) => a == b,
+            (&List(ref a), &List(ref b)) => a == b,
+            (&Map(ref a), &Map(ref b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+/// Strings are shown as they are, so that formatting a value gives the
+/// text a string capture puts in its place.
+impl<'t> fmt::Show for Value<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Slice(s) => write!(f, "{}", s),
+            Text(ref s) => write!(f, "{}", s),
+            Int(n) => write!(f, "{}", n),
+            Float(x) => write!(f, "{}", x),
+            Bool(b) => write!(f, "{}", b),
+            List(ref vs) => {
+                try!(write!(f, "["));
+                for (k, v) in vs.iter().enumerate() {
+                    try!(write!(f, "{}{}", if k > 0 { ", " } else { "" }, v));
+                }
+                write!(f, "]")
+            }
+            Map(ref m) => {
+                try!(write!(f, "{{"));
+                for (k, (key, v)) in m.iter().enumerate() {
+                    try!(write!(f, "{}{}: {}", if k > 0 { ", " } else { "" }, key, v));
+                }
+                write!(f, "}}")
+            }
+            Data(_) => write!(f, "<data>"),
+        }
+    }
+}
+
+#[test]
+fn values() {
+    let mut m = BTreeMap::new();
+    m.insert("b".to_string(), List(vec!(Int(1), Float(1.5), Bool(true))));
+    m.insert("a".to_string(), Slice("x"));
+    let v = Map(m);
+    assert_eq!(format!("{}", v), "{a: x, b: [1, 1.5, true]}".to_string());
+    assert_eq!(v.as_map().unwrap().get(&"a".to_string()), Some(&Text("x".to_string())));
+    assert_eq!(Int(2).as_float(), Some(2.0));
+    assert!(Text("1".to_string()) != Int(1));
+    let d = Data(Rc::new(box (7 as uint) as Box<Any>));
+    assert_eq!(d.data::<uint>(), Some(&7));
+    assert!(d.data::<int>().is_none());
+    assert!(d == d.clone());
+    assert!(d != Data(Rc::new(box (7 as uint) as Box<Any>)));
+}
@@ -0,0 +1,463 @@
+//! Checking grammars before they're compiled, as LPeg's `verify` and
+//! `checkrule` do: a grammar whose loops can match nothing would loop
+//! forever, and one that calls a rule it doesn't define can't be linked.
+//! The analyses here (which rules can match the empty string, which call
+//! which before consuming input) are also what the compiler uses to find
+//! left-recursive rules, and rules whose matches depend on what was captured
+//! before they're called.
+
+use std::fmt;
+use ast::{Ast, Rule, Span, Nil, Lit, Dot, Cls, Seq, Alt, Rep, And, Not, Cap, Pos, Table, Group,
+          Subst, Backref, RunTime, Const, Arg, Format, Num, Function, Fold, Throw,
+          Grammar, NonTerm};
+use ast::{ZeroOne, OneMore};
+
+/// A problem with a grammar.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Problem {
+    /// The rule can call itself before consuming any input.  It's matched
+    /// by growing a seed, so this is only a warning.
+    LeftRecursion,
+    /// A loop whose body can match the empty string, so would never end.
+    EmptyLoop,
+    /// A reference to a rule its grammar doesn't define.
+    UndefinedRule(String),
+    /// A rule that can't be reached from the first rule of its grammar.
+    UnusedRule,
+    /// A rule with the name of an earlier one.
+    DuplicateRule,
+    /// A back-reference to a name that no capture has.
+    UndefinedCapture(String),
+}
+
+/// A problem, the rule it's in, and where that rule is in the source.
+#[deriving(Clone,PartialEq)]
+pub struct Diagnostic {
+    pub problem: Problem,
+    /// The rule the problem is in; None outside of any grammar.
+    pub rule: Option<String>,
+    /// The span of that rule, if the grammar was parsed from text.
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    /// Errors keep a grammar from being compiled; the others are warnings.
+    pub fn is_error(&self) -> bool {
+        match self.problem {
+            LeftRecursion | UnusedRule => false,
+            EmptyLoop | UndefinedRule(_) | DuplicateRule | UndefinedCapture(_) => true,
+        }
+    }
+}
+
+impl fmt::Show for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.problem, &self.rule) {
+            (&LeftRecursion, &Some(ref r)) => write!(f, "rule '{}' is left-recursive", r),
+            (&EmptyLoop, &Some(ref r)) =>
+                write!(f, "rule '{}' has a loop whose body can match the empty string", r),
+            (&EmptyLoop, &None) => write!(f, "a loop's body can match the empty string"),
+            (&UndefinedRule(ref name), &Some(_)) =>
+                write!(f, "rule '{}' undefined in given grammar", name),
+            (&UndefinedRule(ref name), &None) => write!(f, "rule '{}' used outside a grammar", name),
+            (&UnusedRule, &Some(ref r)) => write!(f, "rule '{}' is never used", r),
+            (&DuplicateRule, &Some(ref r)) => write!(f, "rule '{}' is defined more than once", r),
+            (&UndefinedCapture(ref name), _) =>
+                write!(f, "no capture named '{}' to refer back to", name),
+            (p, &None) => write!(f, "{}", p),
+        }
+    }
+}
+
+/// Checks a pattern, and every grammar in it, returning its problems in the
+/// order they're found.
+pub fn verify(ast: &Ast) -> Vec<Diagnostic> {
+    let mut names = vec!();
+    capture_names(ast, &mut names);
+    let mut v = Verifier { diags: vec!(), names: names };
+    v.check(ast, &[], &[], None);
+    v.diags
+}
+
+struct Verifier {
+    diags: Vec<Diagnostic>,
+    // the names of the captures anywhere in the pattern, which
+    // back-references may refer to
+    names: Vec<String>,
+}
+
+impl Verifier {
+    fn report(&mut self, problem: Problem, rule: Option<&Rule>) {
+        self.diags.push(Diagnostic {
+            problem: problem,
+            rule: rule.map(|r| r.name.clone()),
+            span: rule.and_then(|r| r.span.clone()),
+        });
+    }
+
+    // Checks `ast`, a part of `rule`, whose grammar has `rules`.
+    fn check(&mut self, ast: &Ast, rules: &[Rule], nullable: &[bool], rule: Option<&Rule>) {
+        match *ast {
+            Seq(ref es) | Alt(ref es) => {
+                for e in es.iter() {
+                    self.check(e, rules, nullable, rule);
+                }
+            }
+            Rep(ref e, ZeroOne) => self.check(&**e, rules, nullable, rule),
+            Rep(ref e, _) => {
+                if is_nullable(&**e, rules, nullable) {
+                    self.report(EmptyLoop, rule);
+                }
+                self.check(&**e, rules, nullable, rule);
+            }
+            And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) | Subst(_, ref e) |
+            Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) | Num(_, _, ref e) |
+            Function(_, _, ref e) | Fold(_, _, ref e) => self.check(&**e, rules, nullable, rule),
+            NonTerm(ref name) => {
+                if rule_index(rules, name.as_slice()).is_none() {
+                    self.report(UndefinedRule(name.clone()), rule);
+                }
+            }
+            Backref(ref name) => {
+                if !self.names.contains(name) {
+                    self.report(UndefinedCapture(name.clone()), rule);
+                }
+            }
+            Grammar(ref inner) => self.check_grammar(inner.as_slice()),
+            _ => {}
+        }
+    }
+
+    // A grammar's rules see only each other, not the rules of a grammar
+    // around it.
+    fn check_grammar(&mut self, rules: &[Rule]) {
+        if rules.is_empty() {
+            return
+        }
+        for (n, r) in rules.iter().enumerate() {
+            if rule_index(rules, r.name.as_slice()) != Some(n) {
+                self.report(DuplicateRule, Some(r));
+            }
+        }
+
+        let nullable = nullable_rules(rules);
+        for r in rules.iter() {
+            self.check(&r.body, rules, nullable.as_slice(), Some(r));
+        }
+
+        let left: Vec<Vec<uint>> = rules.iter().map(|r| {
+            let mut calls = vec!();
+            left_calls(&r.body, rules, nullable.as_slice(), &mut calls);
+            calls
+        }).collect();
+        for (n, r) in rules.iter().enumerate() {
+            if reaches(left.as_slice(), left[n].as_slice(), n) {
+                self.report(LeftRecursion, Some(r));
+            }
+        }
+
+        let calls: Vec<Vec<uint>> = rules.iter().map(|r| {
+            let mut calls = vec!();
+            all_calls(&r.body, rules, &mut calls);
+            calls
+        }).collect();
+        for (n, r) in rules.iter().enumerate() {
+            let duplicate = rule_index(rules, r.name.as_slice()) != Some(n);
+            if n > 0 && !duplicate && !reaches(calls.as_slice(), calls[0].as_slice(), n) {
+                self.report(UnusedRule, Some(r));
+            }
+        }
+    }
+}
+
+// Left recursion: a rule is left-recursive if it can call itself, directly
+// or through other rules, before consuming any input.  Each cycle of such
+// calls needs one rule that the VM grows a seed for, which stops the
+// recursion; this picks the heads greedily, in the order the rules are
+// defined, so the first rule of `expr <- expr '+' term / term` is its own head.
+pub fn left_recursive(rules: &[Rule]) -> Vec<bool> {
+    let nullable = nullable_rules(rules);
+    let calls: Vec<Vec<uint>> = rules.iter().map(|r| {
+        let mut calls = vec!();
+        left_calls(&r.body, rules, nullable.as_slice(), &mut calls);
+        calls
+    }).collect();
+
+    let mut heads = Vec::from_elem(rules.len(), false);
+    for n in range(0, rules.len()) {
+        // is there a cycle back to n, not through any head?
+        let mut seen = Vec::from_elem(rules.len(), false);
+        let mut todo = calls[n].clone();
+        while !todo.is_empty() {
+            let r = todo.pop().unwrap();
+            if r == n {
+                *heads.get_mut(n) = true;
+                break;
+            }
+            if seen[r] || heads[r] {
+                continue;
+            }
+            *seen.get_mut(r) = true;
+            todo.push_all(calls[r].as_slice());
+        }
+    }
+    heads
+}
+
+// Whether each rule may match a back-reference, itself or through the rules
+// it calls.  What such a rule matches at a position depends on what was
+// captured before it was called there, so it mustn't be memoized.
+pub fn refers_back(rules: &[Rule]) -> Vec<bool> {
+    let calls: Vec<Vec<uint>> = rules.iter().map(|r| {
+        let mut calls = vec!();
+        all_calls(&r.body, rules, &mut calls);
+        calls
+    }).collect();
+    let direct: Vec<uint> = range(0, rules.len()).filter(|&n| has_backref(&rules[n].body)).collect();
+    range(0, rules.len()).map(|n| {
+        direct.iter().any(|&m| m == n || reaches(calls.as_slice(), calls[n].as_slice(), m))
+    }).collect()
+}
+
+// Whether `ast` has a back-reference in it, in nested grammars too.
+fn has_backref(ast: &Ast) -> bool {
+    match *ast {
+        Backref(_) => true,
+        Seq(ref es) | Alt(ref es) => es.iter().any(has_backref),
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => has_backref(&**e),
+        Grammar(ref rules) => rules.iter().any(|r| has_backref(&r.body)),
+        _ => false
+    }
+}
+
+// The highest number of a capture in `ast`, in nested grammars too; 0 if
+// there are none.
+pub fn last_capture(ast: &Ast) -> uint {
+    let num = match *ast {
+        Cap(n, _, _) | Pos(n, _) | Table(n, _) | Group(n, _, _) | Subst(n, _) |
+        RunTime(n, _, _) | Const(n, _) | Arg(n, _) | Format(n, _, _) | Num(n, _, _) |
+        Function(n, _, _) | Fold(n, _, _) => n,
+        _ => 0
+    };
+    let inner = match *ast {
+        Seq(ref es) | Alt(ref es) => es.iter().map(last_capture).max().unwrap_or(0),
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => last_capture(&**e),
+        Grammar(ref rules) => rules.iter().map(|r| last_capture(&r.body)).max().unwrap_or(0),
+        _ => 0
+    };
+    if num > inner { num } else { inner }
+}
+
+// Adds to `names` the name of every named capture in `ast`.
+fn capture_names(ast: &Ast, names: &mut Vec<String>) {
+    match *ast {
+        Cap(_, Some(ref name), _) | Pos(_, Some(ref name)) | Group(_, Some(ref name), _) => {
+            names.push(name.clone());
+        }
+        _ => {}
+    }
+    match *ast {
+        Seq(ref es) | Alt(ref es) => {
+            for e in es.iter() {
+                capture_names(e, names);
+            }
+        }
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => capture_names(&**e, names),
+        Grammar(ref rules) => {
+            for r in rules.iter() {
+                capture_names(&r.body, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Whether rule `to` can be reached from the rules `from`, in the graph of
+// rule calls `calls`.
+fn reaches(calls: &[Vec<uint>], from: &[uint], to: uint) -> bool {
+    let mut seen = Vec::from_elem(calls.len(), false);
+    let mut todo = from.to_vec();
+    while !todo.is_empty() {
+        let r = todo.pop().unwrap();
+        if r == to {
+            return true
+        }
+        if !seen[r] {
+            *seen.get_mut(r) = true;
+            todo.push_all(calls[r].as_slice());
+        }
+    }
+    false
+}
+
+// Whether each rule can match the empty string, found as a fixpoint.
+fn nullable_rules(rules: &[Rule]) -> Vec<bool> {
+    let mut nullable = Vec::from_elem(rules.len(), false);
+    loop {
+        let mut changed = false;
+        for (n, r) in rules.iter().enumerate() {
+            if !nullable[n] && is_nullable(&r.body, rules, nullable.as_slice()) {
+                *nullable.get_mut(n) = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            return nullable
+        }
+    }
+}
+
+fn rule_index(rules: &[Rule], name: &str) -> Option<uint> {
+    rules.iter().position(|r| r.name.as_slice() == name)
+}
+
+// Whether `ast` can succeed without consuming input, given which of the
+// rules of its grammar can.
+fn is_nullable(ast: &Ast, rules: &[Rule], nullable: &[bool]) -> bool {
+    match *ast {
+        Nil => true,
+        Lit(ref s, _) => s.is_empty(),
+        Dot(_) | Cls(..) => false,
+        Seq(ref es) => es.iter().all(|e| is_nullable(e, rules, nullable)),
+        Alt(ref es) => es.iter().any(|e| is_nullable(e, rules, nullable)),
+        Rep(ref e, OneMore) => is_nullable(&**e, rules, nullable),
+        Rep(..) | And(_) | Not(_) | Pos(..) | Backref(_) | Const(..) | Arg(..) => true,
+        Cap(_, _, ref e) | Table(_, ref e) | Subst(_, ref e) | Group(_, _, ref e) |
+        RunTime(_, _, ref e) | Format(_, _, ref e) | Num(_, _, ref e) | Function(_, _, ref e) |
+        Fold(_, _, ref e) => is_nullable(&**e, rules, nullable),
+        Grammar(ref inner) => !inner.is_empty() && nullable_rules(inner.as_slice())[0],
+        // a throw succeeds only by its recovery rule
+        NonTerm(ref name) | Throw(ref name) => match rule_index(rules, name.as_slice()) {
+            Some(n) => nullable[n],
+            None => false
+        },
+        _ => false
+    }
+}
+
+// Adds to `calls` the rules that `ast` may call at the position it starts at.
+fn left_calls(ast: &Ast, rules: &[Rule], nullable: &[bool], calls: &mut Vec<uint>) {
+    match *ast {
+        Seq(ref es) => {
+            for e in es.iter() {
+                left_calls(e, rules, nullable, calls);
+                if !is_nullable(e, rules, nullable) {
+                    break;
+                }
+            }
+        }
+        Alt(ref es) => {
+            for e in es.iter() {
+                left_calls(e, rules, nullable, calls);
+            }
+        }
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => {
+            left_calls(&**e, rules, nullable, calls);
+        }
+        NonTerm(ref name) | Throw(ref name) => {
+            match rule_index(rules, name.as_slice()) {
+                Some(n) => calls.push(n),
+                None => {}
+            }
+        }
+        // a nested grammar's rules are its own; it's checked when compiled
+        _ => {}
+    }
+}
+
+// Adds to `calls` every rule that `ast` may call.
+fn all_calls(ast: &Ast, rules: &[Rule], calls: &mut Vec<uint>) {
+    match *ast {
+        Seq(ref es) | Alt(ref es) => {
+            for e in es.iter() {
+                all_calls(e, rules, calls);
+            }
+        }
+        Rep(ref e, _) | And(ref e) | Not(ref e) | Cap(_, _, ref e) | Table(_, ref e) |
+        Subst(_, ref e) | Group(_, _, ref e) | RunTime(_, _, ref e) | Format(_, _, ref e) |
+        Num(_, _, ref e) | Function(_, _, ref e) | Fold(_, _, ref e) => all_calls(&**e, rules, calls),
+        NonTerm(ref name) | Throw(ref name) => {
+            match rule_index(rules, name.as_slice()) {
+                Some(n) => calls.push(n),
+                None => {}
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+fn problems(src: &str) -> Vec<(Problem, Option<String>)> {
+    use parse::parse;
+    verify(&parse(src).unwrap()).into_iter().map(|d| (d.problem, d.rule)).collect()
+}
+
+#[test]
+fn verify_ok() {
+    use ast::mk_peg_grammar;
+    assert!(verify(&mk_peg_grammar()).is_empty());
+    assert!(problems("'a'* / [b-c]+").is_empty());
+    assert!(problems("S <- 'a' S / ''").is_empty());
+}
+
+#[test]
+fn verify_problems() {
+    fn some(s: &str) -> Option<String> { Some(s.to_string()) }
+    assert_eq!(problems("(''*)*"), vec!((EmptyLoop, None), (EmptyLoop, None)));
+    assert_eq!(problems("S <- ('a'? B)+  B <- &'b'"), vec!((EmptyLoop, some("S"))));
+    assert_eq!(problems("S <- 'a' T"), vec!((UndefinedRule("T".to_string()), some("S"))));
+    assert_eq!(problems("'a' T"), vec!((UndefinedRule("T".to_string()), None)));
+    assert_eq!(problems("S <- 'a'  S <- 'b'"), vec!((DuplicateRule, some("S"))));
+    assert_eq!(problems("S <- A  A <- 'a'  B <- A"), vec!((UnusedRule, some("B"))));
+    assert_eq!(problems("E <- E '+' T / T  T <- [0-9]"), vec!((LeftRecursion, some("E"))));
+    assert_eq!(problems("A <- B 'a' / 'x'  B <- A 'b'"),
+               vec!((LeftRecursion, some("A")), (LeftRecursion, some("B"))));
+    // an inner grammar can't see the rules of an outer one
+    assert_eq!(problems("S <- (A <- B)  B <- 'b'"),
+               vec!((UndefinedRule("B".to_string()), some("A")), (UnusedRule, some("B"))));
+    // back-references, to captures anywhere in the pattern
+    assert!(problems("S <- {:q: 'a' :} B  B <- =q").is_empty());
+    assert_eq!(problems("{:q: 'a' :} =p"), vec!((UndefinedCapture("p".to_string()), None)));
+    // a label's recovery rule is used by its throws; a label needn't have one
+    assert!(problems("S <- 'a' (';' / ^Semi) ^Other  Semi <- ''").is_empty());
+}
+
+#[test]
+fn verify_refers_back() {
+    use parse::parse;
+    use ast::Grammar;
+    let rules = match parse("S <- {:q: 'a' :} A B  A <- B / C  B <- =q  C <- 'c'").unwrap() {
+        Grammar(rules) => rules,
+        _ => unreachable!()
+    };
+    assert_eq!(refers_back(rules.as_slice()), vec!(true, true, true, false));
+}
+
+#[test]
+fn verify_last_capture() {
+    use parse::parse;
+    assert_eq!(last_capture(&parse("'a'").unwrap()), 0);
+    assert_eq!(last_capture(&parse("S <- {'a'} A  A <- ({} {'b'}) -> f").unwrap()), 4);
+}
+
+#[test]
+fn verify_diagnostics() {
+    use parse::parse;
+    let diags = verify(&parse("S <- A\nA <- 'a'\nB <- ('b'?)*").unwrap());
+    assert_eq!(diags.len(), 2);
+    assert!(diags[0].is_error());
+    assert_eq!(format!("{}", diags[0]),
+               "rule 'B' has a loop whose body can match the empty string".to_string());
+    assert_eq!(diags[0].span, Some(Span { start: 16, end: 28 }));
+    assert!(!diags[1].is_error());
+    assert_eq!(format!("{}", diags[1]), "rule 'B' is never used".to_string());
+    assert_eq!(diags[1].span, Some(Span { start: 16, end: 28 }));
+}
@@ -1,22 +1,106 @@
 
+use std::str::CharRange;
+use std::collections::BTreeMap;
 use code::*; // didn't feel like listing them
+use capture::{Csimple, Cposition, Cbackref, Cruntime, Cclose, Crule, Cerror};
+use capture::{RunTime, Outcome, Accept, Reject, CapEnv, CapState, CaptureError, ValueFn, FnParam};
+use capture::{get_captures, get_tree, get_values};
+use capture;
+use capture::Node;
+use cst;
+use cst::Cst;
+use error::{MatchError, ExpectChar, ExpectClass, ExpectAny, ExpectRule};
+use value::Value;
+#[cfg(test)]
+use capture::Cgroup;
+use compile::{Program, Error, err};
+#[cfg(test)]
+use ast::FLAG_NORMAL;
 
 //use capture::Capture;
 
+#[deriving(PartialEq,Show)]
 struct CapLevel(pub uint);
+#[deriving(PartialEq,Show)]
 struct StackIdx(pub uint);
 
 
+#[deriving(PartialEq,Show)]
 enum StackEntry {
-  ReturnTo(Option<CodeIdx>),
-  AlternateTo(CodeIdx, CharNum, CapLevel)
+  ReturnTo(CodeIdx),
+  AlternateTo(CodeIdx, BytePos, CapLevel),
+  // a call to a memoized rule: return-pos, the rule's first instruction,
+  // and the subject-pos and capture-level at the call.  Returning from it,
+  // or failing past it, records the rule's result in the memo table.
+  MemoCall(CodeIdx, CodeIdx, BytePos, CapLevel),
+  // a call to a left-recursive rule, which is growing a seed: fields as
+  // for MemoCall.  Returning from it with a longer match than the seed's
+  // makes that the seed, and runs the rule again.
+  GrowCall(CodeIdx, CodeIdx, BytePos, CapLevel)
+}
+
+// Left recursion, by seed growing (Medeiros et al., "Left recursion in
+// parsing expression grammars"): a left-recursive rule called at a position
+// starts with a seed of Fail there, so its recursive call fails and one of
+// its other alternatives matches; that match becomes the seed, and the rule
+// is run again, its recursive call now matching the seed.  This goes on for
+// as long as the match gets longer.
+struct Seed {
+  // the longest match so far, or Fail (None), and its captures
+  result: Option<(BytePos, Vec<Capture>)>,
+  // still being grown by a GrowCall on the stack
+  growing: bool
+}
+
+// Packrat memoization: the result of calling a rule at a position, either
+// Fail (None) or the position it matched up to and the captures it made.
+// Keyed by (position, rule), so that when the table is full the results at
+// the lowest positions -- the ones a match is least likely to backtrack
+// to -- are the first evicted.
+struct Memo {
+  // which instructions begin a memoized rule; empty if memoization is off
+  rules: Vec<bool>,
+  // the most results to keep
+  capacity: uint,
+  results: BTreeMap<(uint, uint), Option<(BytePos, Vec<Capture>)>>
+}
+impl Memo {
+  fn off() -> Memo {
+    Memo { rules: vec!(), capacity: 0, results: BTreeMap::new() }
+  }
+
+  #[inline]
+  fn is_memoized(&self, rule: uint) -> bool {
+    rule < self.rules.len() && self.rules[rule]
+  }
+
+  fn insert(&mut self, BytePos(ip): BytePos, CodeIdx(rule): CodeIdx,
+            result: Option<(BytePos, Vec<Capture>)>) {
+    if self.capacity == 0 {
+      return
+    }
+    // overwriting a result makes no room, so needs none
+    if self.results.len() >= self.capacity && !self.results.contains_key(&(ip, rule)) {
+      let lowest = *self.results.keys().next().unwrap();
+      self.results.remove(&lowest);
+    }
+    self.results.insert((ip, rule), result);
+  }
+
+  // drops the results at or after position `ip`
+  fn forget_from(&mut self, ip: uint) {
+    let stale: Vec<(uint, uint)> =
+      self.results.keys().filter(|&&(pos, _)| pos >= ip).map(|&k| k).collect();
+    for k in stale.iter() {
+      self.results.remove(k);
+    }
+  }
 }
 struct State {
   // current instruction index, or FAIL (None)
   p: Option<CodeIdx>,
-  // current subject position (char index in subject string)
-  // (NOT byte index; this represents number of unicode chars matched so far)
-  i: CharNum,
+  // current subject position (byte offset into the subject string)
+  i: BytePos,
   // stack-entry: either (uint) return-pos, or (next-pos, subject-pos, cap-list)
   // this StackIdx is actually the index of first available slot on stack;
   // that is, the number of entries in the stack.
@@ -26,19 +110,21 @@ struct State {
   c: CapLevel
 }
 
+#[deriving(PartialEq,Show)]
 struct VmState(
   // current instruction index, or FAIL (None)
   Option<CodeIdx>,
-  // current subject position (char index in subject string) (NOT byte index)
-  CharNum,
+  // current subject position: a byte offset into the subject string, always
+  // on a char boundary
+  BytePos,
   // stack-entry: either (uint) return-pos, or (next-pos, subject-pos, cap-list).
   // this index is redundant if we're using a growable stack vec:
   //   StackIdx should always == TOS (stack.len()-1); and
   //   stack should never be empty when machine is running:
-  //   (bottom of stack should be an entry with ReturnTo(None)).
-  //   (this comment may not actually be true)
+  //   the bottom of stack is a choice whose alternate is IGiveup, so
+  //   that failing all the way back to it ends the match.
   StackIdx,
-  // Capture pointer? -- captures are a tuple of (CodeIdx,CharNum); and
+  // Capture pointer? -- captures are a tuple of (BytePos,CodeIdx); and
   // 'captures' is a Vec<Capture>; so CapLevel should really be CapCount and
   // should always equal captures.len().  I think.  There's some weird stuff
   // in the Lua implementation about "dynamic" captures, and also some stuff
@@ -54,26 +140,270 @@ struct VmState(
 /// Abstractly the machine is a pure state-machine operating on
 /// 4 registers:
 ///   p: the next instruction (opcode) to execute.  Program counter.
-///   i: current character in the input stream.
+///   i: current position (byte offset) in the input string.
 ///   e: the call-stack; the 'entry' at TOS indicates where to return to
 ///   c: capture-stack, tracking positions where rules match.
 /// Operational semantics of the VM is as in tables below: given 4 registers
 /// and a current opcode, return the updated register values.
-struct Vm {
+///
+/// The input isn't copied into the Vm; it's handed to each step, and matched
+/// as UTF-8 bytes, decoding a char only where an instruction needs one.
+pub struct Vm {
+  // the compiled program, followed by the IGiveup instruction
   program: Vec<Opcode>,
-  text: Vec<char>,
+  sets: Vec<Charset>,
+  strings: Vec<String>,
+  rules: Vec<(String, uint)>,
   stack: Vec<StackEntry>,
-  captures: Vec<Capture>
+  captures: Vec<Capture>,
+  memo: Memo,
+  // which instructions begin a left-recursive rule
+  left_recursive: Vec<bool>,
+  // keyed by (position, rule), like the memo table
+  seeds: BTreeMap<(uint, uint), Seed>,
+  // the captures each IBackref may refer to, by its key
+  refs: Vec<Vec<uint>>,
+  // which instructions begin a rule that may match a back-reference
+  refers_back: Vec<bool>,
+  // the functions of match-time captures, by the key of their
+  // ICloseRunTimes, with their names
+  functions: Vec<(String, Option<MatchFn>)>,
+  // the label of each IThrow, by its key, and where its recovery rule is
+  labels: Vec<(String, Option<uint>)>,
+  // what capture values are made from: names, params, the values
+  // match-time functions produced, and value functions
+  env: CapEnv,
+  // the index in rules of the rule each instruction begins, if any
+  rule_at: Vec<Option<uint>>,
+  // CST mode: record each rule's match in the capture list
+  cst: bool,
+  // the farthest position the match has failed at, and what was expected
+  // there
+  farthest: uint,
+  expected: Vec<Want>,
+  // where the match was broken off, and why, if a match-time function
+  // wasn't given or moved it somewhere it can't go
+  fault: Option<(uint, String)>,
+  // the rules being called, innermost last: each one's index in rules,
+  // where it was called, the farthest failure and the number of expected
+  // items then, and whether it was called in tail position
+  calls: Vec<(Option<uint>, uint, uint, uint, bool)>,
+  // the rule that what was expected at the farthest failure was expected
+  // in, if any, and where it was called
+  farthest_rule: Option<uint>,
+  farthest_call: uint,
+  // the keys in sets of the first and follow sets of each rule
+  first: Vec<uint>,
+  follow: Vec<uint>,
+  // error recovery: the text it resynchronizes before, if not the first
+  // and follow sets, and the sorted spans of the input it has skipped
+  sync: Vec<String>,
+  skips: Vec<(uint, uint)>,
+  // the literal every match begins with, or else the key in sets of the
+  // chars a match can begin with, if it can't be empty, and the bytes they
+  // can begin with; and where a search may find a match
+  prefix: String,
+  start: Option<uint>,
+  start_bytes: Vec<bool>,
+  anchor: Anchor,
+  // what each test instruction expects if it jumps, by its heads key
+  heads: Vec<Vec<Want>>,
+  // the number of entries pushed on the stack since the last match began
+  pushes: uint,
 }
+
+/// Where a search (see `Vm::find`) may find a match.
+#[deriving(PartialEq,Show,Clone)]
+pub enum Anchor {
+  /// Only where the search starts.
+  Anchored,
+  /// Only where the search starts, and only if it reaches the end of the
+  /// input, as if the pattern were followed by `!.`.
+  Whole,
+  /// Anywhere from where the search starts on: the first place it matches.
+  Unanchored,
+}
+
+/// A function called during the match, for a match-time capture.
+pub type MatchFn = Box<FnMut(&RunTime) -> Outcome + 'static>;
+
 #[allow(unused_mut)]
 impl Vm {
-  fn new(program: Vec<Opcode>) -> Vm {
+  pub fn new(program: Program) -> Vm {
+    let mut insts = program.insts;
+    insts.push(IGiveup);
+    let mut left_recursive = Vec::from_elem(insts.len(), false);
+    for &pos in program.left_recursive.iter() {
+      *left_recursive.get_mut(pos) = true;
+    }
+    let mut refers_back = Vec::from_elem(insts.len(), false);
+    for &pos in program.refers_back.iter() {
+      *refers_back.get_mut(pos) = true;
+    }
+    let start_bytes = match program.start {
+      Some(key) => program.sets[key].first_bytes(),
+      None => vec!()
+    };
+    let mut rule_at = Vec::from_elem(insts.len(), None);
+    for (k, &(_, pos)) in program.rules.iter().enumerate() {
+      *rule_at.get_mut(pos) = Some(k);
+    }
     Vm {
-      program: program,
-      text: vec!(),
+      program: insts,
+      sets: program.sets,
+      strings: program.strings,
+      rules: program.rules,
       stack: vec!(),
-      captures: vec!()
+      captures: vec!(),
+      memo: Memo::off(),
+      left_recursive: left_recursive,
+      seeds: BTreeMap::new(),
+      refs: program.refs,
+      refers_back: refers_back,
+      functions: program.functions.into_iter().map(|name| (name, None)).collect(),
+      labels: program.labels,
+      env: CapEnv { names: program.names, params: program.params, dynamic: vec!(), functions: vec!() },
+      rule_at: rule_at,
+      cst: false,
+      farthest: 0,
+      expected: vec!(),
+      fault: None,
+      calls: vec!(),
+      farthest_rule: None,
+      farthest_call: 0,
+      first: program.first,
+      follow: program.follow,
+      sync: vec!(),
+      skips: vec!(),
+      prefix: program.prefix,
+      start: program.start,
+      start_bytes: start_bytes,
+      anchor: Unanchored,
+      heads: program.heads,
+      pushes: 0,
+    }
+  }
+
+  /// Gives the function that match-time captures `p => name` call.  It's
+  /// called each time p matches, during the match, and can let the match
+  /// go on, from where p's match ended or further on, or make p fail.
+  /// Each function a grammar names must be given before it's matched:
+  /// until it is, matches fail, and `match_error` says which is missing.
+  /// A function that moves the match back, or into the middle of a char,
+  /// breaks the match off, which fails as well.
+  pub fn on_match(&mut self, name: &str, f: MatchFn) -> Result<(), Error> {
+    match self.functions.iter().position(|&(ref n, _)| n.as_slice() == name) {
+      Some(k) => {
+        *self.functions.get_mut(k) = (name.to_string(), Some(f));
+        Ok(())
+      }
+      None => err(format!("no match-time capture calls a function named '{}'", name))
+    }
+  }
+
+  /// The capture list left by the last successful match, or by one that
+  /// ended on a labeled failure.
+  pub fn captures(&self) -> &[Capture] {
+    self.captures.as_slice()
+  }
+
+  /// The number of entries the last match pushed on the backtracking
+  /// stack: its choices and calls, and the bottom entry.
+  pub fn stack_pushes(&self) -> uint {
+    self.pushes
+  }
+
+  /// Gives the function that function and fold captures `p -> name` and
+  /// `p ~> name` call on the values of the captures inside p, as captures
+  /// are made from the capture list of a match.  As with `on_match`, each
+  /// function a grammar names must be given before it's matched.
+  pub fn function(&mut self, name: &str, f: ValueFn) -> Result<(), Error> {
+    if !self.env.params.iter().any(|p| *p == FnParam(name.to_string())) {
+      return err(format!("no capture calls a function named '{}'", name))
+    }
+    match self.env.functions.iter().position(|&(ref n, _)| n.as_slice() == name) {
+      Some(k) => { *self.env.functions.get_mut(k) = (name.to_string(), f); }
+      None => self.env.functions.push((name.to_string(), f))
     }
+    Ok(())
+  }
+
+  /// The captures of the last successful match of `input`, in the order
+  /// they were opened.  `args` are the extra arguments of the match, for
+  /// argument captures.
+  pub fn get_captures<'t>(&mut self, input: &'t str, args: &[Value<'t>]) -> Vec<capture::Capture<'t>> {
+    let mut cs = CapState { input: input, env: &mut self.env, args: args };
+    get_captures(&mut cs, self.captures.as_slice())
+  }
+
+  /// The tree of the captures of the last successful match of `input`,
+  /// which ended at byte offset `end`; or of the captures made before the
+  /// labeled failure that ended a match, at the failure's position.
+  pub fn get_tree<'t>(&mut self, input: &'t str, end: uint, args: &[Value<'t>]) -> Node<'t> {
+    let mut cs = CapState { input: input, env: &mut self.env, args: args };
+    get_tree(&mut cs, end, self.captures.as_slice())
+  }
+
+  /// The values of the outermost captures of the last successful match of
+  /// `input`, or why one of its captures has none.
+  pub fn get_values<'t>(&mut self, input: &'t str, args: &[Value<'t>])
+                        -> Result<Vec<Value<'t>>, CaptureError> {
+    let mut cs = CapState { input: input, env: &mut self.env, args: args };
+    get_values(&mut cs, self.captures.as_slice())
+  }
+
+  /// Turns CST mode on or off.  In CST mode every call of a rule records
+  /// the rule and the span it matched in the capture list, as a node that
+  /// `get_cst` builds a concrete syntax tree of.  Like captures, the nodes
+  /// of alternatives that fail are dropped.
+  pub fn record_rules(&mut self, on: bool) {
+    self.cst = on;
+  }
+
+  /// The concrete syntax tree of the last successful match of `input`,
+  /// made in CST mode, which ended at byte offset `end`.  The text that
+  /// rules named in `trivia` matched is kept as trivia.
+  pub fn get_cst<'t>(&self, input: &'t str, end: uint, trivia: &[&str]) -> Cst<'t> {
+    cst::build(input, end, self.captures.as_slice(), self.rules.as_slice(), trivia, &[])
+  }
+
+  /// Turns on packrat mode for the named rules: the result of calling one
+  /// of them at a given position is remembered, so it's never matched there
+  /// twice, and backtracking can't make the match take exponential time.
+  /// At most `capacity` results are kept; the ones at the lowest positions
+  /// in the input are forgotten first.  A `capacity` of 0 turns it off.
+  /// Names are looked up in every grammar of the program, nested ones too.
+  /// A rule that may match a back-reference can't be memoized: what it
+  /// matches depends on more than where it's called.
+  pub fn memoize(&mut self, rules: &[&str], capacity: uint) -> Result<(), Error> {
+    let mut memoized = Vec::from_elem(self.program.len(), false);
+    for &name in rules.iter() {
+      let mut found = false;
+      for &(ref rule, pos) in self.rules.iter() {
+        if rule.as_slice() == name {
+          if self.refers_back[pos] {
+            return err(format!("rule '{}' refers back to a capture, so can't be memoized", name))
+          }
+          *memoized.get_mut(pos) = true;
+          found = true;
+        }
+      }
+      if !found {
+        return err(format!("no rule named '{}' to memoize", name))
+      }
+    }
+    self.memo = Memo { rules: memoized, capacity: capacity, results: BTreeMap::new() };
+    Ok(())
+  }
+
+  /// Packrat mode for every rule of the program that can be memoized;
+  /// see `memoize`.
+  pub fn memoize_all(&mut self, capacity: uint) {
+    let mut memoized = Vec::from_elem(self.program.len(), false);
+    for &(_, pos) in self.rules.iter() {
+      *memoized.get_mut(pos) = !self.refers_back[pos];
+    }
+    self.memo = Memo { rules: memoized, capacity: capacity, results: BTreeMap::new() };
   }
 
   //Figure 2. basic instructions for the parsing machine:
@@ -113,40 +443,149 @@ impl Vm {
   //   p is stored pc, i is stored S, c is stored capturecount.
 
 
-  fn step(&mut self, VmState(p,i,e,c): VmState) -> VmState {
+  fn step(&mut self, text: &str, VmState(p,i,e,c): VmState) -> VmState {
 
     match (p,i,e,c) {
 
-      //(None, _, ReturnTo(CodeIdx(pc)), _) => VmState(Fail, i, e,c),
-      //(None, _, AlternateTo(CodeIdx(pc), CharNum(i1), CapLevel(c1)), _) => VmState(p, i1, e,c),
+      //  Fail,i,p:e,c  any               ⇒ Fail,i,e,c
+      //  Fail,i0,(p,i1,c1):e,c0  any     ⇒ p,i1,e,c1
       (None,_,StackIdx(sp),_) if sp > 0 => { // if sp < 1, stack was empty and we're hosed
         let tos = self.stack.pop();
         let sp = sp - 1;
-        assert!(sp == self.stack.len() && sp > 0);  // can't run with an empty stack,
-                                                    // or machine won't know where to return
+        assert!(sp == self.stack.len());
         match tos {
-          Some(ReturnTo(_))
-            => return VmState(None, i, StackIdx(sp), c),
-          Some(AlternateTo(CodeIdx(pc), CharNum(i1), CapLevel(c1)))
-            => return VmState(p, CharNum(i1), StackIdx(sp), CapLevel(c1)),
+          Some(ReturnTo(_)) => {
+            self.rule_failed();
+            return VmState(None, i, StackIdx(sp), c)
+          }
+          Some(MemoCall(_, rule, start, _)) => {
+            self.memo.insert(start, rule, None);
+            self.rule_failed();
+            return VmState(None, i, StackIdx(sp), c)
+          }
+          // the rule failed to match on top of its seed: the seed is
+          // as long as it gets, and is the rule's match
+          Some(GrowCall(ret, rule, start, c0)) => {
+            return match self.grown(ret, rule, start, c0, sp) {
+              Some(state) => {
+                self.calls.pop();
+                self.close_rule(state)
+              }
+              None => {
+                self.rule_failed();
+                VmState(None, i, StackIdx(sp), c)
+              }
+            }
+          }
+          Some(AlternateTo(dest, i1, CapLevel(c1))) => {
+            // forget whatever was captured since the choice was made
+            self.captures.truncate(c1);
+            return VmState(Some(dest), i1, StackIdx(sp), CapLevel(c1))
+          }
           None
             => unreachable!() //fail!("popped an invalid entry from vm stack!")
         }
       }
       (None,_,_,_) /*sp == 0*/ => fail!("vm stack shouldn't have been empty!"),
 
-      (Some(CodeIdx(pc)), CharNum(ip), StackIdx(sp), CapLevel(cap)) => {
+      (Some(CodeIdx(pc)), BytePos(ip), StackIdx(sp), CapLevel(cap)) => {
+        // text that error recovery skipped isn't matched
+        let (ip, i) = if self.skips.is_empty() { (ip, i) } else {
+          let ip = self.skip(ip);
+          (ip, BytePos(ip))
+        };
         let op = self.program[pc];
         match op {
 
           //  p,i,e,c       Char x,S[i] = x   ⇒ p+1,i+1,e,c
-          IChar(ch, flags) if ch == self.text[ip] => {
-            print!("IChar {} ...\n", ch);
-            return VmState(Some(CodeIdx(pc+1)),CharNum(ip+1),e,c)
-          }
           //  p,i,e,c       Char x,S[i] != x  ⇒ Fail,i,e,c
-          IChar(ch, flags) if ch != self.text[ip] => {
-            return VmState(None,i,e,c)
+          IChar(ch, flags) => {
+            match char_at(text, ip) {
+              Some((x, next)) if x == ch
+                => return VmState(Some(CodeIdx(pc+1)),BytePos(next),e,c),
+              _ => {
+                self.expect(ip, WChar(ch));
+                return VmState(None,i,e,c)
+              }
+            }
+          }
+          // as that many Chars, failing where the first to fail would
+          IString(key) => {
+            match self.match_string(text, ip, key) {
+              Ok(next) => return VmState(Some(CodeIdx(pc+1)),BytePos(next),e,c),
+              Err((at, ch)) => {
+                self.expect(at, WChar(ch));
+                return VmState(None,BytePos(at),e,c)
+              }
+            }
+          }
+          //  p,i,e,c       Charset X,S[i] ∈ X  ⇒ p+1,i+1,e,c
+          //  p,i,e,c       Charset X,S[i] ∉ X  ⇒ Fail,i,e,c
+          ISet(set) => {
+            match char_at(text, ip) {
+              Some((x, next)) if self.sets[set].contains(x)
+                => return VmState(Some(CodeIdx(pc+1)),BytePos(next),e,c),
+              _ => {
+                self.expect(ip, WSet(set));
+                return VmState(None,i,e,c)
+              }
+            }
+          }
+          // the tests are as Any, Char and Charset, but don't consume,
+          // and jump instead of failing, expecting what the pattern they
+          // pass over would have
+          ITestAny(offset, head) => {
+            match char_at(text, ip) {
+              Some(_) => return VmState(Some(CodeIdx(pc+1)),i,e,c),
+              None => {
+                self.expect_heads(ip, head);
+                return VmState(Some(CodeIdx((pc as int + offset) as uint)),i,e,c)
+              }
+            }
+          }
+          ITestChar(ch, offset, head) => {
+            match char_at(text, ip) {
+              Some((x, _)) if x == ch
+                => return VmState(Some(CodeIdx(pc+1)),i,e,c),
+              _ => {
+                self.expect_heads(ip, head);
+                return VmState(Some(CodeIdx((pc as int + offset) as uint)),i,e,c)
+              }
+            }
+          }
+          ITestSet(set, offset, head) => {
+            match char_at(text, ip) {
+              Some((x, _)) if self.sets[set].contains(x)
+                => return VmState(Some(CodeIdx(pc+1)),i,e,c),
+              _ => {
+                self.expect_heads(ip, head);
+                return VmState(Some(CodeIdx((pc as int + offset) as uint)),i,e,c)
+              }
+            }
+          }
+          //  p,i,e,c       Span X,S[i] ∈ X     ⇒ p,i+1,e,c
+          //  p,i,e,c       Span X,S[i] ∉ X     ⇒ p+1,i,e,c
+          ISpan(set) => {
+            let mut ip = ip;
+            loop {
+              match char_at(text, ip) {
+                Some((x, next)) if self.sets[set].contains(x) => ip = self.skip(next),
+                _ => break
+              }
+            }
+            return VmState(Some(CodeIdx(pc+1)),BytePos(ip),e,c)
+          }
+          //  p,i,e,c       Any n,i+n ≤ |S|   ⇒ p+1,i+n,e,c
+          //  p,i,e,c       Any n,i+n > |S|   ⇒ Fail,i,e,c
+          // (n is always 1 here; IAny's operand is flags)
+          IAny(flags) => {
+            match char_at(text, ip) {
+              Some((_, next)) => return VmState(Some(CodeIdx(pc+1)), BytePos(next), e,c),
+              None => {
+                self.expect(ip, WAny);
+                return VmState(None,i,e,c)
+              }
+            }
           }
           //  p,i,e,c       Jump l            ⇒ p+l,i,e,c
           IJmp(offset) => {
@@ -159,6 +598,7 @@ impl Vm {
             let dest2 = (pc as int + offset) as uint;
             let e2 = AlternateTo(CodeIdx(dest2), i, c);
             self.stack.push(e2);
+            self.pushes += 1;
             let sp = sp + 1;
             assert!(sp == self.stack.len());
             assert!(dest2 < self.program.len());
@@ -166,11 +606,16 @@ impl Vm {
           }
           //  p,i,e,ci      Call l            ⇒ p+l,i,(p+1):e,c
           ICall(offset) => {
-            let e2 = ReturnTo(Some(CodeIdx((pc as int + offset) as uint)));
-            self.stack.push(e2);
-            let sp = sp + 1;
-            assert!(sp == self.stack.len());
-            return VmState(Some(CodeIdx(pc+1)),i,StackIdx(sp),c)
+            let dest = (pc as int + offset) as uint;
+            assert!(dest < self.program.len());
+            return self.call(pc + 1, dest, i, e, c)
+          }
+          // a call just before a Return: the rule returns through the
+          // caller's frame, rather than to the Return
+          ITailCall(offset) => {
+            let dest = (pc as int + offset) as uint;
+            assert!(dest < self.program.len());
+            return self.tail_call(pc + 1, dest, i, e, c)
           }
           //  p0,i,p1:e c   Return            ⇒ p1,i,e,c
           IRet => {
@@ -178,8 +623,34 @@ impl Vm {
             let sp = sp - 1;
             assert!(sp == self.stack.len() && sp > 0);
             match tos {
-              Some(ReturnTo(dest))
-                => return VmState(dest, i, StackIdx(sp), c),
+              Some(ReturnTo(dest)) => {
+                return self.returned(VmState(Some(dest), i, StackIdx(sp), c))
+              }
+              Some(MemoCall(dest, rule, start, CapLevel(c0))) => {
+                self.calls.pop();
+                let caps = self.captures.slice_from(c0).to_vec();
+                self.memo.insert(start, rule, Some((i, caps)));
+                return self.close_rule(VmState(Some(dest), i, StackIdx(sp), c))
+              }
+              Some(GrowCall(dest, CodeIdx(rule), BytePos(start), CapLevel(c0))) => {
+                let longer = match self.seeds.get(&(start, rule)).unwrap().result {
+                  Some((BytePos(end), _)) => ip > end,
+                  None => true
+                };
+                if !longer {
+                  self.calls.pop();
+                  let state = self.grown(dest, CodeIdx(rule), BytePos(start), CapLevel(c0), sp).unwrap();
+                  return self.close_rule(state)
+                }
+                // a longer match is the new seed; run the rule again on it
+                let caps = self.captures.slice_from(c0).to_vec();
+                self.seeds.get_mut(&(start, rule)).unwrap().result = Some((i, caps));
+                self.forget_grown_on(start, rule);
+                self.captures.truncate(c0);
+                self.stack.push(GrowCall(dest, CodeIdx(rule), BytePos(start), CapLevel(c0)));
+                self.pushes += 1;
+                return VmState(Some(CodeIdx(rule)), BytePos(start), StackIdx(sp + 1), CapLevel(c0))
+              }
               _ => unreachable!() //fail!("popped an invalid entry from vm stack!")
             }
           }
@@ -191,21 +662,145 @@ impl Vm {
             assert!(sp == self.stack.len() && sp > 0); // can't run with an empty stack
             return VmState(Some(CodeIdx(dest)), i, StackIdx(sp), c)
           }
+          //  p0,i0,(p1,i1,c1):e,c0  PartialCommit l  ⇒ p0+l,i0,(p1,i0,c0):e,c0
+          IPartialCommit(offset) => {
+            let dest = (pc as int + offset) as uint;
+            match self.stack.pop() {
+              Some(AlternateTo(alt, _, _)) => self.stack.push(AlternateTo(alt, i, c)),
+              _ => unreachable!() //fail!("PartialCommit without a choice on the stack!")
+            }
+            return VmState(Some(CodeIdx(dest)), i, e, c)
+          }
+          //  p0,i0,(p1,i1,c1):e,c0  BackCommit l     ⇒ p0+l,i1,e,c1
+          IBackCommit(offset) => {
+            let dest = (pc as int + offset) as uint;
+            let tos = self.stack.pop();
+            let sp = sp - 1;
+            assert!(sp == self.stack.len() && sp > 0);
+            match tos {
+              Some(AlternateTo(_, i1, CapLevel(c1))) => {
+                self.captures.truncate(c1);
+                return VmState(Some(CodeIdx(dest)), i1, StackIdx(sp), CapLevel(c1))
+              }
+              _ => unreachable!() //fail!("BackCommit without a choice on the stack!")
+            }
+          }
+          //  p,i,h:e,c     FailTwice         ⇒ Fail,i,e,c
+          IFailTwice => {
+            let _tos = self.stack.pop();
+            let sp = sp - 1;
+            assert!(sp == self.stack.len() && sp > 0);
+            return VmState(None, i, StackIdx(sp), c)
+          }
+          //  p,i,e,c       Fail              ⇒ Fail,i,e,c
+          // (then the stack is popped, one entry per step, by the
+          // Fail rows at the top of this match)
+          IFail => {
+            return VmState(None,i,e,c)
+          }
           //  p,i,e,c       Capture k         ⇒ p+1,i,e,(i,p):c
-          IFullCapture(k) => {
-            self.captures.push(Capture(CharNum(ip),CodeIdx(pc)));
+          // (a capture of the last n bytes; LPeg's, rather than the paper's)
+          IFullCapture(kind, key, n) => {
+            self.captures.push(Capture::full(kind, key, BytePos(ip - n), n));
+            let cap = cap + 1;
+            assert!(cap == self.captures.len());
+            return VmState(Some(CodeIdx(pc+1)), i, e, CapLevel(cap))
+          }
+          // a capture whose end isn't known yet; the next ICloseCapture that
+          // isn't closing a capture opened after it, ends it
+          IOpenCapture(kind, key) => {
+            self.captures.push(Capture::open(kind, key, i));
             let cap = cap + 1;
             assert!(cap == self.captures.len());
             return VmState(Some(CodeIdx(pc+1)), i, e, CapLevel(cap))
           }
-          IAny(count) => {
-            let next_ip = ip + count as uint;
-            if next_ip <= self.text.len() {
-              return VmState(Some(CodeIdx(pc+1)), CharNum(next_ip), e,c)
+          ICloseCapture => {
+            self.captures.push(Capture::close(i));
+            let cap = cap + 1;
+            assert!(cap == self.captures.len());
+            return VmState(Some(CodeIdx(pc+1)), i, e, CapLevel(cap))
+          }
+          // the text of the capture referred to, again; it's recorded as a
+          // capture too, with the number of the one it repeats
+          IBackref(key) => {
+            let (key, start, end) = match self.referent(key) {
+              Some(r) => r,
+              None => return VmState(None,i,e,c)
+            };
+            let len = end - start;
+            let bytes = text.as_bytes();
+            if ip + len > bytes.len() || bytes.slice(ip, ip + len) != bytes.slice(start, end) {
+              return VmState(None,i,e,c)
+            }
+            self.captures.push(Capture::full(Cbackref, key, i, len));
+            let cap = cap + 1;
+            assert!(cap == self.captures.len());
+            return VmState(Some(CodeIdx(pc+1)), BytePos(ip + len), e, CapLevel(cap))
+          }
+          // the end of a match-time capture: call its function on what the
+          // pattern matched, and the captures it made, which are replaced
+          // by the function's values.  The match's extra arguments aren't
+          // known till it's over, so there are none.
+          ICloseRunTime(f) => {
+            let k = self.open_capture();
+            let (num, start) = match self.captures[k] {
+              Capture { key, pos: BytePos(start), .. } => (key, start)
+            };
+            let captures = {
+              let mut cs = CapState { input: text, env: &mut self.env, args: &[] };
+              get_captures(&mut cs, self.captures.slice_from(k + 1))
+            };
+            let m = RunTime {
+              input: text,
+              start: start,
+              end: ip,
+              text: text.slice(start, ip),
+              captures: captures,
+            };
+            // every function is given before the match begins
+            let outcome = match *self.functions.get_mut(f) {
+              (_, Some(ref mut f)) => (*f)(&m),
+              (_, None) => unreachable!()
+            };
+            match outcome {
+              Reject => return VmState(None,i,e,c),
+              Accept(pos, values) => {
+                // no choice can catch a function gone wrong: the match is
+                // broken off, as if it failed
+                if pos < ip || pos > text.len() || !text.is_char_boundary(pos) {
+                  let (ref name, _) = self.functions[f];
+                  let why = format!("function '{}' of a match-time capture moved the match to {}, \
+                                     not a char at or after {}", name, pos, ip);
+                  self.fault = Some((ip, why));
+                  self.captures.clear();
+                  self.stack.clear();
+                  return VmState(None, i, StackIdx(0), CapLevel(0))
+                }
+                self.captures.truncate(k);
+                for v in values.into_iter() {
+                  self.captures.push(Capture::full(Cruntime, self.env.dynamic.len(),
+                                                   BytePos(start), pos - start));
+                  self.env.dynamic.push((num, v));
+                }
+                return VmState(Some(CodeIdx(pc+1)), BytePos(pos), e, CapLevel(self.captures.len()))
+              }
             }
-            else {
-              // goto fail
-              //return VmState(None, i,e,c)
+          }
+          // a labeled failure, which no choice catches.  It's recorded in
+          // the capture list, and the label's recovery rule, if it has one,
+          // is called, as by ICall; if it hasn't, the match ends here,
+          // with the captures still open closed, so that what was captured
+          // up to the error can be read
+          IThrow(key) => {
+            self.captures.push(Capture::full(Cerror, key, i, 0));
+            let c = CapLevel(cap + 1);
+            match self.labels[key] {
+              (_, Some(dest)) => return self.call(pc + 1, dest, i, e, c),
+              (_, None) => {
+                self.close_all(i);
+                self.stack.clear();
+                return VmState(None, i, StackIdx(0), CapLevel(self.captures.len()))
+              }
             }
           }
           IEnd => {
@@ -214,8 +809,9 @@ impl Vm {
             // have the char-pos pointing to where we are now in the
             // input, so can verify that it's all been processed.
 
-            // IEnd should be the last instruction in the program;
-            // to execute it just walk on.  outer runner will see
+            // IEnd is the last instruction of the compiled program;
+            // to execute it, jump past the end of the program (and
+            // past the IGiveup that follows it).  outer runner will see
             // that we've got past the program without failing,
             // and will therefore extract any matches or whatever.
             // Maybe by, if there are captures, return them; if not,
@@ -223,69 +819,50 @@ impl Vm {
             // input that the parse completed.  Presumably this would
             // normally be enforced to match the input-length; but
             // outer caller may choose different semantics if appropriate.
-            return VmState(Some(CodeIdx(pc+1)),i,e,c)
+            return VmState(Some(CodeIdx(self.program.len())),i,e,c)
           }
-          IFail => {
-            // FIXME: fail needs to pop stack and clear relevant captures
-            //return VmState(None,i,e,c)
-          }
-          _ => {
-            return VmState(p,i,e,c)
+          // reached by failing back to the bottom of the stack: no
+          // alternatives are left, so the match fails.
+          IGiveup => {
+            assert!(sp == 0);
+            return VmState(None,i,e,c)
           }
+          IOpenCall(_) => fail!("BUG: IOpenCall at {} was not linked to its rule", pc),
         }
       }
     }
-
-    // fall out of match to fail
-
-    //case IFail:
-    /* pattern failed: try to backtrack */
-    //do {  /* remove pending calls */
-    //  assert(stack > getstackbase(L, ptop));
-    //  s = (--stack)->s;
-    //} while (s == NULL);
-    //if (ndyncap > 0)  /* is there matchtime captures? */
-    //  ndyncap -= removedyncap(L, capture, stack->caplevel, captop);
-    //captop = stack->caplevel;
-    //p = stack->p;
-    //continue;
-    let sp = match e {
-      StackIdx(sp) => sp
-    };
-    'fail: loop {
-      assert!(sp == self.stack.len() && sp > 0);
-      let (tos, sp) = (self.stack.pop(), sp-1);
-      match tos {
-        Some(AlternateTo(dest, CharNum(cn1), CapLevel(cl1)))
-          => {
-            // TODO: what's all that dyncap stuff?  deal with it.
-            return VmState(Some(dest), CharNum(cn1), StackIdx(sp), CapLevel(cl1))
-          }
-        _ => { continue 'fail; }
-      };
-    }
   }
 
-  /// match a string input, and return number of characters (not bytes) matched.
+  /// match a string input, and return the matched prefix of it: a slice of
+  /// `input`, not a copy.
   /// should this be non-self method that creates an internal private Vm to run?
-  fn do_match(&mut self, input: &str) -> Option<CharNum> {
-
-    self.text = input.chars().collect();
-    self.stack.clear();
-    self.captures.clear();
+  pub fn do_match<'t>(&mut self, input: &'t str) -> Option<&'t str> {
+    self.match_at(input, 0).map(|end| input.slice_to(end))
+  }
 
-    self.stack.push(ReturnTo(None));
+  // Matches the input from byte offset `at`, returning where the match ends.
+  fn match_at(&mut self, input: &str, at: uint) -> Option<uint> {
 
-    // initial state for the parsing-machine
-    let mut state = VmState(
-      Some(CodeIdx(0)), // start at the beginning of code
-      CharNum(0),       // and beginning of source
-      StackIdx(self.stack.len()),
-      CapLevel(self.captures.len())
-    );
+    let mut state = self.reset(at);
+    match self.functions.iter().find(|&&(_, ref f)| f.is_none()) {
+      Some(&(ref name, _)) => {
+        self.fault = Some((at, format!("no function given for match-time captures of '{}'", name)));
+        return None
+      }
+      None => {}
+    }
+    for param in self.env.params.iter() {
+      match *param {
+        FnParam(ref name) if !self.env.functions.iter().any(|&(ref n, _)| n == name) => {
+          self.fault = Some((at, format!("no function given for the captures that call '{}'", name)));
+          return None
+        }
+        _ => {}
+      }
+    }
 
     'vm: loop {
-      state = self.step(state);
+      state = self.step(input, state);
       match state {
         // a program has only one "End" instruction, its last;
         // nested grammars can compose, inner "Return"ing to outer
@@ -293,25 +870,1043 @@ impl Vm {
         // a parse will either fail (eventually leaving Fail (None) in 'p'),
         // or succeed, in which case the program counter will point past the
         // "End" instruction.
-        VmState(Some(CodeIdx(pc)),CharNum(i),_,_) if pc == self.program.len() => {
-          return Some(CharNum(i));
+        VmState(Some(CodeIdx(pc)),BytePos(i),_,_) if pc == self.program.len() => {
+          return Some(i);
       }
-        VmState(None,_,_,_) => { break 'vm; }
+        // gave up: failed, and no alternatives left on the stack
+        VmState(None,_,StackIdx(0),_) => { break 'vm; }
         _ => {}
       }
     }
     None
   }
+
+  /// Sets where `find` may find a match: only where it starts searching,
+  /// there and to the end of the input, or anywhere after.  Searches are
+  /// unanchored to begin with.  Other matches, such as `do_match`, are
+  /// always anchored at the start of the input.
+  pub fn anchor(&mut self, anchor: Anchor) {
+    self.anchor = anchor;
+  }
+
+  /// Searches the input from byte offset `at` for a match, where the anchor
+  /// allows, and returns where the match starts and ends.  An unanchored
+  /// search tries each position in turn, but passes over those where no
+  /// match can begin, without running the program there: those where the
+  /// literal every match begins with doesn't, found by a substring search,
+  /// or if there's none, those before a char that isn't in the pattern's
+  /// first set, found by a scan of the bytes.
+  pub fn find(&mut self, input: &str, at: uint) -> Option<(uint, uint)> {
+    match self.anchor {
+      Anchored => self.match_at(input, at).map(|end| (at, end)),
+      Whole => match self.match_at(input, at) {
+        Some(end) if end == input.len() => Some((at, end)),
+        _ => None
+      },
+      Unanchored => {
+        let mut pos = at;
+        loop {
+          pos = match self.next_start(input, pos) {
+            Some(pos) => pos,
+            None => return None
+          };
+          match self.match_at(input, pos) {
+            Some(end) => return Some((pos, end)),
+            None if self.fault.is_some() => return None,
+            None => {}
+          }
+          pos = match char_at(input, pos) {
+            Some((_, next)) => next,
+            None => return None
+          };
+        }
+      }
+    }
+  }
+
+  // The first position from `at` on where a match can begin: the next
+  // place the prefix is, if there's one; `at`, if the pattern can match the
+  // empty string; or else the next char in its first set.
+  fn next_start(&self, input: &str, at: uint) -> Option<uint> {
+    if at > input.len() {
+      return None
+    }
+    if !self.prefix.is_empty() {
+      return input.slice_from(at).find_str(self.prefix.as_slice()).map(|k| at + k)
+    }
+    let set = match self.start {
+      Some(key) => &self.sets[key],
+      None => return Some(at)
+    };
+    let bytes = input.as_bytes();
+    let mut pos = at;
+    loop {
+      match bytes.slice_from(pos).iter().position(|&b| self.start_bytes[b as uint]) {
+        Some(k) => pos += k,
+        None => return None
+      }
+      // an ASCII byte is in the set; a lead byte may begin a char that isn't
+      let (c, next) = char_at(input, pos).unwrap();
+      if (c as u32) < 128 || set.contains(c) {
+        return Some(pos)
+      }
+      pos = next;
+    }
+  }
+
+  /// Matches as `do_match` does, but says why a match failed: where the
+  /// match got farthest, and what it expected to find there.
+  pub fn try_match<'t>(&mut self, input: &'t str) -> Result<&'t str, MatchError> {
+    match self.do_match(input) {
+      Some(m) => Ok(m),
+      None => Err(self.match_error(input))
+    }
+  }
+
+  /// Why the last match of `input` failed: the farthest position it failed
+  /// at, and what it expected there: the chars, classes and any-chars it
+  /// tried, and the rules that failed there without matching anything, in
+  /// place of what they tried.  What a `!` predicate's pattern expected is
+  /// counted too.  Or, if the match was broken off by a match-time function
+  /// that wasn't given or went wrong, where and why.
+  pub fn match_error(&self, input: &str) -> MatchError {
+    match self.fault {
+      Some((pos, ref why)) => return MatchError::fault(input, pos, why.as_slice()),
+      None => {}
+    }
+    let expected = self.expected.iter().map(|w| match *w {
+      WChar(c) => ExpectChar(c),
+      WSet(set) => ExpectClass(self.sets[set].to_class()),
+      WAny => ExpectAny,
+      WRule(rule) => { let (ref name, _) = self.rules[rule]; ExpectRule(name.clone()) }
+    }).collect();
+    MatchError::new(input, self.farthest, expected)
+  }
+
+  /// The labeled failures of the last match of `input`, in the order they
+  /// were thrown: those that recovery rules got the match past, and last,
+  /// if the match ended on one that has no recovery rule, that one.  After
+  /// a match that failed otherwise, there are none: they were backtracked
+  /// over.
+  pub fn errors(&self, input: &str) -> Vec<MatchError> {
+    self.captures.iter().filter(|cap| cap.kind == Cerror).map(|cap| {
+      let BytePos(pos) = cap.pos;
+      let (ref label, _) = self.labels[cap.key];
+      MatchError::labeled(input, pos, label.as_slice())
+    }).collect()
+  }
+
+  /// Sets the text that error recovery (see `recover`) resynchronizes on:
+  /// after an error, the input is skipped up to where one of `tokens`
+  /// begins, rather than to a char of the grammar's first and follow sets.
+  /// No tokens puts those back.
+  pub fn sync_tokens(&mut self, tokens: &[&str]) {
+    self.sync = tokens.iter().map(|t| t.to_string()).collect();
+  }
+
+  /// Matches the input in recovery mode, in which the match can't fail: it
+  /// gives a concrete syntax tree of the whole input, as `get_cst` does,
+  /// with the text it couldn't get past in ERROR leaves.  Each time the
+  /// match fails, or ends before the end of the input, the input is skipped
+  /// from where it got farthest to where it can resynchronize, and the match
+  /// is run again.  It resynchronizes before the next char that something it
+  /// expected could begin with, or that could follow the rule it was
+  /// expected in; or before a sync token, if there are any.  An error at the
+  /// end of the input, where there's nothing to skip, skips what the rule
+  /// it was in had matched.  Returns the tree, and the errors, one for each
+  /// time the match was run again; but if skipping ends up making all of the
+  /// input an error, there's only the first one, where the match was first
+  /// stuck, to go with the one ERROR leaf.  A match broken off by a match-time
+  /// function (see `on_match`) isn't run again: all of the input is one
+  /// error, which says why.
+  pub fn recover<'t>(&mut self, input: &'t str, trivia: &[&str]) -> (Cst<'t>, Vec<MatchError>) {
+    let cst = self.cst;
+    self.cst = true;
+    self.skips.clear();
+    let mut errors = vec!();
+    loop {
+      let at = match self.do_match(input) {
+        Some(m) if m.len() == input.len() => break,
+        // what it expected where it ended, if it failed to match anything
+        // there
+        Some(m) => {
+          errors.push(if self.farthest == m.len() {
+            self.match_error(input)
+          } else {
+            MatchError::new(input, m.len(), vec!())
+          });
+          m.len()
+        }
+        // a match broken off won't get further for being run again: all of
+        // the input is an error, and that's why
+        None if self.fault.is_some() => {
+          errors = vec!(self.match_error(input));
+          self.skips = vec!((0, input.len()));
+          self.captures.clear();
+          break
+        }
+        None => {
+          errors.push(self.match_error(input));
+          self.skip(self.farthest)
+        }
+      };
+      let (from, to) = if at < input.len() {
+        (at, self.resync(input, at))
+      } else {
+        (self.farthest_call, input.len())
+      };
+      let skipped = self.skipped();
+      self.skip_over(from, to);
+      // with nothing more to skip, all of the input is an error, and the
+      // first one found is why
+      if self.skipped() == skipped {
+        errors.truncate(1);
+        self.skips = vec!((0, input.len()));
+        self.captures.clear();
+        break
+      }
+    }
+    let tree = cst::build(input, input.len(), self.captures.as_slice(), self.rules.as_slice(),
+                          trivia, self.skips.as_slice());
+    self.skips.clear();
+    self.cst = cst;
+    (tree, errors)
+  }
+
+  // Where recovery from an error at `at` skips to: the next place after the
+  // char at `at` that the match can resynchronize at, or the end of the
+  // input.
+  fn resync(&self, input: &str, at: uint) -> uint {
+    let mut pos = match char_at(input, at) {
+      Some((_, next)) => next,
+      None => return input.len()
+    };
+    loop {
+      let (c, next) = match char_at(input, pos) {
+        Some(c) => c,
+        None => return input.len()
+      };
+      if !self.sync.is_empty() {
+        let rest = input.slice_from(pos);
+        if self.sync.iter().any(|t| rest.starts_with(t.as_slice())) {
+          return pos
+        }
+      } else if self.can_resume(c) {
+        return pos
+      }
+      pos = next;
+    }
+  }
+
+  // Whether something expected at the farthest failure can begin with `c`,
+  // or `c` can follow the rule it was expected in.
+  fn can_resume(&self, c: char) -> bool {
+    let follows = match self.farthest_rule {
+      Some(rule) => self.sets[self.follow[rule]].contains(c),
+      None => false
+    };
+    follows || self.expected.iter().any(|w| match *w {
+      WChar(x) => x == c,
+      WSet(set) => self.sets[set].contains(c),
+      WAny => true,
+      WRule(rule) => self.sets[self.first[rule]].contains(c),
+    })
+  }
+
+  // Adds a span of the input to those error recovery skips, merging it
+  // with those it overlaps or touches.
+  fn skip_over(&mut self, from: uint, to: uint) {
+    self.skips.push((from, to));
+    self.skips.sort();
+    let mut merged: Vec<(uint, uint)> = vec!();
+    for &(a, b) in self.skips.iter() {
+      let joins = match merged.last() {
+        Some(&(_, end)) => a <= end,
+        None => false
+      };
+      if joins {
+        let n = merged.len() - 1;
+        let (start, end) = merged[n];
+        *merged.get_mut(n) = (start, if b > end { b } else { end });
+      } else {
+        merged.push((a, b));
+      }
+    }
+    self.skips = merged;
+  }
+
+  // How many bytes of the input error recovery has skipped.
+  fn skipped(&self) -> uint {
+    self.skips.iter().fold(0, |n, &(a, b)| n + b - a)
+  }
+
+  // `ip`, or if error recovery skipped the text there, the end of what it
+  // skipped.
+  #[inline]
+  fn skip(&self, ip: uint) -> uint {
+    for &(start, end) in self.skips.iter() {
+      if ip < start {
+        break
+      }
+      if ip < end {
+        return end
+      }
+    }
+    ip
+  }
+
+  // The index in the capture list of the innermost capture still open.
+  fn open_capture(&self) -> uint {
+    let mut closes: uint = 0;
+    for (k, cap) in self.captures.iter().enumerate().rev() {
+      match (cap.kind, cap.len) {
+        (Cclose, _) => closes += 1,
+        (_, None) if closes == 0 => return k,
+        (_, None) => closes -= 1,
+        _ => {}
+      }
+    }
+    fail!("BUG: no capture is open")
+  }
+
+  // The number and span of the last capture that back-reference `key` may
+  // refer to, of those closed so far.  Backtracking has already dropped the
+  // captures of alternatives that failed, so they can't be referred to.
+  fn referent(&self, key: uint) -> Option<(uint, uint, uint)> {
+    let refs = self.refs[key].as_slice();
+    // the ends of the captures closed after the point the scan is at
+    let mut ends = vec!();
+    for cap in self.captures.iter().rev() {
+      let BytePos(pos) = cap.pos;
+      match (cap.kind, cap.len) {
+        (Cclose, _) => ends.push(pos),
+        (_, None) => match ends.pop() {
+          Some(end) if cap.kind != Crule && refs.contains(&cap.key) => return Some((cap.key, pos, end)),
+          // still open
+          _ => {}
+        },
+        (_, Some(n)) if refs.contains(&cap.key) => return Some((cap.key, pos, pos + n)),
+        _ => {}
+      }
+    }
+    None
+  }
+
+  // Calls the rule at `dest`, to return to `ret`.
+  fn call(&mut self, ret: uint, dest: uint, i: BytePos, e: StackIdx, c: CapLevel) -> VmState {
+    let (BytePos(ip), StackIdx(sp), CapLevel(cap)) = (i, e, c);
+    // in CST mode, the rule's node opens at the call; memoized and
+    // seed results are of what's inside it
+    let rule = self.rule_at[dest];
+    let c = if !self.cst { c } else {
+      self.captures.push(Capture::open(Crule, rule.unwrap(), i));
+      CapLevel(cap + 1)
+    };
+    let e2 = if dest < self.left_recursive.len() && self.left_recursive[dest] {
+      // the recursive call of a left-recursive rule matches its seed
+      if self.seeds.contains_key(&(ip, dest)) {
+        let end = match self.seeds.get(&(ip, dest)).unwrap().result {
+          Some((end, ref caps)) => {
+            self.captures.push_all(caps.as_slice());
+            end
+          }
+          None => return VmState(None,i,e,c)
+        };
+        let cap = CapLevel(self.captures.len());
+        return self.close_rule(VmState(Some(CodeIdx(ret)), end, e, cap))
+      }
+      self.seeds.insert((ip, dest), Seed { result: None, growing: true });
+      GrowCall(CodeIdx(ret), CodeIdx(dest), i, c)
+    } else if self.memo.is_memoized(dest) {
+      // packrat: a rule already tried at this position isn't run again
+      match self.memo.results.get(&(ip, dest)) {
+        Some(&Some((end, ref caps))) => {
+          self.captures.push_all(caps.as_slice());
+          let cap = CapLevel(self.captures.len());
+          return self.close_rule(VmState(Some(CodeIdx(ret)), end, e, cap))
+        }
+        Some(&None) => return VmState(None,i,e,c),
+        None => MemoCall(CodeIdx(ret), CodeIdx(dest), i, c)
+      }
+    } else {
+      ReturnTo(CodeIdx(ret))
+    };
+    self.stack.push(e2);
+    self.pushes += 1;
+    self.calls.push((rule, ip, self.farthest, self.expected.len(), false));
+    let sp = sp + 1;
+    assert!(sp == self.stack.len());
+    VmState(Some(CodeIdx(dest)),i,StackIdx(sp),c)
+  }
+
+  // Calls the rule at `dest` in tail position, in place of the rule whose
+  // frame is on top of the stack, so that it returns through that frame;
+  // or, if the frame or the rule keeps a result, just calls it, to return
+  // to `ret`.
+  fn tail_call(&mut self, ret: uint, dest: uint, i: BytePos, e: StackIdx, c: CapLevel) -> VmState {
+    let plain = match self.stack.last() {
+      Some(&ReturnTo(_)) => true,
+      _ => false
+    };
+    let lr = dest < self.left_recursive.len() && self.left_recursive[dest];
+    if !plain || lr || self.memo.is_memoized(dest) {
+      return self.call(ret, dest, i, e, c)
+    }
+    let (BytePos(ip), CapLevel(cap)) = (i, c);
+    let rule = self.rule_at[dest];
+    let c = if !self.cst { c } else {
+      self.captures.push(Capture::open(Crule, rule.unwrap(), i));
+      CapLevel(cap + 1)
+    };
+    self.calls.push((rule, ip, self.farthest, self.expected.len(), true));
+    VmState(Some(CodeIdx(dest)),i,e,c)
+  }
+
+  // Returns from the innermost rule being called, and from those that
+  // called it in tail position, through the frame they share.
+  fn returned(&mut self, state: VmState) -> VmState {
+    let mut state = state;
+    loop {
+      let tail = match self.calls.pop() {
+        Some((_, _, _, _, tail)) => tail,
+        None => false
+      };
+      state = self.close_rule(state);
+      if !tail {
+        return state
+      }
+    }
+  }
+
+  // Matches the chars of literal `key` at `ip`, as that many Chars would,
+  // passing over the text error recovery skipped between them: where they
+  // end, or else where the one that failed was, and its char.
+  fn match_string(&self, text: &str, ip: uint, key: uint) -> Result<uint, (uint, char)> {
+    let mut at = ip;
+    for (k, ch) in self.strings[key].as_slice().chars().enumerate() {
+      if k > 0 && !self.skips.is_empty() {
+        at = self.skip(at);
+      }
+      match char_at(text, at) {
+        Some((x, next)) if x == ch => at = next,
+        _ => return Err((at, ch))
+      }
+    }
+    Ok(at)
+  }
+
+  // A left-recursive rule has grown its seed as far as it goes: return from
+  // the call with the seed's match and captures, or None if it never matched.
+  fn grown(&mut self, ret: CodeIdx, CodeIdx(rule): CodeIdx, BytePos(start): BytePos,
+           CapLevel(c0): CapLevel, sp: uint) -> Option<VmState> {
+    let seed = self.seeds.get_mut(&(start, rule)).unwrap();
+    seed.growing = false;
+    match seed.result {
+      Some((end, ref caps)) => {
+        self.captures.truncate(c0);
+        self.captures.push_all(caps.as_slice());
+        Some(VmState(Some(ret), end, StackIdx(sp), CapLevel(self.captures.len())))
+      }
+      None => None
+    }
+  }
+
+  // Notes a failure to find what `want` is at `ip`, if the match has got no
+  // farther than that.
+  #[inline]
+  fn expect(&mut self, ip: uint, want: Want) {
+    if ip < self.farthest {
+      return
+    }
+    if ip > self.farthest {
+      self.farthest = ip;
+      self.expected.clear();
+      self.note_rule();
+    }
+    if !self.expected.contains(&want) {
+      self.expected.push(want);
+    }
+  }
+
+  // Notes what a test that has jumped at `ip` expects, as if the pattern it
+  // passed over had been run, and failed there.  A rule among it is noted
+  // as having failed where it was called.
+  fn expect_heads(&mut self, ip: uint, head: uint) {
+    if ip < self.farthest {
+      return
+    }
+    for k in range(0, self.heads[head].len()) {
+      match self.heads[head][k] {
+        WRule(rule) => {
+          let (farthest, n) = (self.farthest, self.expected.len());
+          self.expect_rule(rule, ip, farthest, n);
+        }
+        want => self.expect(ip, want)
+      }
+    }
+  }
+
+  // The innermost rule being called has failed, and with it those that
+  // called it in tail position, which return through the same frame.
+  fn rule_failed(&mut self) {
+    loop {
+      let (rule, at, farthest, n, tail) = match self.calls.pop() {
+        Some(call) => call,
+        None => return
+      };
+      match rule {
+        Some(rule) => self.expect_rule(rule, at, farthest, n),
+        None => {}
+      }
+      if !tail {
+        return
+      }
+    }
+  }
+
+  // A rule called at `at`, when the farthest failure and the number of
+  // expected items were `farthest` and `n`, has failed.  If the match got
+  // no farther than where it was called, the rule is what was expected
+  // there, in place of what was expected inside it; but for the first rule
+  // called, which is what the whole input was expected to be.
+  fn expect_rule(&mut self, rule: uint, at: uint, farthest: uint, n: uint) {
+    if self.calls.is_empty() || self.farthest > at {
+      return
+    }
+    if self.farthest < at || farthest < at {
+      self.farthest = at;
+      self.expected.clear();
+    } else {
+      self.expected.truncate(n);
+    }
+    self.note_rule();
+    if !self.expected.contains(&WRule(rule)) {
+      self.expected.push(WRule(rule));
+    }
+  }
+
+  // Closes the captures still open, at `i`.
+  fn close_all(&mut self, i: BytePos) {
+    let mut open: uint = 0;
+    for cap in self.captures.iter() {
+      match (cap.kind, cap.len) {
+        (Cclose, _) => open -= 1,
+        (_, None) => open += 1,
+        _ => {}
+      }
+    }
+    for _ in range(0, open) {
+      self.captures.push(Capture::close(i));
+    }
+  }
+
+  // Notes the innermost rule being called as the one the farthest failure
+  // is in.
+  fn note_rule(&mut self) {
+    let (rule, at) = match self.calls.last() {
+      Some(&(rule, at, _, _, _)) => (rule, at),
+      None => (None, 0)
+    };
+    self.farthest_rule = rule;
+    self.farthest_call = at;
+  }
+
+  // In CST mode, closes the node of the rule a successful call has returned
+  // from.
+  fn close_rule(&mut self, state: VmState) -> VmState {
+    if !self.cst {
+      return state
+    }
+    match state {
+      VmState(p, i, e, CapLevel(cap)) => {
+        assert!(cap == self.captures.len());
+        self.captures.push(Capture::close(i));
+        VmState(p, i, e, CapLevel(cap + 1))
+      }
+    }
+  }
+
+  // Results found at or after `start` while growing `rule` there may have
+  // depended on its old seed, so they're forgotten; except the seeds of
+  // other rules that are still growing, and depend on this one.
+  fn forget_grown_on(&mut self, start: uint, rule: uint) {
+    let stale: Vec<(uint, uint)> = self.seeds.iter()
+      .filter(|&(&(pos, r), seed)| pos >= start && r != rule && !seed.growing)
+      .map(|(&k, _)| k)
+      .collect();
+    for k in stale.iter() {
+      self.seeds.remove(k);
+    }
+    self.memo.forget_from(start);
+  }
+
+  /// Clears the stacks, and returns the initial state for the parsing-machine,
+  /// to match from byte offset `at`.
+  fn reset(&mut self, at: uint) -> VmState {
+    self.stack.clear();
+    self.captures.clear();
+    self.memo.results.clear();
+    self.seeds.clear();
+    self.env.dynamic.clear();
+    self.farthest = at;
+    self.expected.clear();
+    self.fault = None;
+    self.calls.clear();
+    self.farthest_rule = None;
+    self.farthest_call = at;
+
+    // the bottom of the stack: failing back to here gives up
+    let giveup = self.program.len() - 1;
+    self.stack.push(AlternateTo(CodeIdx(giveup), BytePos(0), CapLevel(0)));
+    self.pushes = 1;
+
+    VmState(
+      Some(CodeIdx(0)), // start at the beginning of code
+      BytePos(at),      // and where the match is to be tried
+      StackIdx(self.stack.len()),
+      CapLevel(self.captures.len())
+    )
+  }
+}
+
+/// The char at byte offset `i` of `text`, and the offset of the one after it;
+/// None at the end of the text.  ASCII is the common case, and is read
+/// straight from the bytes.
+#[inline]
+fn char_at(text: &str, i: uint) -> Option<(char, uint)> {
+  if i >= text.len() {
+    return None
+  }
+  let b = text.as_bytes()[i];
+  if b < 0x80 {
+    return Some((b as char, i + 1))
+  }
+  let CharRange { ch, next } = text.char_range_at(i);
+  Some((ch, next))
+}
+
+// A Program of just `insts` and `sets`, with no rules or captures.
+#[cfg(test)]
+fn program(insts: Vec<Opcode>, sets: Vec<Charset>) -> Program {
+  Program { insts: insts, sets: sets, rules: vec!(), left_recursive: vec!(), names: vec!(None),
+            params: vec!(), refs: vec!(), refers_back: vec!(), functions: vec!(), labels: vec!(),
+            first: vec!(), follow: vec!(), start: None, prefix: String::new(), heads: vec!(),
+            strings: vec!() }
 }
 
 #[test]
 fn t1() {
-  let code = vec!(IChar('a'),IChar('n'),IChar('a'), IEnd);
-  let mut vm = Vm::new(code);
+  let code = vec!(IChar('a', 0),IChar('n', 0),IChar('a', 0), IEnd);
+  let mut vm = Vm::new(program(code, vec!()));
   let result = vm.do_match("ana");
-  assert!(result.unwrap() == CharNum(3));
+  assert!(result.unwrap() == "ana");
 }
 
+// Tests of the machine's semantics, one instruction at a time, for each
+// row of Figures 2 and 3 (see notes.md).
+
+#[cfg(test)]
+fn st(p: Option<uint>, i: uint, e: uint, c: uint) -> VmState {
+  VmState(p.map(|pc| CodeIdx(pc)), BytePos(i), StackIdx(e), CapLevel(c))
+}
+
+// A position capture, of the kind that `IFullCapture(Cposition, 0, 0)` makes.
+#[cfg(test)]
+fn poscap(ip: uint) -> Capture {
+  Capture::full(Cposition, 0, BytePos(ip), 0)
+}
+
+// A Vm loaded with `insts`, and with only the giveup entry on its stack.
+// Charset 0 is [a-z].
+#[cfg(test)]
+fn machine(insts: Vec<Opcode>) -> Vm {
+  let az = Charset::new(&[('a','z')], FLAG_NORMAL);
+  let mut vm = Vm::new(program(insts, vec!(az)));
+  vm.reset(0);
+  vm
+}
+
+#[test]
+fn fig2_char() {
+  let mut vm = machine(vec!(IChar('a', 0), IEnd));
+  let s = "ab";
+  assert_eq!(vm.step(s, st(Some(0), 0, 1, 0)), st(Some(1), 1, 1, 0));
+  assert_eq!(vm.step(s, st(Some(0), 1, 1, 0)), st(None, 1, 1, 0));
+  // no char at all: the end of input
+  assert_eq!(vm.step(s, st(Some(0), 2, 1, 0)), st(None, 2, 1, 0));
+}
+
+#[test]
+fn fig2_jump_choice_commit() {
+  let mut vm = machine(vec!(IChoice(3), IJmp(1), ICommit(-1), IEnd));
+  let s = "ab";
+  assert_eq!(vm.step(s, st(Some(0), 1, 1, 0)), st(Some(1), 1, 2, 0));
+  assert_eq!(vm.stack[1], AlternateTo(CodeIdx(3), BytePos(1), CapLevel(0)));
+  assert_eq!(vm.step(s, st(Some(1), 1, 2, 0)), st(Some(2), 1, 2, 0));
+  assert_eq!(vm.step(s, st(Some(2), 2, 2, 0)), st(Some(1), 2, 1, 0));
+  assert_eq!(vm.stack.len(), 1);
+}
+
+#[test]
+fn fig2_call_return() {
+  let mut vm = machine(vec!(ICall(2), IEnd, IRet));
+  let s = "";
+  assert_eq!(vm.step(s, st(Some(0), 0, 1, 0)), st(Some(2), 0, 2, 0));
+  assert_eq!(vm.stack[1], ReturnTo(CodeIdx(1)));
+  assert_eq!(vm.step(s, st(Some(2), 0, 2, 0)), st(Some(1), 0, 1, 0));
+}
+
+#[test]
+fn fig2_capture() {
+  let mut vm = machine(vec!(IFullCapture(Cposition, 0, 0), IEnd));
+  let s = "ab";
+  assert_eq!(vm.step(s, st(Some(0), 1, 1, 0)), st(Some(1), 1, 1, 1));
+  assert_eq!(vm.captures, vec!(poscap(1)));
+}
+
+#[test]
+fn open_close_capture() {
+  let mut vm = machine(vec!(IOpenCapture(Csimple, 1), ISpan(0), ICloseCapture,
+                            IFullCapture(Csimple, 2, 1), IEnd));
+  let s = "ab1";
+  assert_eq!(vm.step(s, st(Some(0), 0, 1, 0)), st(Some(1), 0, 1, 1));
+  assert_eq!(vm.step(s, st(Some(1), 0, 1, 1)), st(Some(2), 2, 1, 1));
+  assert_eq!(vm.step(s, st(Some(2), 2, 1, 1)), st(Some(3), 2, 1, 2));
+  assert_eq!(vm.step(s, st(Some(3), 3, 1, 2)), st(Some(4), 3, 1, 3));
+  assert_eq!(vm.captures, vec!(Capture::open(Csimple, 1, BytePos(0)), Capture::close(BytePos(2)),
+                               Capture::full(Csimple, 2, BytePos(2), 1)));
+}
+
+#[test]
+fn fig2_fail() {
+  let mut vm = machine(vec!(IFail, IEnd));
+  let s = "abc";
+  assert_eq!(vm.step(s, st(Some(0), 2, 1, 0)), st(None, 2, 1, 0));
+
+  // failing pops pending calls...
+  vm.stack.push(AlternateTo(CodeIdx(1), BytePos(1), CapLevel(1)));
+  vm.stack.push(ReturnTo(CodeIdx(1)));
+  vm.captures.push(poscap(0));
+  vm.captures.push(poscap(2));
+  assert_eq!(vm.step(s, st(None, 3, 3, 2)), st(None, 3, 2, 2));
+  // ...up to a choice, which restores its position and captures
+  assert_eq!(vm.step(s, st(None, 3, 2, 2)), st(Some(1), 1, 1, 1));
+  assert_eq!(vm.captures, vec!(poscap(0)));
+  // failing back to the bottom of the stack gives up
+  assert_eq!(vm.step(s, st(None, 1, 1, 1)), st(Some(2), 0, 0, 0));
+  assert_eq!(vm.step(s, st(Some(2), 0, 0, 0)), st(None, 0, 0, 0));
+}
+
+#[test]
+fn fig3_charset_any_span() {
+  let mut vm = machine(vec!(ISet(0), IAny(0), ISpan(0), IEnd));
+  let s = "ab1";
+  assert_eq!(vm.step(s, st(Some(0), 1, 1, 0)), st(Some(1), 2, 1, 0));
+  assert_eq!(vm.step(s, st(Some(0), 2, 1, 0)), st(None, 2, 1, 0));
+  assert_eq!(vm.step(s, st(Some(0), 3, 1, 0)), st(None, 3, 1, 0));
+
+  assert_eq!(vm.step(s, st(Some(1), 2, 1, 0)), st(Some(2), 3, 1, 0));
+  assert_eq!(vm.step(s, st(Some(1), 3, 1, 0)), st(None, 3, 1, 0));
+
+  assert_eq!(vm.step(s, st(Some(2), 0, 1, 0)), st(Some(3), 2, 1, 0));
+  assert_eq!(vm.step(s, st(Some(2), 2, 1, 0)), st(Some(3), 2, 1, 0));
+}
+
+#[test]
+fn fig3_partial_commit() {
+  let mut vm = machine(vec!(IChar('a', 0), IPartialCommit(-1), IEnd));
+  let s = "aaa";
+  vm.stack.push(AlternateTo(CodeIdx(2), BytePos(0), CapLevel(0)));
+  vm.captures.push(poscap(1));
+  assert_eq!(vm.step(s, st(Some(1), 2, 2, 1)), st(Some(0), 2, 2, 1));
+  assert_eq!(vm.stack[1], AlternateTo(CodeIdx(2), BytePos(2), CapLevel(1)));
+}
+
+#[test]
+fn fig3_fail_twice_back_commit() {
+  let mut vm = machine(vec!(IFailTwice, IBackCommit(2), IEnd, IEnd));
+  let s = "ab";
+  vm.stack.push(AlternateTo(CodeIdx(2), BytePos(0), CapLevel(0)));
+  vm.stack.push(AlternateTo(CodeIdx(3), BytePos(1), CapLevel(0)));
+  assert_eq!(vm.step(s, st(Some(0), 2, 3, 0)), st(None, 2, 2, 0));
+  assert_eq!(vm.stack[1], AlternateTo(CodeIdx(2), BytePos(0), CapLevel(0)));
+
+  vm.captures.push(poscap(1));
+  assert_eq!(vm.step(s, st(Some(1), 2, 2, 1)), st(Some(3), 0, 1, 0));
+  assert!(vm.captures.is_empty());
+}
+
+#[test]
+fn failed_matches() {
+  // 'a' / 'b'
+  let code = vec!(IChoice(3), IChar('a', 0), ICommit(2), IChar('b', 0), IEnd);
+  let mut vm = Vm::new(program(code, vec!()));
+  assert_eq!(vm.do_match("b"), Some("b"));
+  assert_eq!(vm.do_match("c"), None);
+  assert_eq!(vm.do_match(""), None);
+
+  // a rule that fails leaves its return address on the stack, above the choice
+  let code = vec!(IChoice(3), ICall(4), ICommit(2), IChar('b', 0), IEnd, IFail);
+  let mut vm = Vm::new(program(code, vec!()));
+  assert_eq!(vm.do_match("b"), Some("b"));
+  assert_eq!(vm.do_match("a"), None);
+
+  // captures made by a failed alternative are dropped
+  let code = vec!(IChoice(4), IFullCapture(Cposition, 0, 0), IChar('x', 0), ICommit(2), IChar('y', 0), IEnd);
+  let mut vm = Vm::new(program(code, vec!()));
+  assert_eq!(vm.do_match("y"), Some("y"));
+  assert!(vm.captures.is_empty());
+}
+
+#[test]
+fn utf8_positions() {
+  // positions are byte offsets; chars are decoded only as instructions need them
+  let mut vm = machine(vec!(IAny(0), ISet(0), IChar('\u00e9', 0), ISpan(0), IEnd));
+  let s = "\u00e9a\u00e9bc\u4e00";
+  assert_eq!(vm.step(s, st(Some(0), 0, 1, 0)), st(Some(1), 2, 1, 0));
+  assert_eq!(vm.step(s, st(Some(1), 2, 1, 0)), st(Some(2), 3, 1, 0));
+  assert_eq!(vm.step(s, st(Some(2), 3, 1, 0)), st(Some(3), 5, 1, 0));
+  assert_eq!(vm.step(s, st(Some(3), 5, 1, 0)), st(Some(4), 7, 1, 0));
+  assert_eq!(vm.step(s, st(Some(1), 7, 1, 0)), st(None, 7, 1, 0));
+  assert_eq!(vm.step(s, st(Some(0), 7, 1, 0)), st(Some(1), 10, 1, 0));
+
+  // the match is a slice of the input
+  let input = "\u00e9a\u00e9bc\u4e00".to_string();
+  let mut vm = machine(vec!(IAny(0), ISpan(0), IEnd));
+  let m = vm.do_match(input.as_slice()).unwrap();
+  assert_eq!(m, "\u00e9a");
+  assert_eq!(m.as_ptr(), input.as_ptr());
+}
+
+
+#[test]
+fn backref() {
+  // {:1: [a-z]* :} ('-' / '+' ...) =1, with the capture made again in the
+  // second alternative after the first failed:
+  //   OpenCapture 1; Span [a-z]; CloseCapture
+  //   Choice L1; Char '-'; Char '!'; Commit L2
+  //   L1: OpenCapture 1; Char '+'; CloseCapture
+  //   L2: Backref 0; End
+  let mut vm = machine(vec!(IOpenCapture(Cgroup, 1), ISpan(0), ICloseCapture,
+                            IChoice(4), IChar('-', 0), IChar('!', 0), ICommit(4),
+                            IOpenCapture(Cgroup, 1), IChar('+', 0), ICloseCapture,
+                            IBackref(0), IEnd));
+  vm.refs = vec!(vec!(1));
+  assert_eq!(vm.do_match("ab-!ab"), Some("ab-!ab"));
+  assert_eq!(vm.do_match("ab-!a"), None);
+  // the capture of the failed '-' alternative is gone; the last one is '+'
+  assert_eq!(vm.do_match("ab-+"), None);
+  assert_eq!(vm.do_match("ab+ab"), None);
+  assert_eq!(vm.do_match("ab++"), Some("ab++"));
+  assert_eq!(vm.captures().last(), Some(&Capture::full(Cbackref, 1, BytePos(3), 1)));
+  // a capture that's still open can't be referred to
+  let mut vm = machine(vec!(IOpenCapture(Cgroup, 1), IChar('a', 0), IBackref(0),
+                            ICloseCapture, IEnd));
+  vm.refs = vec!(vec!(1));
+  assert_eq!(vm.do_match("aa"), None);
+}
+
+#[test]
+fn left_recursion() {
+  // E <- E '-' [a-z] {position} / [a-z]
+  let code = vec!(
+    ICall(2), IJmp(9),
+    IChoice(6), ICall(-1), IChar('-', 0), ISet(0), IFullCapture(Cposition, 0, 0), ICommit(2),
+    ISet(0), IRet,
+    IEnd);
+  let az = Charset::new(&[('a','z')], FLAG_NORMAL);
+  let mut p = program(code, vec!(az));
+  p.left_recursive = vec!(2);
+  let mut vm = Vm::new(p);
+  assert_eq!(vm.do_match("a-b-c!"), Some("a-b-c"));
+  // a position is captured at the end of each subtraction, the inner one
+  // first: ((a-b)-c)
+  assert_eq!(vm.captures, vec!(poscap(3), poscap(5)));
+  assert_eq!(vm.do_match("a"), Some("a"));
+  assert!(vm.captures.is_empty());
+  assert_eq!(vm.do_match("-a"), None);
+
+  // and with packrat mode on, whose results are forgotten as seeds grow
+  use parse::parse;
+  let g = parse("S <- E '.'  E <- E '-' T / T  T <- [a-z] / '(' E ')'").unwrap();
+  let mut vm = Vm::new(Program::new(g).unwrap());
+  vm.memoize_all(100);
+  assert_eq!(vm.do_match("a-(b-c)-d."), Some("a-(b-c)-d."));
+  assert_eq!(vm.do_match("a-(b-c)-d"), None);
+}
+
+// A pathological grammar for a backtracking parser: each rule tries the next
+// one, fails after it, and tries it again, so without packrat memoization
+// matching takes 2^depth calls.
+#[cfg(test)]
+fn pathological(depth: uint) -> Program {
+  use parse::parse;
+  let mut src = String::new();
+  for n in range(0, depth) {
+    src.push_str(format!("R{} <- R{} '!' / R{}\n", n, n + 1, n + 1).as_slice());
+  }
+  src.push_str(format!("R{} <- 'a'", depth).as_slice());
+  Program::new(parse(src.as_slice()).unwrap()).unwrap()
+}
+
+// A grammar of keyword statements, whose alternatives all begin with
+// different chars, compiled with or without test instructions, and the
+// text of n of its statements.
+#[cfg(test)]
+fn keywords(n: uint, tests: bool) -> (Program, String) {
+  use parse::parse;
+  let ast = parse("
+    stmts <- (stmt ';')* !.
+    stmt <- 'if' / 'while' / 'return' / 'break' / 'continue'").unwrap();
+  let p = if tests { Program::new(ast) } else { Program::without_tests(ast) }.unwrap();
+  let mut text = String::new();
+  for _ in range(0, n) {
+    text.push_str("while;break;return;");
+  }
+  (p, text)
+}
+
+#[test]
+fn test_instructions() {
+  use error::ExpectRule;
+  let (p, text) = keywords(1, true);
+  let mut vm = Vm::new(p);
+  assert_eq!(vm.do_match(text.as_slice()), Some(text.as_slice()));
+  // the bottom entry, a choice for the loop and one for the !, and the
+  // calls, the last of which fails
+  assert_eq!(vm.stack_pushes(), 8);
+  // and a choice for each alternative tried, without the tests
+  let (p, _) = keywords(1, false);
+  let mut untested = Vm::new(p);
+  assert_eq!(untested.do_match(text.as_slice()), Some(text.as_slice()));
+  assert_eq!(untested.stack_pushes(), 21);
+  // an alternative passed over by its test is expected all the same
+  assert_eq!(vm.do_match("while;loop;"), None);
+  assert_eq!(vm.match_error("while;loop;").expected, vec!(ExpectRule("stmt".to_string())));
+  assert_eq!(vm.do_match("loop;"), None);
+  assert_eq!(vm.match_error("loop;").expected, vec!(ExpectRule("stmt".to_string())));
+}
+
+#[test]
+fn packrat() {
+  use parse::parse;
+  // 2^64 calls if it weren't memoized
+  let mut vm = Vm::new(pathological(64));
+  vm.memoize_all(1000);
+  assert_eq!(vm.do_match("a!"), Some("a!"));
+  assert_eq!(vm.do_match("b"), None);
+  // even when the table holds next to nothing, results are the same
+  vm.memoize_all(1);
+  assert_eq!(vm.do_match("a"), Some("a"));
+
+  // remembered captures come back with a remembered result
+  let g = Program::new(parse("S <- A 'x' / A 'y'  A <- [a-c]+").unwrap()).unwrap();
+  let mut vm = Vm::new(g);
+  assert!(vm.memoize(&["A"], 100).is_ok());
+  assert_eq!(vm.do_match("abcy"), Some("abcy"));
+  let &(_, a) = &vm.rules[1];
+  assert!(vm.memo.results.get(&(0, a)).is_some());
+  assert_eq!(vm.do_match("abc"), None);
+
+  assert_eq!(vm.memoize(&["B"], 100).err().unwrap().msg,
+             "no rule named 'B' to memoize".to_string());
+
+  // overwriting a result in a full table evicts nothing
+  let mut m = Memo { rules: vec!(), capacity: 2, results: BTreeMap::new() };
+  m.insert(BytePos(0), CodeIdx(1), None);
+  m.insert(BytePos(1), CodeIdx(1), None);
+  m.insert(BytePos(1), CodeIdx(1), Some((BytePos(2), vec!())));
+  assert!(m.results.contains_key(&(0, 1)));
+  assert!(m.results.get(&(1, 1)).unwrap().is_some());
+  // but a new one evicts the lowest
+  m.insert(BytePos(2), CodeIdx(1), None);
+  assert!(!m.results.contains_key(&(0, 1)));
+  assert_eq!(m.results.len(), 2);
+}
+
+#[cfg(test)]
+mod bench {
+  use test::Bencher;
+  use super::{Vm, pathological, keywords};
+  use compile::Program;
+  use parse::parse;
+
+  #[bench]
+  fn backtracking_depth_8(b: &mut Bencher) {
+    let mut vm = Vm::new(pathological(8));
+    b.iter(|| vm.do_match("a"));
+  }
+
+  #[bench]
+  fn backtracking_depth_16(b: &mut Bencher) {
+    let mut vm = Vm::new(pathological(16));
+    b.iter(|| vm.do_match("a"));
+  }
+
+  #[bench]
+  fn packrat_depth_8(b: &mut Bencher) {
+    let mut vm = Vm::new(pathological(8));
+    vm.memoize_all(1000);
+    b.iter(|| vm.do_match("a"));
+  }
+
+  #[bench]
+  fn packrat_depth_16(b: &mut Bencher) {
+    let mut vm = Vm::new(pathological(16));
+    vm.memoize_all(1000);
+    b.iter(|| vm.do_match("a"));
+  }
+
+  #[bench]
+  fn packrat_depth_64(b: &mut Bencher) {
+    let mut vm = Vm::new(pathological(64));
+    vm.memoize_all(1000);
+    b.iter(|| vm.do_match("a"));
+  }
+
+  // the alternatives are passed over by their tests, without a choice
+  #[bench]
+  fn keyword_statements(b: &mut Bencher) {
+    let (p, text) = keywords(100, true);
+    let mut vm = Vm::new(p);
+    b.iter(|| vm.do_match(text.as_slice()));
+  }
+
+  // the same, with a choice pushed for each alternative tried
+  #[bench]
+  fn keyword_statements_without_tests(b: &mut Bencher) {
+    let (p, text) = keywords(100, false);
+    let mut vm = Vm::new(p);
+    b.iter(|| vm.do_match(text.as_slice()));
+  }
+
+  // A search of some text, which matches only at its end
+  fn search(pattern: &str, b: &mut Bencher) {
+    let mut vm = Vm::new(Program::new(parse(pattern).unwrap()).unwrap());
+    let mut text = String::new();
+    for _ in range(0, 200) {
+      text.push_str("the quick brown fox jumps over the lazy dog; ");
+    }
+    text.push_str("needle42!");
+    b.iter(|| vm.find(text.as_slice(), 0));
+  }
+
+  #[bench]
+  fn search_prefix(b: &mut Bencher) {
+    search("'needle' [0-9]+ '!'", b)
+  }
+
+  #[bench]
+  fn search_first_set(b: &mut Bencher) {
+    search("[0-9]+ '!'", b)
+  }
+
+  // any char can begin a match, so the program runs at every position
+  #[bench]
+  fn search_every_position(b: &mut Bencher) {
+    search(". [0-9]+ '!'", b)
+  }
+}
 
 // stuff from lpeg below
 